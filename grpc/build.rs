@@ -3,6 +3,7 @@ use std::{fs, path::PathBuf};
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let v1_out_dir = PathBuf::from("src/v1/pb");
     let v1_proto_files = [
+        "protos/v1/admin/service.proto",
         "protos/v1/llm/service.proto",
         "protos/v1/prompt/service.proto",
     ];
@@ -0,0 +1,86 @@
+use grpc::logging;
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::Parser;
+
+/// Converts a safetensors model and its tokenizer into a single quantized GGUF file, loaded back
+/// by the server with `--model-path <output>`.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// Hub repo id of the model, or the path of a local directory holding its files.
+    #[arg(long, default_value = "mistralai/Mistral-7B-Instruct-v0.2")]
+    pub model_id: String,
+
+    /// Local directory holding config.json, the safetensors weights, tokenizer.json and
+    /// tokenizer_config.json. When set nothing is downloaded.
+    #[arg(long)]
+    pub model_path: Option<PathBuf>,
+
+    /// Quantization of the linear layers (Q8_0 or Q4_K), embeddings and norms stay in float.
+    #[arg(long, default_value = "Q4_K")]
+    pub quantization: llm::Quantization,
+
+    /// Path of the GGUF file to write. Defaults to `<model name>.<quantization>.gguf` in the
+    /// current directory.
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+}
+
+impl Args {
+    fn output(&self) -> PathBuf {
+        match &self.output {
+            Some(output) => output.clone(),
+            None => {
+                let model_name = self
+                    .model_id
+                    .trim_end_matches('/')
+                    .rsplit('/')
+                    .next()
+                    .unwrap_or(&self.model_id);
+                PathBuf::from(format!("{model_name}.{}.gguf", self.quantization))
+            }
+        }
+    }
+}
+
+impl From<&Args> for llm::ModelConfig {
+    fn from(value: &Args) -> Self {
+        Self {
+            model_id: value.model_id.clone(),
+            device: llm::DeviceConfig::Cpu,
+            dtype: Some(llm::DType::F32),
+            quantize: false,
+            quant_variant: None,
+            load_quantization: None,
+            model_path: value.model_path.clone(),
+            adapters: Vec::new(),
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    logging::init();
+    let config: llm::ModelConfig = (&args).into();
+    let output = args.output();
+    tracing::info!("Converting {:?} to {:?}", &config, &output);
+
+    let files = llm::ModelFiles::from_model_config(&config)?;
+    let tokenizer = llm::Tokenizer::from_model_config(&config)?;
+    let mut weights = llm::QuantizedWeights::from_model_files(&files, args.quantization)?;
+    for (key, value) in tokenizer.gguf_metadata()? {
+        weights.set_metadata(&key, value);
+    }
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&output)?);
+    weights.write(&mut writer)?;
+    writer.flush()?;
+    tracing::info!(
+        "Wrote {} tensors and {} metadata entries to {:?}",
+        weights.tensors.len(),
+        weights.metadata.len(),
+        &output
+    );
+    Ok(())
+}
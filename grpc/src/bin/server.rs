@@ -1,5 +1,8 @@
+use grpc::config::ServerConfig;
+use grpc::models::ModelRegistry;
 use grpc::{logging, v1};
 use std::net::ToSocketAddrs;
+use std::sync::Arc;
 use tonic::transport::Server;
 
 use clap::Parser;
@@ -22,43 +25,126 @@ pub struct Args {
     // /// The seed to use when generating random samples.
     // #[arg(long, default_value_t = 299792458)]
     // seed: u64,
-    /// The model id to use.
-    #[arg(long, default_value = "7b-instruct-v0.2")]
-    pub model_id: llm::ModelType,
+    /// Hub repo id of the model, or the path of a local directory holding its files. The
+    /// architecture is detected from its config.json.
+    #[arg(long, default_value = "mistralai/Mistral-7B-Instruct-v0.2")]
+    pub model_id: String,
 
-    /// The data type to load the model in.
-    #[arg(long, default_value = "BF16")]
-    pub dtype: String,
+    /// Device the model runs on: auto, cpu, cuda:N or metal:N. auto takes the first GPU the
+    /// build supports, the CPU otherwise.
+    #[arg(long, default_value = "auto")]
+    pub device: llm::DeviceConfig,
 
-    /// The data type to load the model in.
+    /// Dtype the model runs in: BF16, F16 or F32. Defaults to BF16 on CUDA and F32 otherwise,
+    /// quantized models only run in F32.
+    #[arg(long, value_parser = llm::str_to_dtype)]
+    pub dtype: Option<llm::DType>,
+
+    /// Load the quantized weights of a GGUF file, reading the hyperparameters and the tokenizer
+    /// from its metadata.
     #[arg(long, default_value = "false", default_value_t = false)]
     pub quantize: bool,
 
+    /// Quantize the linear layers of safetensors weights while loading them (Q8_0 or Q4_K),
+    /// embeddings and norms stay in float.
+    #[arg(long)]
+    pub quantize_on_load: Option<llm::Quantization>,
+
+    /// Quantization of the GGUF file to load when the repo or directory holds several
+    /// (Q4_K_M, Q8_0, ...). Defaults to Q4_K_M when available.
+    #[arg(long)]
+    pub quant_variant: Option<String>,
+
     #[arg(long, default_value = "main")]
     pub revision: String,
+
+    /// Local directory holding config.json, the weights, tokenizer.json and
+    /// tokenizer_config.json, or a single GGUF file. When set the model is loaded from it without
+    /// any network access.
+    #[arg(long)]
+    pub model_path: Option<std::path::PathBuf>,
+
+    /// LoRA adapter loaded over the base weights, as `name=path` of a PEFT adapter directory.
+    /// Repeat it to load several adapters, requests select them by name.
+    #[arg(long = "adapter")]
+    pub adapters: Vec<llm::AdapterConfig>,
+
+    /// JSON file listing the models to serve, each with its own generation pipeline, see
+    /// `ServerConfig::from_file`. When set the model and batching flags are ignored.
+    #[arg(long)]
+    pub config: Option<std::path::PathBuf>,
+
+    /// Serve the admin service, which loads, swaps and unloads models while the server runs. It
+    /// is not authenticated and loads whatever model ids, model paths and adapter paths it is
+    /// sent, so it listens on `--admin-address` instead of the public port.
+    #[arg(long, default_value = "false", default_value_t = false)]
+    pub admin: bool,
+
+    /// Address of the admin service. The loopback default only accepts local clients, only bind
+    /// it to a reachable interface behind a proxy that authenticates the callers.
+    #[arg(long, default_value = "127.0.0.1:50052")]
+    pub admin_address: std::net::SocketAddr,
+
+    /// Maximum number of sequences run together in a single forward pass.
+    #[arg(long, default_value_t = 32)]
+    pub max_batch_size: usize,
+
+    /// Maximum number of padded positions (batch size * sequence length) in a single forward pass.
+    #[arg(long, default_value_t = 16384)]
+    pub max_batch_tokens: usize,
+}
+
+impl From<&Args> for llm::BatchingConfig {
+    fn from(value: &Args) -> Self {
+        Self {
+            max_batch_size: value.max_batch_size,
+            max_batch_tokens: value.max_batch_tokens,
+        }
+    }
 }
 
 impl From<Args> for llm::ModelConfig {
     fn from(value: Args) -> Self {
         Self {
             model_id: value.model_id,
-            dtype: llm::str_to_dtype(&value.dtype),
+            device: value.device,
+            dtype: value.dtype,
             quantize: value.quantize,
+            quant_variant: value.quant_variant,
+            load_quantization: value.quantize_on_load,
+            model_path: value.model_path,
+            adapters: value.adapters,
         }
     }
 }
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let config: llm::ModelConfig = args.into();
     logging::init();
+    let admin = args.admin.then_some(args.admin_address);
+    let config = match args.config.clone() {
+        Some(path) => ServerConfig::from_file(&path)?,
+        None => {
+            let batching_config: llm::BatchingConfig = (&args).into();
+            ServerConfig::single(args.into(), batching_config)
+        }
+    };
     tracing::info!("Starting server with config: {:?}", &config);
-    Server::builder()
+    let models = Arc::new(ModelRegistry::load(&config).await?);
+    let server = Server::builder()
         .add_service(v1::services::spec_service()?)
-        .add_service(v1::services::prompt::service(config.model_id))
-        .add_service(v1::services::llm::service(config).await)
-        .serve("[::]:50051".to_socket_addrs().unwrap().next().unwrap())
-        .await
-        .unwrap();
+        .add_service(v1::services::prompt::service(models.clone()))
+        .add_service(v1::services::llm::service(models.clone()))
+        .serve("[::]:50051".to_socket_addrs().unwrap().next().unwrap());
+    match admin {
+        Some(admin_address) => {
+            tracing::info!("Serving the admin service on {}", admin_address);
+            let admin_server = Server::builder()
+                .add_service(v1::services::admin::service(models))
+                .serve(admin_address);
+            tokio::try_join!(server, admin_server)?;
+        }
+        None => server.await?,
+    }
     Ok(())
 }
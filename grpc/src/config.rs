@@ -0,0 +1,280 @@
+use crate::{Error, Result};
+use std::path::{Path, PathBuf};
+
+/// A model served under `name`, with its own generation pipeline.
+#[derive(Debug, Clone)]
+pub struct ServedModel {
+    pub name: String,
+    pub config: llm::ModelConfig,
+    pub batching_config: llm::BatchingConfig,
+}
+
+/// The models of a server, requests naming no model go to `default`.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub models: Vec<ServedModel>,
+    pub default: String,
+}
+
+impl ServerConfig {
+    /// A server with a single model, named after its model id.
+    pub fn single(config: llm::ModelConfig, batching_config: llm::BatchingConfig) -> Self {
+        let name = config.model_id.clone();
+        Self {
+            models: vec![ServedModel {
+                name: name.clone(),
+                config,
+                batching_config,
+            }],
+            default: name,
+        }
+    }
+
+    /// Reads the JSON file listing the models to serve:
+    ///
+    /// ```json
+    /// {
+    ///   "default": "mistral",
+    ///   "models": [
+    ///     { "name": "mistral", "model_id": "mistralai/Mistral-7B-Instruct-v0.2" },
+    ///     { "name": "qwen", "model_path": "models/qwen2-7b.Q4_K_M.gguf", "max_batch_size": 8 }
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// Models take the fields of the server flags, `device`, `dtype`, `quantize`,
+    /// `quantize_on_load`, `quant_variant`, `adapters` (as `name=path`), `max_batch_size` and
+    /// `max_batch_tokens`, with the same defaults. Relative paths are resolved from the directory
+    /// of the file. `default` defaults to the first model.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        tracing::debug!("reading server config: {:?}", path);
+        let file: ServerConfigFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let directory = path.parent().unwrap_or(Path::new("."));
+        let models = file
+            .models
+            .into_iter()
+            .map(|entry| entry.into_served_model(directory))
+            .collect::<Result<Vec<_>>>()?;
+        let default = match file.default {
+            Some(default) => default,
+            None => models
+                .first()
+                .map(|model| model.name.clone())
+                .ok_or_else(|| Error::InvalidConfig {
+                    message: format!("{path:?} lists no models"),
+                })?,
+        };
+        let config = Self { models, default };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (index, model) in self.models.iter().enumerate() {
+            if self.models[..index]
+                .iter()
+                .any(|other| other.name == model.name)
+            {
+                return Err(Error::InvalidConfig {
+                    message: format!("model {:?} is listed twice", model.name),
+                });
+            }
+        }
+        if !self.models.iter().any(|model| model.name == self.default) {
+            return Err(Error::InvalidConfig {
+                message: format!("default model {:?} is not listed", self.default),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ServerConfigFile {
+    default: Option<String>,
+    models: Vec<ModelEntry>,
+}
+
+/// A model as listed in the config file, also built by the admin service from its requests.
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct ModelEntry {
+    pub(crate) name: String,
+    pub(crate) model_id: Option<String>,
+    pub(crate) model_path: Option<PathBuf>,
+    pub(crate) device: Option<String>,
+    pub(crate) dtype: Option<String>,
+    #[serde(default)]
+    pub(crate) quantize: bool,
+    pub(crate) quantize_on_load: Option<String>,
+    pub(crate) quant_variant: Option<String>,
+    #[serde(default)]
+    pub(crate) adapters: Vec<String>,
+    pub(crate) max_batch_size: Option<usize>,
+    pub(crate) max_batch_tokens: Option<usize>,
+}
+
+impl ModelEntry {
+    pub(crate) fn into_served_model(self, directory: &Path) -> Result<ServedModel> {
+        let invalid = |message: String| Error::InvalidConfig {
+            message: format!("model {:?}: {message}", self.name),
+        };
+        let model_path = self.model_path.as_ref().map(|path| directory.join(path));
+        let model_id = match (&self.model_id, &model_path) {
+            (Some(model_id), _) => model_id.clone(),
+            (None, Some(model_path)) => model_path.display().to_string(),
+            (None, None) => return Err(invalid("missing model_id or model_path".to_owned())),
+        };
+        let device = self
+            .device
+            .as_deref()
+            .map(str::parse::<llm::DeviceConfig>)
+            .transpose()
+            .map_err(|error| invalid(error.to_string()))?
+            .unwrap_or_default();
+        let dtype = self
+            .dtype
+            .as_deref()
+            .map(llm::str_to_dtype)
+            .transpose()
+            .map_err(|error| invalid(error.to_string()))?;
+        let load_quantization = self
+            .quantize_on_load
+            .as_deref()
+            .map(str::parse::<llm::Quantization>)
+            .transpose()
+            .map_err(|error| invalid(error.to_string()))?;
+        let adapters = self
+            .adapters
+            .iter()
+            .map(|adapter| {
+                adapter
+                    .parse::<llm::AdapterConfig>()
+                    .map(|adapter| llm::AdapterConfig {
+                        path: directory.join(&adapter.path),
+                        ..adapter
+                    })
+                    .map_err(|error| invalid(error.to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let defaults = llm::BatchingConfig::default();
+        Ok(ServedModel {
+            config: llm::ModelConfig {
+                model_id,
+                device,
+                dtype,
+                quantize: self.quantize,
+                quant_variant: self.quant_variant,
+                load_quantization,
+                model_path,
+                adapters,
+            },
+            batching_config: llm::BatchingConfig {
+                max_batch_size: self.max_batch_size.unwrap_or(defaults.max_batch_size),
+                max_batch_tokens: self.max_batch_tokens.unwrap_or(defaults.max_batch_tokens),
+            },
+            name: self.name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `content` as a config file in its own temporary directory and reads it back.
+    fn from_json(content: serde_json::Value) -> Result<ServerConfig> {
+        let directory =
+            std::env::temp_dir().join(format!("server-config-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory)?;
+        let path = directory.join("models.json");
+        std::fs::write(&path, content.to_string())?;
+        let config = ServerConfig::from_file(&path);
+        std::fs::remove_dir_all(&directory)?;
+        config
+    }
+
+    fn is_invalid(config: Result<ServerConfig>, expected: &str) -> bool {
+        matches!(config, Err(Error::InvalidConfig { message }) if message.contains(expected))
+    }
+
+    #[test]
+    fn parses_the_models_of_the_file() -> Result<()> {
+        let config = from_json(serde_json::json!({
+            "models": [
+                { "name": "mistral", "model_id": "mistralai/Mistral-7B-Instruct-v0.2" },
+                {
+                    "name": "qwen",
+                    "model_path": "qwen2.gguf",
+                    "device": "cuda:1",
+                    "dtype": "bf16",
+                    "quantize_on_load": "Q4_K",
+                    "adapters": ["chat=adapters/chat"],
+                    "max_batch_size": 8
+                }
+            ]
+        }))?;
+        assert_eq!(config.default, "mistral");
+        let [mistral, qwen] = config.models.as_slice() else {
+            panic!("expected two models, got {:?}", config.models);
+        };
+        assert_eq!(mistral.name, "mistral");
+        assert_eq!(
+            mistral.config.model_id,
+            "mistralai/Mistral-7B-Instruct-v0.2"
+        );
+        assert_eq!(mistral.config.device, llm::DeviceConfig::Auto);
+        assert!(mistral.config.model_path.is_none());
+
+        let defaults = llm::BatchingConfig::default();
+        assert_eq!(qwen.batching_config.max_batch_size, 8);
+        assert_eq!(
+            qwen.batching_config.max_batch_tokens,
+            defaults.max_batch_tokens
+        );
+        assert_eq!(qwen.config.device, llm::DeviceConfig::Cuda(1));
+        assert_eq!(qwen.config.dtype, Some(llm::DType::BF16));
+        assert_eq!(qwen.config.load_quantization, Some(llm::Quantization::Q4K));
+        // Paths are resolved from the directory of the file, the model id defaults to the path.
+        let model_path = qwen.config.model_path.as_ref().unwrap();
+        assert!(model_path.is_absolute() && model_path.ends_with("qwen2.gguf"));
+        assert_eq!(qwen.config.model_id, model_path.display().to_string());
+        assert_eq!(qwen.config.adapters[0].name, "chat");
+        assert_eq!(
+            qwen.config.adapters[0].path,
+            model_path.parent().unwrap().join("adapters/chat")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let models = serde_json::json!([
+            { "name": "mistral", "model_id": "mistral" },
+            { "name": "qwen", "model_id": "qwen" }
+        ]);
+        let config = from_json(serde_json::json!({ "default": "qwen", "models": models }));
+        assert_eq!(config.unwrap().default, "qwen");
+
+        let config = from_json(serde_json::json!({ "default": "llama", "models": models }));
+        assert!(is_invalid(config, "default model \"llama\" is not listed"));
+
+        let config = from_json(serde_json::json!({
+            "models": [
+                { "name": "mistral", "model_id": "mistral" },
+                { "name": "mistral", "model_id": "qwen" }
+            ]
+        }));
+        assert!(is_invalid(config, "model \"mistral\" is listed twice"));
+
+        let config = from_json(serde_json::json!({ "models": [] }));
+        assert!(is_invalid(config, "lists no models"));
+
+        let config = from_json(serde_json::json!({ "models": [{ "name": "mistral" }] }));
+        assert!(is_invalid(config, "missing model_id or model_path"));
+
+        let config = from_json(serde_json::json!({
+            "models": [{ "name": "mistral", "model_id": "mistral", "dtype": "f8" }]
+        }));
+        assert!(is_invalid(config, "model \"mistral\""));
+    }
+}
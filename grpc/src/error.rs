@@ -7,11 +7,17 @@ pub enum Error {
     #[error(transparent)]
     TokenizerError(#[from] llm::TokenizerError),
     #[error(transparent)]
+    StdIoError(#[from] std::io::Error),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error(transparent)]
     AddrParseError(#[from] std::net::AddrParseError),
     #[error(transparent)]
     TonicTransportError(#[from] tonic::transport::Error),
     #[error(transparent)]
     TonicReflectionError(#[from] tonic_reflection::server::Error),
+    #[error("Invalid server config: {message}")]
+    InvalidConfig { message: String },
     #[error("Internal error: {message}")]
     InternalError { message: String },
 }
@@ -1,14 +1,18 @@
 extern crate clap;
 extern crate llm;
 extern crate rand;
+extern crate serde;
+extern crate serde_json;
 extern crate thiserror;
 extern crate tonic;
 extern crate tracing;
 extern crate tracing_subscriber;
 extern crate uuid;
 
+pub mod config;
 mod error;
 pub mod logging;
+pub mod models;
 pub mod utils;
 pub mod v1;
 
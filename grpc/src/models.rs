@@ -0,0 +1,215 @@
+use crate::config::{ServedModel, ServerConfig};
+use crate::{Error, Result};
+use std::sync::{Arc, RwLock};
+use tonic::Status;
+
+/// A model the services serve: its generation pipeline, which holds the tokenizer, and chat
+/// template.
+#[derive(Debug)]
+pub struct LoadedModel {
+    pub name: String,
+    pub model_id: String,
+    pub generator: llm::Generator,
+    pub template: Option<llm::ChatTemplate>,
+}
+
+impl LoadedModel {
+    /// Loads the tokenizer and the weights of `model` and starts its generation pipeline. The
+    /// weights are read on a blocking thread, so the server keeps answering while they load.
+    pub async fn load(model: &ServedModel) -> Result<Self> {
+        tracing::info!("Loading model {:?}: {:?}", &model.name, &model.config);
+        let config = model.config.clone();
+        let generation = tokio::task::spawn_blocking(move || llm::TextGeneration::new(config))
+            .await
+            .map_err(|error| Error::InternalError {
+                message: error.to_string(),
+            })??;
+        let template = generation.tokenizer.template.clone();
+        if template.is_none() {
+            tracing::debug!("No chat template found for model: {:?}", &model.name);
+        }
+        let generator = llm::Generator::new(generation, model.batching_config).await;
+        Ok(Self {
+            name: model.name.clone(),
+            model_id: model.config.model_id.clone(),
+            generator,
+            template,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Models {
+    models: Vec<Arc<LoadedModel>>,
+    default: String,
+}
+
+/// The models shared by the llm, prompt and admin services. Loading or unloading a model swaps
+/// its entry under a single lock, so both services move to the new model and tokenizer from the
+/// same request on. Requests already holding the previous model finish on it.
+#[derive(Debug)]
+pub struct ModelRegistry {
+    models: RwLock<Models>,
+}
+
+// The lookups fail with the `Status` the services return as is.
+#[allow(clippy::result_large_err)]
+impl ModelRegistry {
+    /// Loads every model of `config`.
+    pub async fn load(config: &ServerConfig) -> Result<Self> {
+        let mut models = Vec::with_capacity(config.models.len());
+        for model in config.models.iter() {
+            models.push(Arc::new(LoadedModel::load(model).await?));
+        }
+        Ok(Self {
+            models: RwLock::new(Models {
+                models,
+                default: config.default.clone(),
+            }),
+        })
+    }
+
+    /// The model served as `name`, the default one when empty.
+    pub fn get(&self, name: &str) -> std::result::Result<Arc<LoadedModel>, Status> {
+        let models = self.models.read().expect("model registry lock poisoned");
+        let name = if name.is_empty() {
+            models.default.as_str()
+        } else {
+            name
+        };
+        models
+            .models
+            .iter()
+            .find(|model| model.name == name)
+            .cloned()
+            .ok_or_else(|| {
+                let names: Vec<&str> = models
+                    .models
+                    .iter()
+                    .map(|model| model.name.as_str())
+                    .collect();
+                Status::not_found(format!(
+                    "Unknown model {name:?}, the served models are {names:?}"
+                ))
+            })
+    }
+
+    /// The served models and the name of the default one.
+    pub fn list(&self) -> (Vec<Arc<LoadedModel>>, String) {
+        let models = self.models.read().expect("model registry lock poisoned");
+        (models.models.clone(), models.default.clone())
+    }
+
+    /// Serves `model`, replacing the model served under the same name, which is returned so
+    /// that it can be drained.
+    pub fn insert(&self, model: LoadedModel, make_default: bool) -> Option<Arc<LoadedModel>> {
+        let mut models = self.models.write().expect("model registry lock poisoned");
+        if make_default {
+            models.default = model.name.clone();
+        }
+        let model = Arc::new(model);
+        match models
+            .models
+            .iter_mut()
+            .find(|served| served.name == model.name)
+        {
+            Some(served) => Some(std::mem::replace(served, model)),
+            None => {
+                models.models.push(model);
+                None
+            }
+        }
+    }
+
+    /// Stops serving the model `name`. The default model can not be removed, another model has
+    /// to be made the default first.
+    pub fn remove(&self, name: &str) -> std::result::Result<Arc<LoadedModel>, Status> {
+        let mut models = self.models.write().expect("model registry lock poisoned");
+        if models.default == name {
+            return Err(Status::failed_precondition(format!(
+                "Model {name:?} is the default model, load another default model first"
+            )));
+        }
+        let index = models
+            .models
+            .iter()
+            .position(|model| model.name == name)
+            .ok_or_else(|| Status::not_found(format!("Unknown model {name:?}")))?;
+        Ok(models.models.remove(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn served(fixture: &llm::TinyMistral, name: &str) -> ServedModel {
+        ServedModel {
+            name: name.to_owned(),
+            config: fixture.model_config(),
+            batching_config: Default::default(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn routes_the_requests_by_model_name(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let fixture = llm::TinyMistral::new()?;
+        let config = ServerConfig {
+            models: vec![served(&fixture, "first"), served(&fixture, "second")],
+            default: "second".to_owned(),
+        };
+        let registry = ModelRegistry::load(&config).await?;
+
+        assert_eq!(registry.get("")?.name, "second");
+        assert_eq!(registry.get("first")?.name, "first");
+        assert_eq!(registry.get("second")?.name, "second");
+        let status = registry.get("third").unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        assert!(status.message().contains(r#"["first", "second"]"#));
+
+        let (models, default) = registry.list();
+        let names: Vec<&str> = models.iter().map(|model| model.name.as_str()).collect();
+        assert_eq!(names, vec!["first", "second"]);
+        assert_eq!(default, "second");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn swaps_and_removes_models() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let fixture = llm::TinyMistral::new()?;
+        let registry = ModelRegistry::load(&ServerConfig::single(
+            fixture.model_config(),
+            Default::default(),
+        ))
+        .await?;
+        let previous = registry.get("tiny-mistral")?;
+
+        // A new name is added, the default only moves when asked to.
+        let third = LoadedModel::load(&served(&fixture, "third")).await?;
+        assert!(registry.insert(third, false).is_none());
+        assert_eq!(registry.get("")?.name, "tiny-mistral");
+
+        // The same name replaces the served model and hands back the previous one.
+        let replacement = LoadedModel::load(&served(&fixture, "tiny-mistral")).await?;
+        let replaced = registry
+            .insert(replacement, false)
+            .expect("no replaced model");
+        assert!(Arc::ptr_eq(&replaced, &previous));
+        assert!(!Arc::ptr_eq(&registry.get("tiny-mistral")?, &previous));
+        assert_eq!(registry.list().0.len(), 2);
+
+        let status = registry.remove("tiny-mistral").unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        let third = LoadedModel::load(&served(&fixture, "third")).await?;
+        registry.insert(third, true);
+        assert_eq!(registry.get("")?.name, "third");
+        assert_eq!(registry.remove("tiny-mistral")?.name, "tiny-mistral");
+        assert_eq!(
+            registry.remove("tiny-mistral").unwrap_err().code(),
+            tonic::Code::NotFound
+        );
+        assert_eq!(registry.list().0.len(), 1);
+        Ok(())
+    }
+}
@@ -13,10 +13,20 @@ impl From<PromptConfig> for llm::PromptConfig {
         Self {
             max_new_tokens: utils::default_to_optional(value.max_new_tokens).unwrap_or(200),
             num_beams: utils::default_to_optional(value.num_beams),
+            num_return_sequences: utils::default_to_optional(value.num_return_sequences),
+            length_penalty: utils::default_to_optional(value.length_penalty),
+            early_stopping: value.early_stopping,
             temperature: utils::default_to_optional(value.temperature as f64),
             top_k: utils::default_to_optional(value.top_k as usize),
             top_p: utils::default_to_optional(value.top_p as f64),
             repetition_penalty: utils::default_to_optional(value.repetition_penalty),
+            frequency_penalty: utils::default_to_optional(value.frequency_penalty),
+            presence_penalty: utils::default_to_optional(value.presence_penalty),
+            penalty_window: utils::default_to_optional(value.penalty_window.max(0) as usize),
+            stop: value.stop,
+            stop_token_ids: value.stop_token_ids,
+            include_stop: value.include_stop,
+            logprobs: value.logprobs.then_some(value.top_logprobs.max(0) as usize),
             seed,
         }
     }
@@ -35,6 +45,7 @@ impl From<PromptRequest> for llm::Prompt {
             id,
             content: value.content,
             config,
+            adapter: utils::default_to_optional(value.adapter),
         }
     }
 }
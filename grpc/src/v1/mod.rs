@@ -1,6 +1,10 @@
 mod messages;
+#[allow(non_camel_case_types)]
 mod pb;
 
+pub mod admin {
+    pub use super::pb::v1_admin_service::*;
+}
 pub mod llm {
     pub use super::pb::v1_llm_service::*;
 }
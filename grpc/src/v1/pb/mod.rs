@@ -1,4 +1,7 @@
 // This file is @generated by prost-build.
+pub mod v1_admin_service {
+    include!("v1_admin_service.rs");
+}
 pub mod v1_llm_service {
     include!("v1_llm_service.rs");
 }
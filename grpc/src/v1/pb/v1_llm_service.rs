@@ -17,6 +17,42 @@ pub struct PromptConfig {
     pub repetition_penalty: f32,
     #[prost(int64, tag = "7")]
     pub seed: i64,
+    /// Number of beams returned in PromptReply.sequences when num_beams > 1 (defaults to 1).
+    #[prost(int32, tag = "8")]
+    pub num_return_sequences: i32,
+    /// Exponent applied to the sequence length when scoring beams (defaults to 1.0).
+    #[prost(float, tag = "9")]
+    pub length_penalty: f32,
+    /// Stop the beam search as soon as num_beams sequences are finished.
+    #[prost(bool, tag = "10")]
+    pub early_stopping: bool,
+    /// Subtracted from a token's logit once per occurrence in the generated tokens of the penalty
+    /// window, the prompt is not counted.
+    #[prost(float, tag = "11")]
+    pub frequency_penalty: f32,
+    /// Subtracted from a token's logit if it occurs in the generated tokens of the penalty window.
+    #[prost(float, tag = "12")]
+    pub presence_penalty: f32,
+    /// Number of most recent tokens (prompt included) the penalties look at, 0 for the whole sequence.
+    #[prost(int32, tag = "13")]
+    pub penalty_window: i32,
+    /// Generation stops once any of these strings appears in the generated text. Beam search
+    /// sequences are cut at the first one once the search is done.
+    #[prost(string, repeated, tag = "14")]
+    pub stop: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// Generation stops once any of these tokens is sampled.
+    #[prost(uint32, repeated, tag = "15")]
+    pub stop_token_ids: ::prost::alloc::vec::Vec<u32>,
+    /// Keep the matched stop string or token in the returned content.
+    #[prost(bool, tag = "16")]
+    pub include_stop: bool,
+    /// Return the log probability of every sampled token in PromptReply.logprobs.
+    #[prost(bool, tag = "17")]
+    pub logprobs: bool,
+    /// Number of most likely alternatives returned with each sampled token when logprobs is set, at
+    /// most 20.
+    #[prost(int32, tag = "18")]
+    pub top_logprobs: i32,
 }
 /// A request for llm streaming generation.
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -28,6 +64,12 @@ pub struct PromptRequest {
     pub content: ::prost::alloc::string::String,
     #[prost(message, optional, tag = "3")]
     pub config: ::core::option::Option<PromptConfig>,
+    /// Name of the LoRA adapter to generate with, the base model when empty.
+    #[prost(string, tag = "4")]
+    pub adapter: ::prost::alloc::string::String,
+    /// Name of the model to generate with, as listed by list_models. The default model when empty.
+    #[prost(string, tag = "5")]
+    pub model: ::prost::alloc::string::String,
 }
 /// Data about the generation process and the model.
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -39,6 +81,12 @@ pub struct PromptMetaData {
     pub average_batch_size: f32,
     #[prost(message, optional, tag = "3")]
     pub model: ::core::option::Option<prompt_meta_data::Model>,
+    /// Milliseconds between the request being received and its first forward pass.
+    #[prost(float, tag = "4")]
+    pub queue_wait_ms: f32,
+    /// Milliseconds between the request being received and its first generated token.
+    #[prost(float, tag = "5")]
+    pub time_to_first_token_ms: f32,
 }
 /// Nested message and enum types in `PromptMetaData`.
 pub mod prompt_meta_data {
@@ -55,22 +103,171 @@ pub mod prompt_meta_data {
         pub number_of_devices: i32,
     }
 }
+/// A finished beam search sequence.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Sequence {
+    #[prost(string, tag = "1")]
+    pub content: ::prost::alloc::string::String,
+    #[prost(float, tag = "2")]
+    pub score: f32,
+}
+/// Which of the request's stop conditions ended the generation.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StopReason {
+    #[prost(oneof = "stop_reason::Reason", tags = "1, 2")]
+    pub reason: ::core::option::Option<stop_reason::Reason>,
+}
+/// Nested message and enum types in `StopReason`.
+pub mod stop_reason {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Reason {
+        #[prost(string, tag = "1")]
+        Sequence(::prost::alloc::string::String),
+        #[prost(uint32, tag = "2")]
+        TokenId(u32),
+    }
+}
+/// Token counts of a request.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Usage {
+    #[prost(uint32, tag = "1")]
+    pub prompt_tokens: u32,
+    #[prost(uint32, tag = "2")]
+    pub completion_tokens: u32,
+}
+/// Log probability of a single token.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TokenLogProb {
+    #[prost(uint32, tag = "1")]
+    pub token_id: u32,
+    #[prost(string, tag = "2")]
+    pub token: ::prost::alloc::string::String,
+    #[prost(float, tag = "3")]
+    pub logprob: f32,
+}
+/// Log probability of the sampled token and of the most likely tokens at its position.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LogProbs {
+    #[prost(message, optional, tag = "1")]
+    pub token: ::core::option::Option<TokenLogProb>,
+    /// Sorted from most to least likely.
+    #[prost(message, repeated, tag = "2")]
+    pub alternatives: ::prost::alloc::vec::Vec<TokenLogProb>,
+}
 /// A generated chuck where if is_end_of_sequence is True it will be the last of the stream.
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct PromptReply {
     #[prost(string, tag = "1")]
     pub id: ::prost::alloc::string::String,
+    /// Text generated so far, the prompt is not included.
     #[prost(string, tag = "2")]
     pub content: ::prost::alloc::string::String,
     #[prost(bool, tag = "3")]
     pub is_end_of_sequence: bool,
     #[prost(message, optional, tag = "4")]
     pub config: ::core::option::Option<PromptConfig>,
+    /// Set on the last reply.
     #[prost(message, optional, tag = "5")]
     pub meta: ::core::option::Option<PromptMetaData>,
+    /// Text added since the previous reply.
     #[prost(string, tag = "6")]
     pub generated: ::prost::alloc::string::String,
+    /// Best beams (highest score first), only set on the last reply of a beam search.
+    #[prost(message, repeated, tag = "7")]
+    pub sequences: ::prost::alloc::vec::Vec<Sequence>,
+    /// Set on the last reply when a stop string or stop token ended the generation.
+    #[prost(message, optional, tag = "8")]
+    pub stop_reason: ::core::option::Option<StopReason>,
+    /// Set on the last reply.
+    #[prost(enumeration = "FinishReason", tag = "9")]
+    pub finish_reason: i32,
+    /// Set on the last reply.
+    #[prost(message, optional, tag = "10")]
+    pub usage: ::core::option::Option<Usage>,
+    /// Set when PromptConfig.logprobs is, not computed for beam search.
+    #[prost(message, optional, tag = "11")]
+    pub logprobs: ::core::option::Option<LogProbs>,
+}
+/// Request to list the models served.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListModelsRequest {}
+/// A model served with its own generation pipeline.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ModelInfo {
+    /// Name PromptRequest.model selects the model with.
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    /// Hub repo id or local path the model was loaded from.
+    #[prost(string, tag = "2")]
+    pub model_id: ::prost::alloc::string::String,
+    /// Requests without a model go to the default one.
+    #[prost(bool, tag = "3")]
+    pub is_default: bool,
+    /// LoRA adapters PromptRequest.adapter can select.
+    #[prost(string, repeated, tag = "4")]
+    pub adapters: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(message, optional, tag = "5")]
+    pub meta: ::core::option::Option<prompt_meta_data::Model>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListModelsReply {
+    /// The default model first.
+    #[prost(message, repeated, tag = "1")]
+    pub models: ::prost::alloc::vec::Vec<ModelInfo>,
+}
+/// Why the generation of a request ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum FinishReason {
+    Unspecified = 0,
+    /// A stop string or stop token was generated.
+    Stop = 1,
+    /// max_new_tokens were generated.
+    Length = 2,
+    /// The model generated its end of sequence token.
+    Eos = 3,
+    /// The client disconnected.
+    Cancelled = 4,
+    /// The generation failed.
+    Error = 5,
+}
+impl FinishReason {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            FinishReason::Unspecified => "FINISH_REASON_UNSPECIFIED",
+            FinishReason::Stop => "FINISH_REASON_STOP",
+            FinishReason::Length => "FINISH_REASON_LENGTH",
+            FinishReason::Eos => "FINISH_REASON_EOS",
+            FinishReason::Cancelled => "FINISH_REASON_CANCELLED",
+            FinishReason::Error => "FINISH_REASON_ERROR",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "FINISH_REASON_UNSPECIFIED" => Some(Self::Unspecified),
+            "FINISH_REASON_STOP" => Some(Self::Stop),
+            "FINISH_REASON_LENGTH" => Some(Self::Length),
+            "FINISH_REASON_EOS" => Some(Self::Eos),
+            "FINISH_REASON_CANCELLED" => Some(Self::Cancelled),
+            "FINISH_REASON_ERROR" => Some(Self::Error),
+            _ => None,
+        }
+    }
 }
 /// Generated client implementations.
 pub mod llm_client {
@@ -182,6 +379,32 @@ pub mod llm_client {
             req.extensions_mut().insert(GrpcMethod::new("v1_llm_service.Llm", "prompt"));
             self.inner.server_streaming(req, path, codec).await
         }
+        /// Lists the models served.
+        pub async fn list_models(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListModelsRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ListModelsReply>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/v1_llm_service.Llm/list_models",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("v1_llm_service.Llm", "list_models"));
+            self.inner.unary(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -202,6 +425,14 @@ pub mod llm_server {
             &self,
             request: tonic::Request<super::PromptRequest>,
         ) -> std::result::Result<tonic::Response<Self::promptStream>, tonic::Status>;
+        /// Lists the models served.
+        async fn list_models(
+            &self,
+            request: tonic::Request<super::ListModelsRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ListModelsReply>,
+            tonic::Status,
+        >;
     }
     #[derive(Debug)]
     pub struct LlmServer<T: Llm> {
@@ -329,6 +560,52 @@ pub mod llm_server {
                     };
                     Box::pin(fut)
                 }
+                "/v1_llm_service.Llm/list_models" => {
+                    #[allow(non_camel_case_types)]
+                    struct list_modelsSvc<T: Llm>(pub Arc<T>);
+                    impl<
+                        T: Llm,
+                    > tonic::server::UnaryService<super::ListModelsRequest>
+                    for list_modelsSvc<T> {
+                        type Response = super::ListModelsReply;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListModelsRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as Llm>::list_models(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = list_modelsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         Ok(
@@ -28,6 +28,9 @@ pub struct ApplyTemplateRequest {
     /// If it's not present and no custom_template provided than will return error)
     #[prost(string, tag = "3")]
     pub custom_template: ::prost::alloc::string::String,
+    /// Optional (name of the model whose template is used, the default model when empty)
+    #[prost(string, tag = "4")]
+    pub model: ::prost::alloc::string::String,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -44,7 +47,11 @@ pub struct ApplyTemplateReply {
 /// Request to use a jninja template to apply format a series of messages.
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
-pub struct GetTemplateRequest {}
+pub struct GetTemplateRequest {
+    /// Optional (name of the model, the default model when empty)
+    #[prost(string, tag = "1")]
+    pub model: ::prost::alloc::string::String,
+}
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct GetTemplateReply {
@@ -0,0 +1,127 @@
+use crate::config::ModelEntry;
+use crate::models::{LoadedModel, ModelRegistry};
+use crate::utils;
+use crate::v1::admin::*;
+use crate::EndpointResult;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Request, Response, Status};
+
+#[derive(Debug)]
+pub struct AdminServer {
+    models: Arc<ModelRegistry>,
+    /// Loads and unloads run one at a time, so that two swaps of a model do not interleave and
+    /// only one set of new weights is loading at once.
+    changes: tokio::sync::Mutex<()>,
+}
+
+impl AdminServer {
+    pub fn new(models: Arc<ModelRegistry>) -> Self {
+        Self {
+            models,
+            changes: tokio::sync::Mutex::new(()),
+        }
+    }
+}
+
+/// Drains the generator of a model that is no longer served, returns the number of cancelled
+/// requests. The weights are freed once the last reference to the model is dropped.
+async fn drain(model: Arc<LoadedModel>, drain_timeout_ms: u64) -> u32 {
+    let timeout = utils::default_to_optional(drain_timeout_ms).map(Duration::from_millis);
+    let cancelled = model.generator.drain(timeout).await;
+    tracing::info!(
+        "Model {:?} drained, {} requests cancelled",
+        &model.name,
+        cancelled
+    );
+    cancelled as u32
+}
+
+#[tonic::async_trait]
+impl admin_server::Admin for AdminServer {
+    async fn load_model(&self, req: Request<LoadModelRequest>) -> EndpointResult<LoadModelReply> {
+        let LoadModelRequest {
+            name,
+            config,
+            make_default,
+            drain_timeout_ms,
+        } = req.into_inner();
+        if name.is_empty() {
+            return Err(Status::invalid_argument("Missing model name"));
+        }
+        let config = config.ok_or_else(|| Status::invalid_argument("Missing model config"))?;
+        let model = ModelEntry::from((name, config))
+            .into_served_model(Path::new(""))
+            .map_err(|error| Status::invalid_argument(error.to_string()))?;
+
+        let _changes = self.changes.lock().await;
+        // The previous model keeps serving while the new one loads.
+        let loaded = LoadedModel::load(&model).await?;
+        let previous = self.models.insert(loaded, make_default);
+        let swapped = previous.is_some();
+        let cancelled_requests = match previous {
+            Some(previous) => drain(previous, drain_timeout_ms).await,
+            None => 0,
+        };
+        tracing::info!("Model {:?} loaded, swapped: {}", &model.name, swapped);
+        Ok(Response::new(LoadModelReply {
+            name: model.name,
+            swapped,
+            cancelled_requests,
+        }))
+    }
+
+    async fn unload_model(
+        &self,
+        req: Request<UnloadModelRequest>,
+    ) -> EndpointResult<UnloadModelReply> {
+        let UnloadModelRequest {
+            name,
+            drain_timeout_ms,
+        } = req.into_inner();
+        let _changes = self.changes.lock().await;
+        let model = self.models.remove(&name)?;
+        let cancelled_requests = drain(model, drain_timeout_ms).await;
+        Ok(Response::new(UnloadModelReply {
+            name,
+            cancelled_requests,
+        }))
+    }
+}
+
+impl From<(String, ModelConfig)> for ModelEntry {
+    fn from((name, config): (String, ModelConfig)) -> Self {
+        let ModelConfig {
+            model_id,
+            model_path,
+            device,
+            dtype,
+            quantize,
+            quantize_on_load,
+            quant_variant,
+            adapters,
+            max_batch_size,
+            max_batch_tokens,
+        } = config;
+        Self {
+            name,
+            model_id: utils::default_to_optional(model_id),
+            model_path: utils::default_to_optional(model_path).map(PathBuf::from),
+            device: utils::default_to_optional(device),
+            dtype: utils::default_to_optional(dtype),
+            quantize,
+            quantize_on_load: utils::default_to_optional(quantize_on_load),
+            quant_variant: utils::default_to_optional(quant_variant),
+            adapters,
+            max_batch_size: utils::default_to_optional(max_batch_size).map(|size| size as usize),
+            max_batch_tokens: utils::default_to_optional(max_batch_tokens)
+                .map(|tokens| tokens as usize),
+        }
+    }
+}
+
+pub fn service(models: Arc<ModelRegistry>) -> admin_server::AdminServer<AdminServer> {
+    tracing::info!("Adding admin service");
+    admin_server::AdminServer::new(AdminServer::new(models))
+}
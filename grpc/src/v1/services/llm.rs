@@ -1,21 +1,19 @@
+use crate::models::ModelRegistry;
 use crate::v1::llm::*;
 use crate::{EndpointResult, EndpointStream};
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 
 #[derive(Debug)]
 pub struct LlmServer {
-    generator: llm::Generator,
+    models: Arc<ModelRegistry>,
 }
 
 impl LlmServer {
-    pub async fn new(config: llm::ModelConfig) -> crate::Result<Self> {
-        Ok(Self {
-            generator: llm::Generator::from_model_config(config)
-                .await
-                .expect("Error initializing text generation"),
-        })
+    pub fn new(models: Arc<ModelRegistry>) -> Self {
+        Self { models }
     }
 }
 
@@ -29,7 +27,18 @@ impl llm_server::Llm for LlmServer {
             req.remote_addr()
         );
 
-        match self.generator.prompt(req.into_inner().into()).await {
+        let request = req.into_inner();
+        let model = self.models.get(&request.model)?;
+        match model.generator.prompt(request.into()).await {
+            Err(llm::Error::GeneratorDraining) => {
+                return Err(Status::unavailable(format!(
+                    "Model {:?} is being unloaded",
+                    &model.name
+                )));
+            }
+            Err(error @ llm::Error::InvalidPromptConfig { .. }) => {
+                return Err(Status::invalid_argument(error.to_string()));
+            }
             Err(error) => {
                 let error: crate::Error = error.into();
                 return Err(error.into());
@@ -60,14 +69,30 @@ impl llm_server::Llm for LlmServer {
             }
         }
     }
+
+    async fn list_models(
+        &self,
+        _req: Request<ListModelsRequest>,
+    ) -> EndpointResult<ListModelsReply> {
+        let (models, default) = self.models.list();
+        let mut models: Vec<ModelInfo> = models
+            .iter()
+            .map(|served| ModelInfo {
+                name: served.name.clone(),
+                model_id: served.model_id.clone(),
+                is_default: served.name == default,
+                adapters: served.generator.adapters().to_vec(),
+                meta: Some(served.generator.meta_data().clone().into()),
+            })
+            .collect();
+        models.sort_by_key(|model| !model.is_default);
+        Ok(Response::new(ListModelsReply { models }))
+    }
 }
 
-pub async fn service(config: llm::ModelConfig) -> llm_server::LlmServer<LlmServer> {
+pub fn service(models: Arc<ModelRegistry>) -> llm_server::LlmServer<LlmServer> {
     tracing::info!("Adding llm service");
-    let server = LlmServer::new(config)
-        .await
-        .expect("Error loading llm service");
-    llm_server::LlmServer::new(server)
+    llm_server::LlmServer::new(LlmServer::new(models))
 }
 
 impl From<llm::GenerationResult> for PromptReply {
@@ -78,14 +103,30 @@ impl From<llm::GenerationResult> for PromptReply {
             config,
             content,
             generated,
+            sequences,
+            stopped_by,
+            finish_reason,
+            usage,
+            meta,
+            logprobs,
         } = value;
         Self {
             id,
             content,
             is_end_of_sequence,
             config: Some(config.into()),
-            meta: None,
+            meta: meta.map(|meta| meta.into()),
             generated,
+            sequences: sequences
+                .into_iter()
+                .map(|sequence| sequence.into())
+                .collect(),
+            stop_reason: stopped_by.map(|stopped_by| stopped_by.into()),
+            finish_reason: finish_reason
+                .map(|finish_reason| FinishReason::from(finish_reason) as i32)
+                .unwrap_or_default(),
+            usage: usage.map(|usage| usage.into()),
+            logprobs: logprobs.map(|logprobs| logprobs.into()),
         }
     }
 }
@@ -95,20 +136,210 @@ impl From<llm::PromptConfig> for PromptConfig {
         let llm::PromptConfig {
             max_new_tokens,
             num_beams,
+            num_return_sequences,
+            length_penalty,
+            early_stopping,
             temperature,
             top_k,
             top_p,
             repetition_penalty,
+            frequency_penalty,
+            presence_penalty,
+            penalty_window,
+            stop,
+            stop_token_ids,
+            include_stop,
+            logprobs,
             seed,
         } = value;
         Self {
             max_new_tokens,
             num_beams: num_beams.unwrap_or_default(),
+            num_return_sequences: num_return_sequences.unwrap_or_default(),
+            length_penalty: length_penalty.unwrap_or_default(),
+            early_stopping,
             temperature: temperature.unwrap_or_default() as f32,
             top_k: top_k.unwrap_or_default() as i32,
             top_p: top_p.unwrap_or_default() as f32,
             repetition_penalty: repetition_penalty.unwrap_or_default(),
+            frequency_penalty: frequency_penalty.unwrap_or_default(),
+            presence_penalty: presence_penalty.unwrap_or_default(),
+            penalty_window: penalty_window.unwrap_or_default() as i32,
+            stop,
+            stop_token_ids,
+            include_stop,
+            logprobs: logprobs.is_some(),
+            top_logprobs: logprobs.unwrap_or_default() as i32,
             seed: seed as i64,
         }
     }
 }
+
+impl From<llm::GeneratedSequence> for Sequence {
+    fn from(value: llm::GeneratedSequence) -> Self {
+        let llm::GeneratedSequence { content, score } = value;
+        Self { content, score }
+    }
+}
+
+impl From<llm::StopCondition> for StopReason {
+    fn from(value: llm::StopCondition) -> Self {
+        let reason = match value {
+            llm::StopCondition::Sequence(sequence) => stop_reason::Reason::Sequence(sequence),
+            llm::StopCondition::TokenId(token_id) => stop_reason::Reason::TokenId(token_id),
+        };
+        Self {
+            reason: Some(reason),
+        }
+    }
+}
+
+impl From<llm::FinishReason> for FinishReason {
+    fn from(value: llm::FinishReason) -> Self {
+        match value {
+            llm::FinishReason::Stop => FinishReason::Stop,
+            llm::FinishReason::Length => FinishReason::Length,
+            llm::FinishReason::Eos => FinishReason::Eos,
+            llm::FinishReason::Cancelled => FinishReason::Cancelled,
+            llm::FinishReason::Error => FinishReason::Error,
+        }
+    }
+}
+
+impl From<llm::Usage> for Usage {
+    fn from(value: llm::Usage) -> Self {
+        let llm::Usage {
+            prompt_tokens,
+            completion_tokens,
+        } = value;
+        Self {
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+}
+
+impl From<llm::GenerationMetaData> for PromptMetaData {
+    fn from(value: llm::GenerationMetaData) -> Self {
+        let llm::GenerationMetaData {
+            tokens_per_second,
+            average_batch_size,
+            queue_wait,
+            time_to_first_token,
+            model,
+        } = value;
+        Self {
+            tokens_per_second,
+            average_batch_size,
+            model: Some(model.into()),
+            queue_wait_ms: queue_wait.as_secs_f32() * 1000.,
+            time_to_first_token_ms: time_to_first_token.as_secs_f32() * 1000.,
+        }
+    }
+}
+
+impl From<llm::ModelMetaData> for prompt_meta_data::Model {
+    fn from(value: llm::ModelMetaData) -> Self {
+        let llm::ModelMetaData {
+            device,
+            dtype,
+            model_type,
+            number_of_devices,
+        } = value;
+        Self {
+            device,
+            dtype,
+            r#type: model_type,
+            number_of_devices: number_of_devices as i32,
+        }
+    }
+}
+
+impl From<llm::TokenLogProb> for TokenLogProb {
+    fn from(value: llm::TokenLogProb) -> Self {
+        let llm::TokenLogProb {
+            token_id,
+            token,
+            logprob,
+        } = value;
+        Self {
+            token_id,
+            token,
+            logprob,
+        }
+    }
+}
+
+impl From<llm::TokenLogProbs> for LogProbs {
+    fn from(value: llm::TokenLogProbs) -> Self {
+        let llm::TokenLogProbs {
+            token,
+            alternatives,
+        } = value;
+        Self {
+            token: Some(token.into()),
+            alternatives: alternatives
+                .into_iter()
+                .map(|alternative| alternative.into())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use llm_server::Llm;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streams_the_tiny_model_and_lists_it(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let fixture = llm::TinyMistral::new()?;
+        let config = ServerConfig::single(fixture.model_config(), Default::default());
+        let server = LlmServer::new(Arc::new(ModelRegistry::load(&config).await?));
+
+        let request = PromptRequest {
+            content: "how are you".to_owned(),
+            config: Some(PromptConfig {
+                max_new_tokens: 4,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut stream = server.prompt(Request::new(request)).await?.into_inner();
+        let mut last = None;
+        while let Some(reply) = stream.next().await {
+            let reply = reply?;
+            let is_end_of_sequence = reply.is_end_of_sequence;
+            last = Some(reply);
+            if is_end_of_sequence {
+                break;
+            }
+        }
+        let last = last.expect("no prompt reply");
+        assert!(last.is_end_of_sequence);
+        assert!(
+            [FinishReason::Length as i32, FinishReason::Eos as i32].contains(&last.finish_reason)
+        );
+        assert!(last.usage.is_some_and(|usage| usage.completion_tokens <= 4));
+
+        let unknown = PromptRequest {
+            model: "unknown".to_owned(),
+            ..Default::default()
+        };
+        let status = server.prompt(Request::new(unknown)).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        let models = server
+            .list_models(Request::new(ListModelsRequest {}))
+            .await?
+            .into_inner()
+            .models;
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].name, "tiny-mistral");
+        assert!(models[0].is_default);
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+pub mod admin;
 pub mod llm;
 pub mod prompt;
 
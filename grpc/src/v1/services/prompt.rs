@@ -1,35 +1,19 @@
+use crate::models::ModelRegistry;
 use crate::utils;
 use crate::v1::prompt;
 use crate::v1::prompt::prompt_server;
 use crate::EndpointResult;
+use std::sync::Arc;
 use tonic::{Request, Response, Status};
 
 #[derive(Debug)]
 pub struct PromptServer {
-    model_id: String,
-    tokenizer: llm::Tokenizer,
-    template: Option<llm::ChatTemplate>,
+    models: Arc<ModelRegistry>,
 }
 
 impl PromptServer {
-    pub fn new(model_type: llm::ModelType) -> crate::Result<Self> {
-        let tokenizer = llm::Tokenizer::load(model_type)?;
-        if tokenizer.template.is_none() {
-            tracing::debug!(
-                "Prompt service: no chat template found for model: {:?}",
-                &model_type
-            );
-        } else {
-            tracing::debug!(
-                "Prompt service: chat template found for model: {:?}",
-                &model_type
-            );
-        }
-        Ok(Self {
-            model_id: model_type.id(),
-            template: tokenizer.template.clone(),
-            tokenizer,
-        })
+    pub fn new(models: Arc<ModelRegistry>) -> Self {
+        Self { models }
     }
 }
 
@@ -43,16 +27,18 @@ impl prompt_server::Prompt for PromptServer {
             id,
             messages,
             custom_template,
+            model,
         } = req.into_inner();
+        let model = self.models.get(&model)?;
         let template: Option<llm::ChatTemplate> =
             if let Some(custom_string) = utils::default_to_optional(custom_template) {
                 tracing::debug!(
                     "Prompt apply template using custom template: {:?}",
                     &custom_string
                 );
-                Some(self.tokenizer.new_chat_template(custom_string))
+                Some(model.generator.tokenizer().new_chat_template(custom_string))
             } else {
-                self.template.clone()
+                model.template.clone()
             };
         if let Some(template) = template {
             let raw_template = template.get_template();
@@ -69,25 +55,26 @@ impl prompt_server::Prompt for PromptServer {
         } else {
             Err(Status::not_found(format!(
                 "Could not load a template for model: {} and no custom template was given.",
-                self.model_id.clone()
+                model.model_id.clone()
             )))
         }
     }
 
     async fn get_template(
         &self,
-        _req: Request<prompt::GetTemplateRequest>,
+        req: Request<prompt::GetTemplateRequest>,
     ) -> EndpointResult<prompt::GetTemplateReply> {
-        if let Some(template) = self.template.as_ref() {
+        let model = self.models.get(&req.into_inner().model)?;
+        if let Some(template) = model.template.as_ref() {
             let response = prompt::GetTemplateReply {
-                model_id: self.model_id.clone(),
+                model_id: model.model_id.clone(),
                 template: template.get_template(),
             };
             Ok(Response::new(response))
         } else {
             Err(Status::not_found(format!(
                 "Could not load a template for model: {}",
-                self.model_id.clone()
+                model.model_id.clone()
             )))
         }
     }
@@ -100,8 +87,43 @@ impl From<prompt::Message> for llm::ChatMessage {
     }
 }
 
-pub fn service(model_type: llm::ModelType) -> prompt_server::PromptServer<PromptServer> {
+pub fn service(models: Arc<ModelRegistry>) -> prompt_server::PromptServer<PromptServer> {
     tracing::info!("Adding prompt service");
-    let server = PromptServer::new(model_type).expect("Error loading prompt service");
-    prompt_server::PromptServer::new(server)
+    prompt_server::PromptServer::new(PromptServer::new(models))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use prompt_server::Prompt;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn applies_the_template_of_the_tiny_model(
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let fixture = llm::TinyMistral::new()?;
+        let config = ServerConfig::single(fixture.model_config(), Default::default());
+        let server = PromptServer::new(Arc::new(ModelRegistry::load(&config).await?));
+
+        let template = server
+            .get_template(Request::new(prompt::GetTemplateRequest::default()))
+            .await?
+            .into_inner();
+        assert_eq!(template.template, llm::TinyMistral::CHAT_TEMPLATE);
+
+        let request = prompt::ApplyTemplateRequest {
+            messages: vec![prompt::Message {
+                role: "user".to_owned(),
+                content: "hello world".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let reply = server
+            .apply_template(Request::new(request))
+            .await?
+            .into_inner();
+        assert_eq!(reply.content, "<s>hello world ");
+        Ok(())
+    }
 }
@@ -0,0 +1,113 @@
+use super::{DeviceError, DeviceResult};
+
+/// Device a model runs on, parsed from `auto`, `cpu`, `cuda:N` or `metal:N`. `cuda` and `metal`
+/// alone select the first device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeviceConfig {
+    /// The first CUDA device when the build and the machine have one, then the first Metal
+    /// device, the CPU otherwise.
+    #[default]
+    Auto,
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl DeviceConfig {
+    /// Opens the device. Asking for a GPU the build has no support for is an error rather than a
+    /// silent fallback to the CPU.
+    pub fn candle_device(&self) -> DeviceResult<candle_core::Device> {
+        match *self {
+            DeviceConfig::Auto => {
+                if candle_core::utils::cuda_is_available() {
+                    Ok(candle_core::Device::new_cuda(0)?)
+                } else if candle_core::utils::metal_is_available() {
+                    Ok(candle_core::Device::new_metal(0)?)
+                } else {
+                    Ok(candle_core::Device::Cpu)
+                }
+            }
+            DeviceConfig::Cpu => Ok(candle_core::Device::Cpu),
+            DeviceConfig::Cuda(ordinal) => {
+                if !candle_core::utils::cuda_is_available() {
+                    return Err(DeviceError::NotCompiled {
+                        device: self.to_string(),
+                        feature: "cuda",
+                    });
+                }
+                Ok(candle_core::Device::new_cuda(ordinal)?)
+            }
+            DeviceConfig::Metal(ordinal) => {
+                if !candle_core::utils::metal_is_available() {
+                    return Err(DeviceError::NotCompiled {
+                        device: self.to_string(),
+                        feature: "metal",
+                    });
+                }
+                Ok(candle_core::Device::new_metal(ordinal)?)
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for DeviceConfig {
+    type Err = DeviceError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || DeviceError::InvalidDevice {
+            value: value.to_owned(),
+        };
+        let (kind, ordinal) = match value.split_once(':') {
+            Some((kind, ordinal)) => (kind, Some(ordinal.parse().map_err(|_| invalid())?)),
+            None => (value, None),
+        };
+        match (kind.to_lowercase().as_str(), ordinal) {
+            ("auto", None) => Ok(DeviceConfig::Auto),
+            ("cpu", None) => Ok(DeviceConfig::Cpu),
+            ("cuda", ordinal) => Ok(DeviceConfig::Cuda(ordinal.unwrap_or_default())),
+            ("metal", ordinal) => Ok(DeviceConfig::Metal(ordinal.unwrap_or_default())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceConfig::Auto => write!(f, "auto"),
+            DeviceConfig::Cpu => write!(f, "cpu"),
+            DeviceConfig::Cuda(ordinal) => write!(f, "cuda:{ordinal}"),
+            DeviceConfig::Metal(ordinal) => write!(f, "metal:{ordinal}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_devices() {
+        assert_eq!("cpu".parse::<DeviceConfig>().unwrap(), DeviceConfig::Cpu);
+        assert_eq!(
+            "cuda".parse::<DeviceConfig>().unwrap(),
+            DeviceConfig::Cuda(0)
+        );
+        assert_eq!(
+            "cuda:1".parse::<DeviceConfig>().unwrap(),
+            DeviceConfig::Cuda(1)
+        );
+        assert_eq!(
+            "metal:0".parse::<DeviceConfig>().unwrap(),
+            DeviceConfig::Metal(0)
+        );
+        assert!("cuda:x".parse::<DeviceConfig>().is_err());
+        assert!("tpu".parse::<DeviceConfig>().is_err());
+        assert!("cpu:1".parse::<DeviceConfig>().is_err());
+    }
+
+    #[test]
+    fn cpu_always_opens() {
+        assert!(DeviceConfig::Cpu.candle_device().unwrap().is_cpu());
+    }
+}
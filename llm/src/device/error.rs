@@ -2,8 +2,16 @@ pub type DeviceResult<T> = core::result::Result<T, DeviceError>;
 
 #[derive(Debug, thiserror::Error)]
 pub enum DeviceError {
+    #[cfg(feature = "cuda")]
     #[error(transparent)]
     CudaDriverError(#[from] cudarc::driver::DriverError),
     #[error(transparent)]
     CandleDeviceError(#[from] candle_core::Error),
+    #[error("Invalid device {value:?}, expected auto, cpu, cuda:N or metal:N")]
+    InvalidDevice { value: String },
+    #[error("Device {device} needs a build with the {feature:?} feature")]
+    NotCompiled {
+        device: String,
+        feature: &'static str,
+    },
 }
@@ -1,7 +1,13 @@
+#[cfg(feature = "cuda")]
 mod device;
+mod device_config;
+#[cfg(feature = "cuda")]
 mod device_map;
 mod error;
 
+#[cfg(feature = "cuda")]
 pub use device::Device;
+pub use device_config::DeviceConfig;
+#[cfg(feature = "cuda")]
 pub use device_map::DeviceMap;
 pub use error::{DeviceError, DeviceResult};
@@ -16,6 +16,15 @@ pub enum Error {
     ModelError(#[from] crate::models::ModelError),
     #[error(transparent)]
     DeviceError(#[from] crate::device::DeviceError),
+    #[error("Unknown adapter {adapter:?}, the available adapters are {available:?}")]
+    UnknownAdapter {
+        adapter: String,
+        available: Vec<String>,
+    },
+    #[error("Invalid prompt config: {message}")]
+    InvalidPromptConfig { message: String },
+    #[error("The generator is draining and no longer accepts requests")]
+    GeneratorDraining,
     #[error("Generation error: {message}")]
     GenerationError { message: String },
 }
@@ -0,0 +1,16 @@
+#[derive(Debug, Clone, Copy)]
+pub struct BatchingConfig {
+    /// Maximum number of sequences run together in a single forward pass.
+    pub max_batch_size: usize,
+    /// Maximum number of positions, `batch size * padded sequence length`, in a single forward pass.
+    pub max_batch_tokens: usize,
+}
+
+impl Default for BatchingConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 32,
+            max_batch_tokens: 16384,
+        }
+    }
+}
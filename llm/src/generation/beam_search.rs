@@ -0,0 +1,277 @@
+use crate::PromptConfig;
+
+/// Score given to the duplicated beams of the first step so only one of them gets expanded.
+const INITIAL_BEAM_SCORE: f32 = -1e9;
+
+#[derive(Debug, Clone)]
+pub struct BeamHypothesis {
+    pub token_ids: Vec<u32>,
+    pub score: f32,
+}
+
+/// Beam search state of a single request. Every running beam is one row of the batch, rows are
+/// kept in the same order as `beam_scores`.
+#[derive(Debug)]
+pub struct BeamSearch {
+    num_beams: usize,
+    length_penalty: f32,
+    early_stopping: bool,
+    num_return_sequences: usize,
+    beam_scores: Vec<f32>,
+    beam_token_ids: Vec<Vec<u32>>,
+    hypotheses: Vec<BeamHypothesis>,
+    is_done: bool,
+}
+
+impl BeamSearch {
+    /// Returns `None` when the config does not ask for more than one beam.
+    pub fn from_prompt_config(config: &PromptConfig) -> Option<Self> {
+        let num_beams = config.num_beams.unwrap_or(1).max(1) as usize;
+        if num_beams <= 1 {
+            return None;
+        }
+        let num_return_sequences = config
+            .num_return_sequences
+            .unwrap_or(1)
+            .clamp(1, num_beams as i32) as usize;
+        let mut beam_scores = vec![INITIAL_BEAM_SCORE; num_beams];
+        beam_scores[0] = 0.;
+        Some(Self {
+            num_beams,
+            length_penalty: config.length_penalty.unwrap_or(1.),
+            early_stopping: config.early_stopping,
+            num_return_sequences,
+            beam_scores,
+            beam_token_ids: vec![Vec::new(); num_beams],
+            hypotheses: Vec::with_capacity(num_beams + 1),
+            is_done: false,
+        })
+    }
+}
+
+impl BeamSearch {
+    pub fn num_beams(&self) -> usize {
+        self.num_beams
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.is_done
+    }
+
+    /// Advances every beam by one token given the log probabilities of each beam row.
+    ///
+    /// Returns for every beam of the next step the index of the beam it extends and the token
+    /// that was appended to it.
+    pub fn step(&mut self, log_probs: &[Vec<f32>], eos_ids: &[u32]) -> Vec<(usize, u32)> {
+        let generated_length = self.beam_token_ids[0].len() + 1;
+        let mut candidates: Vec<(f32, usize, u32)> =
+            Vec::with_capacity(log_probs.iter().map(|row| row.len()).sum());
+        for (beam, row) in log_probs.iter().enumerate() {
+            let beam_score = self.beam_scores[beam];
+            for (token_id, log_prob) in row.iter().enumerate() {
+                candidates.push((beam_score + log_prob, beam, token_id as u32));
+            }
+        }
+        // Enough candidates are kept for `num_beams` of them to be left after removing the ones
+        // that end the sequence, even when every end of sequence token ranks first for each beam.
+        let number_of_candidates =
+            (2.max(1 + eos_ids.len()) * self.num_beams).min(candidates.len());
+        if number_of_candidates < candidates.len() {
+            candidates.select_nth_unstable_by(number_of_candidates - 1, |a, b| b.0.total_cmp(&a.0));
+            candidates.truncate(number_of_candidates);
+        }
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let mut next_beams = Vec::with_capacity(self.num_beams);
+        for (rank, (score, beam, token_id)) in candidates.into_iter().enumerate() {
+            if eos_ids.contains(&token_id) {
+                if rank < self.num_beams {
+                    let mut token_ids = self.beam_token_ids[beam].clone();
+                    token_ids.push(token_id);
+                    self.add_hypothesis(token_ids, score);
+                }
+                continue;
+            }
+            next_beams.push((score, beam, token_id));
+            if next_beams.len() == self.num_beams {
+                break;
+            }
+        }
+
+        let best_running_score = next_beams
+            .first()
+            .map(|(score, _, _)| self.normalize(*score, generated_length))
+            .unwrap_or(f32::NEG_INFINITY);
+        // The request keeps `num_beams` batch rows, the search ends if fewer beams are left.
+        self.is_done = next_beams.len() < self.num_beams
+            || (self.hypotheses.len() >= self.num_beams
+                && (self.early_stopping || self.worst_score() >= best_running_score));
+
+        let mut beam_scores = Vec::with_capacity(next_beams.len());
+        let mut beam_token_ids = Vec::with_capacity(next_beams.len());
+        let mut parents = Vec::with_capacity(next_beams.len());
+        for (score, beam, token_id) in next_beams.into_iter() {
+            let mut token_ids = self.beam_token_ids[beam].clone();
+            token_ids.push(token_id);
+            beam_scores.push(score);
+            beam_token_ids.push(token_ids);
+            parents.push((beam, token_id));
+        }
+        self.beam_scores = beam_scores;
+        self.beam_token_ids = beam_token_ids;
+        parents
+    }
+
+    /// Adds the still running beams to the finished hypotheses and returns the best ones, sorted
+    /// from highest to lowest score.
+    pub fn finalize(&mut self) -> Vec<BeamHypothesis> {
+        let running = std::mem::take(&mut self.beam_token_ids);
+        let scores = std::mem::take(&mut self.beam_scores);
+        for (token_ids, score) in running.into_iter().zip(scores) {
+            self.add_hypothesis(token_ids, score);
+        }
+        self.is_done = true;
+        let mut hypotheses = std::mem::take(&mut self.hypotheses);
+        hypotheses.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hypotheses.truncate(self.num_return_sequences);
+        hypotheses
+    }
+
+    fn normalize(&self, score: f32, length: usize) -> f32 {
+        score / (length.max(1) as f32).powf(self.length_penalty)
+    }
+
+    fn worst_score(&self) -> f32 {
+        self.hypotheses
+            .iter()
+            .map(|hypothesis| hypothesis.score)
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    fn add_hypothesis(&mut self, token_ids: Vec<u32>, sum_log_probs: f32) {
+        let score = self.normalize(sum_log_probs, token_ids.len());
+        if self.hypotheses.len() >= self.num_beams && score <= self.worst_score() {
+            return;
+        }
+        self.hypotheses.push(BeamHypothesis { token_ids, score });
+        if self.hypotheses.len() > self.num_beams {
+            self.hypotheses.sort_by(|a, b| b.score.total_cmp(&a.score));
+            self.hypotheses.truncate(self.num_beams);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn beam_search(num_beams: i32, length_penalty: f32, early_stopping: bool) -> BeamSearch {
+        let config = PromptConfig {
+            num_beams: Some(num_beams),
+            num_return_sequences: Some(num_beams),
+            length_penalty: Some(length_penalty),
+            early_stopping,
+            ..Default::default()
+        };
+        BeamSearch::from_prompt_config(&config).unwrap()
+    }
+
+    fn ln(probabilities: &[f32]) -> Vec<f32> {
+        probabilities.iter().map(|p| p.ln()).collect()
+    }
+
+    #[test]
+    fn a_single_beam_is_greedy_sampling() {
+        let config = PromptConfig {
+            num_beams: Some(1),
+            ..Default::default()
+        };
+        assert!(BeamSearch::from_prompt_config(&config).is_none());
+    }
+
+    #[test]
+    fn step_expands_only_the_first_beam_then_keeps_the_best_ones() {
+        let mut search = beam_search(2, 1., false);
+        // The duplicated beams of the first step must not both be expanded.
+        let beams = search.step(&[ln(&[0.1, 0.6, 0.3]), ln(&[0.1, 0.6, 0.3])], &[]);
+        assert_eq!(beams, vec![(0, 1), (0, 2)]);
+
+        // Beam 1 continues with a sure token and overtakes beam 0.
+        let beams = search.step(&[ln(&[0.34, 0.33, 0.33]), ln(&[0.98, 0.01, 0.01])], &[]);
+        assert_eq!(beams, vec![(1, 0), (0, 0)]);
+        assert_eq!(search.beam_token_ids, vec![vec![2, 0], vec![1, 0]]);
+        assert!(!search.is_done());
+    }
+
+    #[test]
+    fn ended_beams_become_hypotheses_and_stop_the_search() {
+        let eos = 0;
+        let mut search = beam_search(2, 1., true);
+        search.step(&[ln(&[0.1, 0.6, 0.3]), ln(&[0.1, 0.6, 0.3])], &[eos]);
+        let beams = search.step(&[ln(&[0.8, 0.1, 0.1]), ln(&[0.7, 0.2, 0.1])], &[eos]);
+        // Both best candidates end the sequence, the running beams are the next best ones.
+        assert_eq!(search.hypotheses.len(), 2);
+        assert_eq!(beams.len(), 2);
+        assert!(beams.iter().all(|(_, token_id)| *token_id != eos));
+        assert!(search.is_done());
+
+        let hypotheses = search.finalize();
+        assert_eq!(hypotheses.len(), 2);
+        assert_eq!(hypotheses[0].token_ids, vec![1, eos]);
+        assert_eq!(hypotheses[1].token_ids, vec![2, eos]);
+        assert!(hypotheses[0].score >= hypotheses[1].score);
+    }
+
+    #[test]
+    fn keeps_every_beam_running_with_several_eos_ids() {
+        let eos_ids = [0, 1];
+        let mut search = beam_search(2, 1., false);
+        search.step(
+            &[ln(&[0.1, 0.1, 0.5, 0.3]), ln(&[0.1, 0.1, 0.5, 0.3])],
+            &eos_ids,
+        );
+        // For both beams the two end of sequence tokens rank above every other token.
+        let probabilities = [0.45, 0.45, 0.06, 0.04];
+        let beams = search.step(&[ln(&probabilities), ln(&probabilities)], &eos_ids);
+        assert_eq!(beams, vec![(0, 2), (0, 3)]);
+        assert_eq!(search.hypotheses.len(), 2);
+    }
+
+    #[test]
+    fn finalize_returns_the_best_sequences() {
+        let mut search = beam_search(3, 1., false);
+        search.num_return_sequences = 2;
+        search.step(&vec![ln(&[0.5, 0.3, 0.2]); 3], &[]);
+        let hypotheses = search.finalize();
+        assert!(search.is_done());
+        let token_ids: Vec<&[u32]> = hypotheses
+            .iter()
+            .map(|hypothesis| hypothesis.token_ids.as_slice())
+            .collect();
+        assert_eq!(token_ids, vec![&[0][..], &[1][..]]);
+        approx::assert_abs_diff_eq!(hypotheses[0].score, 0.5f32.ln(), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn scores_are_normalized_by_the_length_penalty() {
+        let score = -6.;
+        approx::assert_abs_diff_eq!(beam_search(2, 0., false).normalize(score, 3), -6.);
+        approx::assert_abs_diff_eq!(beam_search(2, 1., false).normalize(score, 3), -2.);
+        approx::assert_abs_diff_eq!(
+            beam_search(2, 2., false).normalize(score, 3),
+            -6. / 9.,
+            epsilon = 1e-6
+        );
+
+        // A higher penalty favors the longer hypothesis of the same average log probability.
+        let mut search = beam_search(2, 2., false);
+        search.add_hypothesis(vec![1], -1.);
+        search.add_hypothesis(vec![1, 2, 3], -3.);
+        let best = search
+            .hypotheses
+            .iter()
+            .max_by(|a, b| a.score.total_cmp(&b.score))
+            .unwrap();
+        assert_eq!(best.token_ids, vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,14 @@
+/// Why a request stopped generating, set on its last `GenerationResult`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// One of the request's stop strings or stop token ids was generated.
+    Stop,
+    /// `max_new_tokens` tokens were generated.
+    Length,
+    /// The model generated its end of sequence token.
+    Eos,
+    /// The client stopped listening before the generation ended.
+    Cancelled,
+    /// The generation failed, the content of the reply is empty.
+    Error,
+}
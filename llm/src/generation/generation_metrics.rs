@@ -0,0 +1,80 @@
+use crate::ModelMetaData;
+use std::time::{Duration, Instant};
+
+/// Timings and batch sizes of a single request, collected by the generation tasks while it runs.
+#[derive(Debug, Clone)]
+pub struct GenerationMetrics {
+    created_at: Instant,
+    scheduled_at: Option<Instant>,
+    first_token_at: Option<Instant>,
+    batch_size_sum: usize,
+    number_of_steps: usize,
+}
+
+/// Statistics of a finished request.
+#[derive(Debug, Clone)]
+pub struct GenerationMetaData {
+    /// Generated tokens per second, counted from the first forward pass of the request.
+    pub tokens_per_second: f32,
+    /// Average number of batch rows of the forward passes the request ran in.
+    pub average_batch_size: f32,
+    /// Time between the request being received and its first forward pass.
+    pub queue_wait: Duration,
+    /// Time between the request being received and its first sampled token.
+    pub time_to_first_token: Duration,
+    pub model: ModelMetaData,
+}
+
+impl Default for GenerationMetrics {
+    fn default() -> Self {
+        Self {
+            created_at: Instant::now(),
+            scheduled_at: None,
+            first_token_at: None,
+            batch_size_sum: 0,
+            number_of_steps: 0,
+        }
+    }
+}
+
+impl GenerationMetrics {
+    /// Records a forward pass over a batch of `batch_size` rows that included the request.
+    pub fn record_step(&mut self, batch_size: usize) {
+        self.scheduled_at.get_or_insert_with(Instant::now);
+        self.batch_size_sum += batch_size;
+        self.number_of_steps += 1;
+    }
+
+    /// Records that a token was sampled for the request.
+    pub fn record_token(&mut self) {
+        self.first_token_at.get_or_insert_with(Instant::now);
+    }
+
+    pub fn meta_data(
+        &self,
+        number_tokens_generated: u32,
+        model: ModelMetaData,
+    ) -> GenerationMetaData {
+        let now = Instant::now();
+        let scheduled_at = self.scheduled_at.unwrap_or(now);
+        let first_token_at = self.first_token_at.unwrap_or(now);
+        let generation_time = now.duration_since(scheduled_at).as_secs_f32();
+        let tokens_per_second = if generation_time > 0. {
+            number_tokens_generated as f32 / generation_time
+        } else {
+            0.
+        };
+        let average_batch_size = if self.number_of_steps > 0 {
+            self.batch_size_sum as f32 / self.number_of_steps as f32
+        } else {
+            0.
+        };
+        GenerationMetaData {
+            tokens_per_second,
+            average_batch_size,
+            queue_wait: scheduled_at.duration_since(self.created_at),
+            time_to_first_token: first_token_at.duration_since(self.created_at),
+            model,
+        }
+    }
+}
@@ -1,7 +1,9 @@
 extern crate tokio; // Should decople from tokio in future.
 
-use super::{GenerationLogitsProcessor, GenerationResult};
-use crate::{Prompt, PromptConfig};
+use super::{
+    BeamSearch, GenerationLogitsProcessor, GenerationMetrics, GenerationResult, InFlightGuard,
+};
+use crate::{IncrementalDetokenizer, Prompt, PromptConfig};
 
 pub type GenerationResultSender = tokio::sync::mpsc::Sender<GenerationResult>;
 
@@ -9,11 +11,22 @@ pub type GenerationResultSender = tokio::sync::mpsc::Sender<GenerationResult>;
 pub struct GenerationRequest {
     pub id: String,
     pub content: String,
+    /// Text generated so far, without the prompt.
     pub generated: String,
     pub config: PromptConfig,
+    /// LoRA adapter the request's rows go through, the base model when `None`.
+    pub adapter: Option<String>,
     pub reply_sender: GenerationResultSender,
     pub number_tokens_generated: u32,
-    // logit: GenerationLogitsProcessor,
+    pub beam_search: Option<BeamSearch>,
+    /// Created once from the request's seed so the random stream advances from token to token and
+    /// only depends on this request, not on the other requests sharing its batch.
+    pub logits_processor: GenerationLogitsProcessor,
+    pub metrics: GenerationMetrics,
+    /// Created by the decoder once the prompt length is known.
+    pub detokenizer: Option<IncrementalDetokenizer>,
+    /// Set by the generator, which waits for the request to be dropped when draining.
+    pub in_flight: Option<InFlightGuard>,
 }
 
 impl GenerationRequest {
@@ -22,16 +35,23 @@ impl GenerationRequest {
             id,
             content,
             config,
+            adapter,
         } = prompt;
-        // let logit = GenerationLogitsProcessor::from_prompt_config(&config);
+        let logits_processor = GenerationLogitsProcessor::from_prompt_config(&config);
+        let beam_search = BeamSearch::from_prompt_config(&config);
         Self {
             id,
             content,
             config,
+            adapter,
             reply_sender,
-            // logit,
+            logits_processor,
             generated: String::new(),
             number_tokens_generated: 0,
+            beam_search,
+            metrics: GenerationMetrics::default(),
+            detokenizer: None,
+            in_flight: None,
         }
     }
 }
@@ -41,7 +61,21 @@ impl GenerationRequest {
         &self.reply_sender
     }
 
-    pub fn logit_processor(&self) -> GenerationLogitsProcessor {
-        GenerationLogitsProcessor::from_prompt_config(&self.config)
+    /// Whether the client went away or the generator gave up waiting for the request while
+    /// draining.
+    pub fn is_cancelled(&self) -> bool {
+        self.reply_sender.is_closed()
+            || self
+                .in_flight
+                .as_ref()
+                .is_some_and(|in_flight| in_flight.is_cancelled())
+    }
+
+    /// Number of batch rows used by the request, one per beam when using beam search.
+    pub fn number_of_rows(&self) -> usize {
+        match &self.beam_search {
+            Some(beam_search) => beam_search.num_beams(),
+            None => 1,
+        }
     }
 }
@@ -1,12 +1,36 @@
-use crate::PromptConfig;
+use crate::{FinishReason, GenerationMetaData, PromptConfig, StopCondition, TokenLogProbs};
+
+/// A finished beam search sequence with its length normalized score.
+#[derive(Debug, Clone)]
+pub struct GeneratedSequence {
+    pub content: String,
+    pub score: f32,
+}
+
+/// Number of prompt and generated tokens of a request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
 
 #[derive(Debug)]
 pub struct GenerationResult {
     pub id: String,
+    /// Text generated so far, the prompt is not included.
     pub content: String,
+    /// Text added by this step, empty while a multi-byte character is incomplete.
     pub generated: String,
     pub is_end_of_sequence: bool,
     pub config: PromptConfig,
+    pub sequences: Vec<GeneratedSequence>,
+    pub stopped_by: Option<StopCondition>,
+    /// Only set on the last result of a request, like `usage`.
+    pub finish_reason: Option<FinishReason>,
+    pub usage: Option<Usage>,
+    pub meta: Option<GenerationMetaData>,
+    /// Set when the request asked for `logprobs`, not computed for beam search.
+    pub logprobs: Option<TokenLogProbs>,
 }
 
 impl GenerationResult {}
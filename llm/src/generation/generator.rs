@@ -1,8 +1,12 @@
 extern crate tokio;
 
-use super::{GenerationBatch, GenerationRequest, GenerationResult, TextGeneration};
-use crate::{tasks, GenerationStep, ModelConfig, Prompt, Result, TokenizedBatch};
+use super::{GenerationBatch, GenerationRequest, GenerationResult, InFlight, TextGeneration};
+use crate::{
+    tasks, BatchingConfig, Error, GenerationStep, ModelBackend, ModelConfig, ModelMetaData, Prompt,
+    Result, TokenizedBatch, Tokenizer,
+};
 use std::sync::Arc;
+use std::time::Duration;
 
 pub type GenerationRequestSender = tokio::sync::mpsc::Sender<GenerationRequest>;
 pub type GenerationResultReceiver = tokio::sync::mpsc::Receiver<GenerationResult>;
@@ -10,32 +14,63 @@ pub type GenerationResultReceiver = tokio::sync::mpsc::Receiver<GenerationResult
 #[derive(Debug)]
 pub struct Generator {
     request_sender: GenerationRequestSender,
+    /// LoRA adapters of the model, checked before requests enter the pipeline.
+    adapters: Vec<String>,
+    /// Beams take a batch row each, a request can not use more rows than a batch holds.
+    max_batch_size: usize,
+    /// Prompts longer than the model context are rejected before entering the pipeline.
+    tokenizer: Arc<Tokenizer>,
+    context_length: usize,
+    model_meta_data: ModelMetaData,
+    in_flight: Arc<InFlight>,
 }
 
 impl Generator {
-    pub async fn new(text_generation: TextGeneration) -> Self {
+    pub async fn new<M: ModelBackend>(
+        text_generation: TextGeneration<M>,
+        config: BatchingConfig,
+    ) -> Self {
         use tokio::sync::mpsc::channel;
-        let TextGeneration { model, tokenizer } = text_generation;
+        let TextGeneration {
+            model,
+            mut tokenizer,
+        } = text_generation;
+        tokenizer.add_eos_ids(&model.eos_token_ids());
+        tokenizer.set_device(model.device());
         let tokenizer = Arc::new(tokenizer);
+        let model_meta_data = model.meta_data();
+        let adapters = model.adapters();
+        let context_length = model.context_length();
 
         let (request_sender, request_receiver) = channel::<GenerationRequest>(128);
         let (generation_batch_sender, generation_batch_receiver) = channel::<GenerationBatch>(128);
         let (tokenized_batch_sender, tokenized_batch_receiver) = channel::<TokenizedBatch>(128);
         let (generation_result_sender, generation_result_receiver) = channel::<GenerationStep>(128);
 
-        let batch_task = tasks::Batching::new(request_receiver, generation_batch_sender);
+        let batch_task = tasks::Batching::new(
+            config.max_batch_size,
+            request_receiver,
+            generation_batch_sender,
+        );
+        let weak_tokenized_batch_sender = tokenized_batch_sender.downgrade();
         let tokenize_task = tasks::Tokenize::new(
             tokenizer.clone(),
             generation_batch_receiver,
-            tokenized_batch_sender.clone(),
+            tokenized_batch_sender,
+        );
+        let generation_task = tasks::Generation::new(
+            model,
+            config,
+            tokenized_batch_receiver,
+            generation_result_sender,
         );
-        let generation_task =
-            tasks::Generation::new(model, tokenized_batch_receiver, generation_result_sender);
 
         let decode_task = tasks::Decoder::new(
             tokenizer.clone(),
+            model_meta_data.clone(),
+            context_length,
             generation_result_receiver,
-            tokenized_batch_sender,
+            weak_tokenized_batch_sender,
         );
 
         let mut batch_task = batch_task.task();
@@ -81,20 +116,414 @@ impl Generator {
         });
 
         tracing::debug!("All generator tasks setup.");
-        Self { request_sender }
+        Self {
+            request_sender,
+            adapters,
+            max_batch_size: config.max_batch_size,
+            tokenizer,
+            context_length,
+            model_meta_data,
+            in_flight: InFlight::new(),
+        }
     }
 
-    pub async fn from_model_config(config: ModelConfig) -> Result<Self> {
+    pub async fn from_model_config(
+        config: ModelConfig,
+        batching_config: BatchingConfig,
+    ) -> Result<Self> {
         let generation = TextGeneration::new(config)?;
-        Ok(Generator::new(generation).await)
+        Ok(Generator::new(generation, batching_config).await)
     }
 }
 
 impl Generator {
+    pub fn adapters(&self) -> &[String] {
+        &self.adapters
+    }
+
+    /// The tokenizer of the pipeline, with the end of sequence ids of the model.
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    pub fn meta_data(&self) -> &ModelMetaData {
+        &self.model_meta_data
+    }
+
+    /// Number of requests that have not finished yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.count()
+    }
+
+    /// Stops accepting requests and waits for the ones in flight to finish. Requests still
+    /// running after `timeout` are cancelled, they end with `FinishReason::Cancelled`. Returns the
+    /// number of cancelled requests.
+    ///
+    /// Dropping the generator afterwards stops its tasks and frees the model.
+    pub async fn drain(&self, timeout: Option<Duration>) -> usize {
+        self.in_flight.start_draining();
+        tracing::info!(
+            "Draining generator, {} requests in flight",
+            self.in_flight()
+        );
+        let finished = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.in_flight.wait_idle())
+                .await
+                .is_ok(),
+            None => {
+                self.in_flight.wait_idle().await;
+                true
+            }
+        };
+        if finished {
+            return 0;
+        }
+        let cancelled = self.in_flight();
+        tracing::info!("Cancelling {} requests after {:?}", cancelled, timeout);
+        self.in_flight.cancel();
+        self.in_flight.wait_idle().await;
+        cancelled
+    }
+
     pub async fn prompt(&self, prompt: Prompt) -> Result<GenerationResultReceiver> {
+        // Counted before checking, so that `drain` either waits for the request or rejects it.
+        let in_flight = self.in_flight.guard();
+        if self.in_flight.is_draining() {
+            return Err(Error::GeneratorDraining);
+        }
+        if let Some(adapter) = &prompt.adapter {
+            if !self.adapters.contains(adapter) {
+                return Err(Error::UnknownAdapter {
+                    adapter: adapter.clone(),
+                    available: self.adapters.clone(),
+                });
+            }
+        }
+        prompt.config.validate(self.max_batch_size)?;
+        let prompt_tokens = self.tokenizer.count_tokens(&prompt.content)?;
+        if prompt_tokens > self.context_length {
+            return Err(Error::InvalidPromptConfig {
+                message: format!(
+                    "the prompt of {prompt_tokens} tokens does not fit the context of {} tokens",
+                    self.context_length
+                ),
+            });
+        }
         let (reply_sender, reply_receiver) = tokio::sync::mpsc::channel::<GenerationResult>(128);
-        let generation_request = GenerationRequest::from_prompt(prompt, reply_sender);
+        let mut generation_request = GenerationRequest::from_prompt(prompt, reply_sender);
+        generation_request.in_flight = Some(in_flight);
         self.request_sender.send(generation_request).await?;
         Ok(reply_receiver)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FinishReason, PromptConfig, ScriptedModel, StopCondition, Tokenizer};
+    use huggingface_tokenizers::models::wordlevel::WordLevel;
+    use huggingface_tokenizers::pre_tokenizers::whitespace::WhitespaceSplit;
+    use huggingface_tokenizers::AddedToken;
+
+    const VOCAB: [&str; 8] = [
+        "<unk>", "<s>", "</s>", "hello", "world", "how", "are", "you",
+    ];
+    const EOS_ID: u32 = 2;
+
+    fn tokenizer() -> Tokenizer {
+        let vocab = VOCAB
+            .iter()
+            .enumerate()
+            .map(|(token_id, token)| (token.to_string(), token_id as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_owned())
+            .build()
+            .unwrap();
+        let mut tokenizer = huggingface_tokenizers::Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(WhitespaceSplit);
+        tokenizer.add_special_tokens(&[
+            AddedToken::from("<s>", true),
+            AddedToken::from("</s>", true),
+        ]);
+        Tokenizer::new(
+            tokenizer,
+            "<s>".to_owned(),
+            "</s>".to_owned(),
+            "</s>".to_owned(),
+            None,
+        )
+        .unwrap()
+    }
+
+    async fn generator(script: Vec<u32>) -> Generator {
+        with_model(ScriptedModel::new(script, EOS_ID, VOCAB.len())).await
+    }
+
+    async fn with_model(model: ScriptedModel) -> Generator {
+        let text_generation = TextGeneration {
+            model,
+            tokenizer: tokenizer(),
+        };
+        Generator::new(text_generation, BatchingConfig::default()).await
+    }
+
+    async fn collect(mut receiver: GenerationResultReceiver) -> Vec<GenerationResult> {
+        let mut results = Vec::new();
+        while let Some(result) = receiver.recv().await {
+            let is_end_of_sequence = result.is_end_of_sequence;
+            results.push(result);
+            if is_end_of_sequence {
+                break;
+            }
+        }
+        results
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streams_the_script_until_eos() -> Result<()> {
+        let generator = generator(vec![3, 4]).await;
+        let results = collect(generator.prompt("how are you".into()).await?).await;
+
+        let deltas: Vec<&str> = results
+            .iter()
+            .map(|result| result.generated.as_str())
+            .collect();
+        assert_eq!(deltas, vec![" hello", " world", ""]);
+        let last = results.last().unwrap();
+        assert_eq!(last.content, " hello world");
+        assert_eq!(last.finish_reason, Some(FinishReason::Eos));
+        let usage = last.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 3);
+        assert_eq!(usage.completion_tokens, 3);
+        Ok(())
+    }
+
+    fn prompt(config: PromptConfig) -> Prompt {
+        Prompt {
+            config,
+            ..Prompt::from("how are you")
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stops_at_a_stop_string_split_across_tokens() -> Result<()> {
+        for (include_stop, content, streamed) in [
+            (false, " hel", " hello"),
+            (true, " hello wor", " hello wor"),
+        ] {
+            let generator = generator(vec![3, 4, 5]).await;
+            let config = PromptConfig {
+                stop: vec!["lo wor".to_owned()],
+                include_stop,
+                ..Default::default()
+            };
+            let results = collect(generator.prompt(prompt(config)).await?).await;
+
+            let last = results.last().unwrap();
+            assert_eq!(last.content, content);
+            assert_eq!(last.finish_reason, Some(FinishReason::Stop));
+            assert_eq!(
+                last.stopped_by,
+                Some(StopCondition::Sequence("lo wor".to_owned()))
+            );
+            // " hello" was already streamed when "world" completed the stop string.
+            let deltas: String = results
+                .iter()
+                .map(|result| result.generated.as_str())
+                .collect();
+            assert_eq!(deltas, streamed);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stops_at_a_stop_token_id() -> Result<()> {
+        for (include_stop, content) in [(false, " hello"), (true, " hello world")] {
+            let generator = generator(vec![3, 4, 5]).await;
+            let config = PromptConfig {
+                stop_token_ids: vec![4],
+                include_stop,
+                ..Default::default()
+            };
+            let results = collect(generator.prompt(prompt(config)).await?).await;
+
+            let last = results.last().unwrap();
+            assert_eq!(last.content, content);
+            assert_eq!(last.finish_reason, Some(FinishReason::Stop));
+            assert_eq!(last.stopped_by, Some(StopCondition::TokenId(4)));
+            assert_eq!(last.usage.unwrap().completion_tokens, 2);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requests_sharing_a_batch_finish_independently() -> Result<()> {
+        let generator = generator(vec![3, 4, 3, 4]).await;
+        let short = Prompt {
+            id: "short".to_owned(),
+            content: "hello".to_owned(),
+            config: PromptConfig {
+                max_new_tokens: 1,
+                ..Default::default()
+            },
+            adapter: None,
+        };
+        let long = Prompt {
+            id: "long".to_owned(),
+            content: "how are you".to_owned(),
+            config: PromptConfig {
+                max_new_tokens: 3,
+                ..Default::default()
+            },
+            adapter: None,
+        };
+        let short = generator.prompt(short).await?;
+        let long = generator.prompt(long).await?;
+        let (short, long) = tokio::join!(collect(short), collect(long));
+
+        let short = short.last().unwrap();
+        assert_eq!(short.content, " hello");
+        assert_eq!(short.finish_reason, Some(FinishReason::Length));
+        let long = long.last().unwrap();
+        assert_eq!(long.content, " hello world hello");
+        assert_eq!(long.finish_reason, Some(FinishReason::Length));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_unknown_adapters() {
+        let generator = generator(vec![3]).await;
+        let prompt = Prompt {
+            adapter: Some("missing".to_owned()),
+            ..Prompt::from("hello")
+        };
+        assert!(matches!(
+            generator.prompt(prompt).await,
+            Err(Error::UnknownAdapter { .. })
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stops_at_the_context_length() -> Result<()> {
+        let generator =
+            with_model(ScriptedModel::new(vec![3; 10], EOS_ID, VOCAB.len()).with_context_length(5))
+                .await;
+        // The three prompt tokens leave room for two more positions, the token sampled from the
+        // last one needs none.
+        for num_beams in [None, Some(2)] {
+            let config = PromptConfig {
+                max_new_tokens: 10,
+                num_beams,
+                ..Default::default()
+            };
+            let last = collect(generator.prompt(prompt(config)).await?).await.pop();
+            let last = last.unwrap();
+            assert_eq!(last.finish_reason, Some(FinishReason::Length));
+            assert_eq!(last.usage.unwrap().completion_tokens, 3);
+        }
+
+        let config = PromptConfig {
+            max_new_tokens: 10,
+            ..Default::default()
+        };
+        let long_prompt = Prompt {
+            config,
+            ..Prompt::from("how are you how are you")
+        };
+        assert!(matches!(
+            generator.prompt(long_prompt).await,
+            Err(Error::InvalidPromptConfig { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_more_beams_than_a_batch_holds() -> Result<()> {
+        let generator = generator(vec![3]).await;
+        let max_batch_size = BatchingConfig::default().max_batch_size as i32;
+        for config in [
+            PromptConfig {
+                num_beams: Some(max_batch_size + 1),
+                ..Default::default()
+            },
+            PromptConfig {
+                num_beams: Some(2),
+                num_return_sequences: Some(max_batch_size + 1),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                generator.prompt(prompt(config)).await,
+                Err(Error::InvalidPromptConfig { .. })
+            ));
+        }
+
+        let config = PromptConfig {
+            num_beams: Some(max_batch_size),
+            max_new_tokens: 1,
+            ..Default::default()
+        };
+        let last = collect(generator.prompt(prompt(config)).await?).await.pop();
+        assert!(last.unwrap().is_end_of_sequence);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_too_many_logprobs_alternatives() -> Result<()> {
+        let generator = generator(vec![3]).await;
+        let config = PromptConfig {
+            logprobs: Some(PromptConfig::MAX_LOGPROBS + 1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            generator.prompt(prompt(config)).await,
+            Err(Error::InvalidPromptConfig { .. })
+        ));
+
+        let config = PromptConfig {
+            logprobs: Some(PromptConfig::MAX_LOGPROBS),
+            max_new_tokens: 1,
+            ..Default::default()
+        };
+        let last = collect(generator.prompt(prompt(config)).await?).await.pop();
+        let logprobs = last.unwrap().logprobs.expect("missing logprobs");
+        assert_eq!(logprobs.alternatives.len(), VOCAB.len());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn draining_finishes_requests_then_rejects_new_ones() -> Result<()> {
+        let generator = generator(vec![3, 4]).await;
+        let results = tokio::spawn(collect(generator.prompt("hello".into()).await?));
+        assert_eq!(generator.drain(None).await, 0);
+        assert_eq!(generator.in_flight(), 0);
+
+        let last = results.await.unwrap().pop().unwrap();
+        assert_eq!(last.finish_reason, Some(FinishReason::Eos));
+        assert!(matches!(
+            generator.prompt("hello".into()).await,
+            Err(Error::GeneratorDraining)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn draining_cancels_requests_after_the_timeout() -> Result<()> {
+        let generator = generator(vec![3; 4000]).await;
+        let prompt = Prompt {
+            config: PromptConfig {
+                max_new_tokens: 4000,
+                ..Default::default()
+            },
+            ..Prompt::from("hello")
+        };
+        let results = tokio::spawn(collect(generator.prompt(prompt).await?));
+        assert_eq!(generator.drain(Some(Duration::ZERO)).await, 1);
+
+        let last = results.await.unwrap().pop().unwrap();
+        assert_eq!(last.finish_reason, Some(FinishReason::Cancelled));
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+extern crate tokio;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Requests of a generator that have not finished yet. Every request holds an `InFlightGuard`
+/// until the pipeline drops it, so draining the generator can wait for them.
+#[derive(Debug)]
+pub struct InFlight {
+    count: tokio::sync::watch::Sender<usize>,
+    /// Set once the generator stops accepting requests.
+    draining: AtomicBool,
+    /// Set when draining stopped waiting, the pipeline then ends the remaining requests.
+    cancelled: AtomicBool,
+}
+
+impl InFlight {
+    pub fn new() -> Arc<Self> {
+        let (count, _) = tokio::sync::watch::channel(0);
+        Arc::new(Self {
+            count,
+            draining: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+        })
+    }
+
+    pub fn guard(self: &Arc<Self>) -> InFlightGuard {
+        self.count.send_modify(|count| *count += 1);
+        InFlightGuard(self.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        *self.count.borrow()
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Resolves once no request is in flight.
+    pub async fn wait_idle(&self) {
+        let mut count = self.count.subscribe();
+        // The sender lives as long as `self`, so waiting can not fail.
+        let _ = count.wait_for(|count| *count == 0).await;
+    }
+}
+
+/// Held by a request while it is in flight.
+#[derive(Debug)]
+pub struct InFlightGuard(Arc<InFlight>);
+
+impl InFlightGuard {
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.count.send_modify(|count| *count -= 1);
+    }
+}
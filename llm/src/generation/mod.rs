@@ -1,16 +1,30 @@
+mod batching_config;
+mod beam_search;
+mod finish_reason;
 mod generation_batch;
 mod generation_logits_processor;
+mod generation_metrics;
 mod generation_request;
 mod generation_result;
 mod generation_step;
 mod generator;
+mod in_flight;
+mod stop_condition;
 mod text_generation;
+mod token_log_probs;
 
 pub mod tasks;
+pub use self::batching_config::BatchingConfig;
+pub use self::beam_search::{BeamHypothesis, BeamSearch};
+pub use self::finish_reason::FinishReason;
 pub use self::generation_batch::GenerationBatch;
 pub use self::generation_logits_processor::GenerationLogitsProcessor;
+pub use self::generation_metrics::{GenerationMetaData, GenerationMetrics};
 pub use self::generation_request::GenerationRequest;
-pub use self::generation_result::GenerationResult;
+pub use self::generation_result::{GeneratedSequence, GenerationResult, Usage};
 pub use self::generation_step::GenerationStep;
 pub use self::generator::Generator;
+pub use self::in_flight::{InFlight, InFlightGuard};
+pub use self::stop_condition::StopCondition;
 pub use self::text_generation::TextGeneration;
+pub use self::token_log_probs::{TokenLogProb, TokenLogProbs};
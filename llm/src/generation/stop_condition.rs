@@ -0,0 +1,65 @@
+/// The stop condition of a request that ended its generation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopCondition {
+    /// One of the request's `stop` strings was found in the generated text.
+    Sequence(String),
+    /// One of the request's `stop_token_ids` was sampled.
+    TokenId(u32),
+}
+
+impl StopCondition {
+    /// Finds the earliest of `stop_sequences` in `text` at or after byte offset `start`, returning
+    /// its byte offset in `text` and the matched sequence.
+    ///
+    /// The whole text from `start` is searched so a stop sequence split over several tokens is
+    /// found as soon as its last token is decoded.
+    pub fn find_sequence<'a>(
+        text: &str,
+        start: usize,
+        stop_sequences: &'a [String],
+    ) -> Option<(usize, &'a str)> {
+        let start = (0..=start.min(text.len()))
+            .rev()
+            .find(|index| text.is_char_boundary(*index))
+            .unwrap_or_default();
+        let searched = &text[start..];
+        stop_sequences
+            .iter()
+            .filter(|sequence| !sequence.is_empty())
+            .filter_map(|sequence| {
+                searched
+                    .find(sequence.as_str())
+                    .map(|offset| (start + offset, sequence.as_str()))
+            })
+            .min_by_key(|(offset, _)| *offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_earliest_sequence_from_the_start_offset() {
+        let stop = vec!["world".to_owned(), "lo".to_owned(), String::new()];
+        assert_eq!(
+            StopCondition::find_sequence("hello world", 0, &stop),
+            Some((3, "lo"))
+        );
+        assert_eq!(
+            StopCondition::find_sequence("hello world", 4, &stop),
+            Some((6, "world"))
+        );
+        assert_eq!(StopCondition::find_sequence("hello", 4, &stop), None);
+    }
+
+    #[test]
+    fn moves_the_start_offset_back_to_a_char_boundary() {
+        let stop = vec!["é!".to_owned()];
+        // Byte 4 falls inside "é", which starts at byte 3.
+        assert_eq!(
+            StopCondition::find_sequence("café!", 4, &stop),
+            Some((3, "é!"))
+        );
+    }
+}
@@ -3,6 +3,7 @@ use crate::{Error, GenerationBatch, GenerationRequest};
 
 #[derive(Debug)]
 pub struct Batching {
+    max_batch_size: usize,
     request_receiver: Receiver<GenerationRequest>,
     batch_sender: Sender<GenerationBatch>,
 }
@@ -10,6 +11,7 @@ pub struct Batching {
 impl Batching {
     pub fn task(self) -> TaskResult<()> {
         let Batching {
+            max_batch_size,
             batch_sender,
             mut request_receiver,
         } = self;
@@ -20,12 +22,12 @@ impl Batching {
                 match request_receiver.recv().await {
                     Some(request) => requests.push(request),
                     None => {
-                        return Err(Error::GenerationError {
-                            message: "Request receiver is closed. Restarting generator".to_owned(),
-                        })
+                        // The generator was dropped, the other tasks stop one after the other.
+                        tracing::debug!("batch_task: request receiver is closed, stopping");
+                        return Ok(());
                     }
                 };
-                while !request_receiver.is_empty() {
+                while !request_receiver.is_empty() && requests.len() < max_batch_size {
                     match request_receiver.recv().await {
                         Some(request) => requests.push(request),
                         None => {
@@ -46,10 +48,12 @@ impl Batching {
 
 impl Batching {
     pub fn new(
+        max_batch_size: usize,
         request_receiver: Receiver<GenerationRequest>,
         batch_sender: Sender<GenerationBatch>,
     ) -> Self {
         Self {
+            max_batch_size,
             request_receiver,
             batch_sender,
         }
@@ -1,22 +1,44 @@
-use candle_core::{IndexOp, Tensor};
+use candle_core::{DType, IndexOp, Tensor, D};
 use indexmap::IndexMap;
 
-use super::{Receiver, Sender, TaskResult};
+use super::{Receiver, TaskResult, WeakSender};
 use crate::{
-    GenerationLogitsProcessor, GenerationResult, GenerationStep, TokenizedBatch, Tokenizer,
+    FinishReason, GeneratedSequence, GenerationLogitsProcessor, GenerationRequest,
+    GenerationResult, GenerationStep, IncrementalDetokenizer, ModelMetaData, Result, StopCondition,
+    TokenLogProbs, TokenizedBatch, Tokenizer, Usage,
 };
 
 #[derive(Debug)]
 pub struct Decoder {
     tokenizer: std::sync::Arc<Tokenizer>,
+    model_meta_data: ModelMetaData,
+    /// Positions the model can hold, requests end with `FinishReason::Length` once their batch
+    /// fills them.
+    context_length: usize,
     generation_result_receiver: Receiver<GenerationStep>,
-    tokenized_batch_sender: Sender<TokenizedBatch>,
+    /// Weak so that the pipeline stops once the generator is dropped, the tokenize task holds
+    /// the last strong sender.
+    tokenized_batch_sender: WeakSender<TokenizedBatch>,
+}
+
+/// Rows of the batch carried over to the next generation step.
+#[derive(Debug, Default)]
+struct NextRows {
+    /// Whether the batch filled the model context, no row can be carried over then.
+    is_at_context_limit: bool,
+    /// Row of the current batch that each next row continues.
+    indexes: Vec<u32>,
+    /// Token fed to the model on each next row.
+    tokens: Vec<u32>,
+    token_ids: Vec<Vec<u32>>,
 }
 
 impl Decoder {
     pub fn task(self) -> TaskResult<()> {
         let Decoder {
             tokenizer,
+            model_meta_data,
+            context_length,
             tokenized_batch_sender,
             mut generation_result_receiver,
         } = self;
@@ -29,114 +51,69 @@ impl Decoder {
 
                     let GenerationStep { batch, logits } = generation_result;
                     let TokenizedBatch {
-                        mut requests,
+                        requests,
                         input_ids,
                         attention_mask,
                         past_key_values,
                         mut token_ids,
+                        pad_id,
                     } = batch;
-                    struct ProcessedToken {
-                        pub token_id: u32,
-                        pub is_end_of_sequence: bool,
-                    }
-
-                    let mut processed_tokens = Vec::with_capacity(requests.len());
-                    let mut added_tokens = Vec::with_capacity(token_ids.len());
-                    for (index, request) in requests.values_mut().enumerate() {
-                        let logit_row = logits.i(index)?.squeeze(0)?;
-                        let GenerationLogitsProcessor {
-                            preprocess: _,
-                            mut process,
-                        } = request.logit_processor();
-                        let token_id = process.sample(&logit_row).expect("Errror sampling");
-                        let is_end_of_sequence = token_id == tokenizer.eos_id;
-                        processed_tokens.push(ProcessedToken {
-                            token_id,
-                            is_end_of_sequence,
-                        });
-                        token_ids[index].push(token_id);
-                        added_tokens.push(token_id);
-                        request.number_tokens_generated += 1;
-                    }
-                    let process_time = loop_start.elapsed();
 
-                    let decoded_text = tokenizer
-                        .batch_decode(&token_ids, false)
-                        .expect("Error batch decode");
-
-                    let mut indicies_to_keep = Vec::new();
+                    // Rows are padded to the longest one of the batch, the next step needs one
+                    // more position than the cache now holds.
+                    let mut next_rows = NextRows {
+                        is_at_context_limit: attention_mask.dim(1)? >= context_length,
+                        ..Default::default()
+                    };
                     let mut kept_requests = IndexMap::new();
-                    let iterator = requests
-                        .into_values()
-                        .zip(processed_tokens)
-                        .zip(decoded_text)
-                        .enumerate();
-
-                    let decode_time = loop_start.elapsed();
-
-                    for (index, ((request, processed), generated)) in iterator {
-                        let ProcessedToken {
-                            token_id,
-                            mut is_end_of_sequence,
-                        } = processed;
-                        let reached_max_tokens =
-                            request.number_tokens_generated >= request.config.max_new_tokens as u32;
-                        tracing::info!("reached_max_tokens: {}", &reached_max_tokens);
-                        if reached_max_tokens {
-                            is_end_of_sequence = true
-                        }
-                        if request.sender().is_closed() {
-                            is_end_of_sequence = true
+                    let mut row = 0;
+                    for mut request in requests.into_values() {
+                        let number_of_rows = request.number_of_rows();
+                        let is_end_of_sequence = if request.beam_search.is_some() {
+                            Decoder::beam_search_step(
+                                tokenizer,
+                                &model_meta_data,
+                                &mut request,
+                                &logits,
+                                row,
+                                &token_ids,
+                                &mut next_rows,
+                            )?
                         } else {
-                            request.sender().blocking_send(GenerationResult {
-                                id: request.id.clone(),
-                                content: generated.clone(),
-                                generated: tokenizer.id_to_token(token_id).unwrap_or_default(),
-                                is_end_of_sequence,
-                                config: request.config.clone(),
-                            })?;
-                        }
+                            Decoder::sample_step(
+                                tokenizer,
+                                &model_meta_data,
+                                &mut request,
+                                &logits,
+                                row,
+                                &mut token_ids,
+                                &mut next_rows,
+                            )?
+                        };
                         if !is_end_of_sequence {
-                            indicies_to_keep.push(index as u32);
                             kept_requests.insert(request.id.clone(), request);
                         }
+                        row += number_of_rows;
                     }
-                    let send_time = loop_start.elapsed();
-                    let num_indicies = indicies_to_keep.len();
+                    let process_time = loop_start.elapsed();
+                    let num_indicies = next_rows.indexes.len();
 
                     if num_indicies > 0 {
                         let device = input_ids.device();
+                        let number_of_rows = token_ids.len();
+                        let NextRows {
+                            indexes,
+                            tokens,
+                            token_ids,
+                            ..
+                        } = next_rows;
+                        let indicies_to_keep = Tensor::from_vec(indexes, num_indicies, device)?;
 
-                        let mut filtered_token_ids = Vec::with_capacity(num_indicies);
-                        for (index, vec) in token_ids.into_iter().enumerate() {
-                            if indicies_to_keep.contains(&(index as u32)) {
-                                filtered_token_ids.push(vec)
-                            }
-                        }
-                        let indicies_to_keep =
-                            Tensor::from_vec(indicies_to_keep, num_indicies, device)?;
-                        let input_ids_shape = input_ids.dims();
-
-                        let added_tokens =
-                            Tensor::new(added_tokens, input_ids.device())?.unsqueeze(1)?;
-
-                        let input_ids = Tensor::cat(&[&input_ids, &added_tokens], 1)
-                            .unwrap_or_else(|error| {
-                                panic!(
-                                    "Error creating new input ids {:?} error: {:?}",
-                                    &input_ids_shape, error
-                                )
-                            });
-                        let input_ids = input_ids
-                            .index_select(&indicies_to_keep, 0)
-                            .unwrap_or_else(|error| {
-                                panic!(
-                                    "Error selecting new input ids {:?} error: {:?}",
-                                    &indicies_to_keep, error
-                                )
-                            });
+                        // The prompt is already held in the key/value cache, so the next step
+                        // only feeds the tokens sampled in this one.
+                        let input_ids = Tensor::new(tokens, device)?.unsqueeze(1)?;
                         let added_attention =
-                            Tensor::zeros((input_ids_shape[0], 1), attention_mask.dtype(), device)
+                            Tensor::zeros((number_of_rows, 1), attention_mask.dtype(), device)
                                 .unwrap_or_else(|error| {
                                     panic!("Error creating added attention: {:?}", error)
                                 });
@@ -154,41 +131,319 @@ impl Decoder {
 
                         let next_batch = TokenizedBatch {
                             requests: kept_requests,
-                            token_ids: filtered_token_ids,
+                            token_ids,
                             input_ids,
                             attention_mask,
                             past_key_values,
+                            pad_id,
                         };
                         if !next_batch.is_empty() {
                             tracing::debug!(
                                 "decode_task: sending non finished batch back to generation."
                             );
-                            tokenized_batch_sender.blocking_send(next_batch)?;
+                            match tokenized_batch_sender.upgrade() {
+                                Some(sender) => sender.blocking_send(next_batch)?,
+                                None => tracing::debug!(
+                                    "decode_task: generator dropped, ending {} requests",
+                                    next_batch.requests.len()
+                                ),
+                            }
                         }
                     }
                     let filter_time = loop_start.elapsed();
 
                     let loop_end = loop_start.elapsed().as_micros();
                     let process_time = process_time.as_micros();
-                    let decode_time = decode_time.as_micros() - process_time;
-                    let send_time = send_time.as_micros() - (decode_time + process_time);
-                    let filter_time =
-                        filter_time.as_micros() - (send_time + decode_time + process_time);
-                    tracing::debug!("decoder task finished in: {:?} micro seconds | process: {:?} ms | decode: {:?} ms | send: {:?} ms | filter: {:?} ms", loop_end, process_time, decode_time, send_time, filter_time);
+                    let filter_time = filter_time.as_micros() - process_time;
+                    tracing::debug!(
+                        "decoder task finished in: {:?} micro seconds | process: {:?} ms | filter: {:?} ms",
+                        loop_end,
+                        process_time,
+                        filter_time
+                    );
+                } else {
+                    tracing::debug!("decode_task: result receiver is closed, stopping");
+                    return Ok(());
                 }
             }
         })
     }
+
+    /// Samples the next token of a single row request and sends it to the client. Returns
+    /// whether the request is finished.
+    fn sample_step(
+        tokenizer: &Tokenizer,
+        model_meta_data: &ModelMetaData,
+        request: &mut GenerationRequest,
+        logits: &Tensor,
+        row: usize,
+        token_ids: &mut [Vec<u32>],
+        next_rows: &mut NextRows,
+    ) -> Result<bool> {
+        let prompt_length = token_ids[row].len() - request.number_tokens_generated as usize;
+        let GenerationLogitsProcessor {
+            preprocess,
+            process,
+        } = &mut request.logits_processor;
+        let logit_row = preprocess.process_logits(
+            &logits.i(row)?.squeeze(0)?,
+            &token_ids[row],
+            prompt_length,
+        )?;
+        let token_id = process.sample(&logit_row)?;
+        let logprobs = match request.config.logprobs {
+            Some(number_of_alternatives) => Some(TokenLogProbs::from_logits(
+                tokenizer,
+                &logit_row,
+                token_id,
+                number_of_alternatives,
+            )?),
+            None => None,
+        };
+        token_ids[row].push(token_id);
+        request.number_tokens_generated += 1;
+        request.metrics.record_token();
+
+        let detokenizer = request
+            .detokenizer
+            .get_or_insert_with(|| IncrementalDetokenizer::new(prompt_length));
+        let config = &request.config;
+        let mut stopped_by = None;
+        let previous_length = request.generated.len();
+        if config.stop_token_ids.contains(&token_id) {
+            stopped_by = Some(StopCondition::TokenId(token_id));
+            if config.include_stop {
+                request
+                    .generated
+                    .push_str(&detokenizer.next(tokenizer, &token_ids[row])?);
+            }
+        } else {
+            request
+                .generated
+                .push_str(&detokenizer.next(tokenizer, &token_ids[row])?);
+        }
+        if stopped_by.is_none() && !config.stop.is_empty() {
+            // Only the end of the text can hold a stop sequence that was not there last step.
+            let longest_stop = config.stop.iter().map(|stop| stop.len()).max();
+            let start = previous_length.saturating_sub(longest_stop.unwrap_or_default());
+            if let Some((offset, sequence)) =
+                StopCondition::find_sequence(&request.generated, start, &config.stop)
+            {
+                let end = match config.include_stop {
+                    true => offset + sequence.len(),
+                    false => offset,
+                };
+                stopped_by = Some(StopCondition::Sequence(sequence.to_owned()));
+                request.generated.truncate(end);
+            }
+        }
+        // Text already streamed can not be taken back, so a stop sequence that started in an
+        // earlier delta only cuts the current one.
+        let delta = request
+            .generated
+            .get(previous_length..)
+            .unwrap_or_default()
+            .to_owned();
+        let reached_max_tokens = request.number_tokens_generated >= config.max_new_tokens as u32
+            || next_rows.is_at_context_limit;
+        tracing::info!("reached_max_tokens: {}", &reached_max_tokens);
+        let finish_reason = if request.is_cancelled() {
+            Some(FinishReason::Cancelled)
+        } else if stopped_by.is_some() {
+            Some(FinishReason::Stop)
+        } else if tokenizer.is_eos(token_id) {
+            Some(FinishReason::Eos)
+        } else if reached_max_tokens {
+            Some(FinishReason::Length)
+        } else {
+            None
+        };
+        let is_end_of_sequence = finish_reason.is_some();
+        let usage = is_end_of_sequence.then_some(Usage {
+            prompt_tokens: prompt_length as u32,
+            completion_tokens: request.number_tokens_generated,
+        });
+        let result = GenerationResult {
+            id: request.id.clone(),
+            content: request.generated.clone(),
+            generated: delta,
+            is_end_of_sequence,
+            config: request.config.clone(),
+            sequences: Vec::new(),
+            stopped_by,
+            finish_reason,
+            usage,
+            meta: is_end_of_sequence.then(|| {
+                request
+                    .metrics
+                    .meta_data(request.number_tokens_generated, model_meta_data.clone())
+            }),
+            logprobs,
+        };
+        Decoder::send_result(request, result);
+        if !is_end_of_sequence {
+            next_rows.indexes.push(row as u32);
+            next_rows.tokens.push(token_id);
+            next_rows
+                .token_ids
+                .push(std::mem::take(&mut token_ids[row]));
+        }
+        Ok(is_end_of_sequence)
+    }
+
+    /// Advances the beams of a request occupying `num_beams` rows starting at `row`. Nothing is
+    /// streamed while the beams are running, the best sequences are sent once the search is done.
+    /// Returns whether the request is finished.
+    fn beam_search_step(
+        tokenizer: &Tokenizer,
+        model_meta_data: &ModelMetaData,
+        request: &mut GenerationRequest,
+        logits: &Tensor,
+        row: usize,
+        token_ids: &[Vec<u32>],
+        next_rows: &mut NextRows,
+    ) -> Result<bool> {
+        let prompt_length = token_ids[row].len() - request.number_tokens_generated as usize;
+        request.number_tokens_generated += 1;
+        request.metrics.record_token();
+        let reached_max_tokens = request.number_tokens_generated
+            >= request.config.max_new_tokens as u32
+            || next_rows.is_at_context_limit;
+        let is_cancelled = request.is_cancelled();
+        let preprocess = &request.logits_processor.preprocess;
+        let beam_search = match request.beam_search.as_mut() {
+            Some(beam_search) => beam_search,
+            None => return Ok(true),
+        };
+
+        let number_of_rows = beam_search.num_beams();
+        let mut beam_logits = Vec::with_capacity(number_of_rows);
+        for beam in 0..number_of_rows {
+            let logit_row = logits.i(row + beam)?.squeeze(0)?.to_dtype(DType::F32)?;
+            beam_logits.push(preprocess.process_logits(
+                &logit_row,
+                &token_ids[row + beam],
+                prompt_length,
+            )?);
+        }
+        let log_probs = candle_nn::ops::log_softmax(&Tensor::stack(&beam_logits, 0)?, D::Minus1)?
+            .to_vec2::<f32>()?;
+        let mut eos_ids = tokenizer.eos_ids.clone();
+        eos_ids.extend_from_slice(&request.config.stop_token_ids);
+        let beams = beam_search.step(&log_probs, &eos_ids);
+
+        if is_cancelled {
+            return Ok(true);
+        }
+        if !beam_search.is_done() && !reached_max_tokens {
+            for (beam, token_id) in beams.into_iter() {
+                let mut beam_token_ids = token_ids[row + beam].clone();
+                beam_token_ids.push(token_id);
+                next_rows.indexes.push((row + beam) as u32);
+                next_rows.tokens.push(token_id);
+                next_rows.token_ids.push(beam_token_ids);
+            }
+            return Ok(false);
+        }
+
+        let prompt = &token_ids[row][..prompt_length];
+        let hypotheses = beam_search.finalize();
+        let last_token_id = hypotheses
+            .first()
+            .and_then(|best| best.token_ids.last().copied());
+        let mut finish_reason = match last_token_id {
+            Some(token_id) if tokenizer.is_eos(token_id) => FinishReason::Eos,
+            Some(token_id) if request.config.stop_token_ids.contains(&token_id) => {
+                FinishReason::Stop
+            }
+            _ => FinishReason::Length,
+        };
+        let mut stopped_by = match finish_reason {
+            FinishReason::Stop => last_token_id.map(StopCondition::TokenId),
+            _ => None,
+        };
+        let usage = Usage {
+            prompt_tokens: prompt_length as u32,
+            completion_tokens: hypotheses
+                .first()
+                .map(|best| best.token_ids.len() as u32)
+                .unwrap_or_default(),
+        };
+        // Decoded after the prompt so the first generated word keeps its leading space.
+        let mut sequences = Vec::with_capacity(hypotheses.len());
+        for hypothesis in hypotheses.into_iter() {
+            let mut sequence_token_ids = prompt.to_vec();
+            sequence_token_ids.extend_from_slice(&hypothesis.token_ids);
+            sequences.push(GeneratedSequence {
+                content: IncrementalDetokenizer::new(prompt_length)
+                    .next(tokenizer, &sequence_token_ids)?,
+                score: hypothesis.score,
+            });
+        }
+        // Beams are not cut short by stop strings, their text ends at the first one once the
+        // search is done.
+        let config = &request.config;
+        for (rank, sequence) in sequences.iter_mut().enumerate() {
+            if let Some((offset, stop)) =
+                StopCondition::find_sequence(&sequence.content, 0, &config.stop)
+            {
+                let end = match config.include_stop {
+                    true => offset + stop.len(),
+                    false => offset,
+                };
+                sequence.content.truncate(end);
+                if rank == 0 {
+                    finish_reason = FinishReason::Stop;
+                    stopped_by = Some(StopCondition::Sequence(stop.to_owned()));
+                }
+            }
+        }
+        let content = sequences
+            .first()
+            .map(|best| best.content.clone())
+            .unwrap_or_default();
+        let result = GenerationResult {
+            id: request.id.clone(),
+            generated: content.clone(),
+            content,
+            is_end_of_sequence: true,
+            config: request.config.clone(),
+            sequences,
+            stopped_by,
+            finish_reason: Some(finish_reason),
+            usage: Some(usage),
+            meta: Some(
+                request
+                    .metrics
+                    .meta_data(request.number_tokens_generated, model_meta_data.clone()),
+            ),
+            logprobs: None,
+        };
+        Decoder::send_result(request, result);
+        Ok(true)
+    }
+
+    /// Sends a result to the client of `request`. A client that disconnected only loses its own
+    /// results, the other requests of the batch keep going.
+    fn send_result(request: &GenerationRequest, result: GenerationResult) {
+        if request.sender().blocking_send(result).is_err() {
+            tracing::debug!("decode_task: request {} was cancelled", &request.id);
+        }
+    }
 }
 
 impl Decoder {
     pub fn new(
         tokenizer: std::sync::Arc<Tokenizer>,
+        model_meta_data: ModelMetaData,
+        context_length: usize,
         generation_result_receiver: Receiver<GenerationStep>,
-        tokenized_batch_sender: Sender<TokenizedBatch>,
+        tokenized_batch_sender: WeakSender<TokenizedBatch>,
     ) -> Self {
         Self {
             tokenizer,
+            model_meta_data,
+            context_length,
             generation_result_receiver,
             tokenized_batch_sender,
         }
@@ -1,27 +1,62 @@
 use super::{Receiver, Sender, TaskResult};
-use crate::{GenerationStep, Model, TokenizedBatch};
+use crate::{
+    BatchingConfig, Error, FinishReason, GenerationResult, GenerationStep, Model, ModelBackend,
+    ModelMetaData, Result, TokenizedBatch, Usage,
+};
+use std::collections::VecDeque;
 
 #[derive(Debug)]
-pub struct Generation {
-    model: Model,
+pub struct Generation<M: ModelBackend = Model> {
+    model: M,
+    config: BatchingConfig,
     tokenized_batch_receiver: Receiver<TokenizedBatch>,
     generation_result_sender: Sender<GenerationStep>,
 }
 
-impl Generation {
+impl<M: ModelBackend> Generation<M> {
     pub fn task(self) -> TaskResult<()> {
         let Generation {
             mut model,
+            config,
             mut tokenized_batch_receiver,
             generation_result_sender,
         } = self;
-        tokio::task::spawn_blocking(move || loop {
-            tracing::debug!("generation_task: awaiting batches");
-            if let Some(batch) = tokenized_batch_receiver.blocking_recv() {
+        tokio::task::spawn_blocking(move || {
+            let mut waiting_batches: VecDeque<TokenizedBatch> = VecDeque::new();
+            loop {
+                if waiting_batches.is_empty() {
+                    tracing::debug!("generation_task: awaiting batches");
+                    match tokenized_batch_receiver.blocking_recv() {
+                        Some(batch) => waiting_batches.push_back(batch),
+                        None => {
+                            // Both the tokenize task and the decoder are done, dropping the
+                            // model frees its weights.
+                            tracing::debug!("generation_task: batch receiver is closed, stopping");
+                            return Ok(());
+                        }
+                    }
+                }
+                while let Ok(batch) = tokenized_batch_receiver.try_recv() {
+                    waiting_batches.push_back(batch);
+                }
                 let loop_start = tokio::time::Instant::now();
 
-                let logits = model.forward(&batch)?;
-                let generation_time = loop_start.elapsed().as_micros();
+                let mut batch = Self::schedule(&model, &config, &mut waiting_batches)?;
+                let batch_size = batch.number_of_rows();
+                for request in batch.requests.values_mut() {
+                    request.metrics.record_step(batch_size);
+                }
+                let schedule_time = loop_start.elapsed().as_micros();
+
+                let logits = match model.forward(&mut batch) {
+                    Ok(logits) => logits,
+                    Err(error) => {
+                        tracing::error!("generation_task: forward failed: {:?}", error);
+                        Self::fail_batch(batch, model.meta_data())?;
+                        continue;
+                    }
+                };
+                let generation_time = loop_start.elapsed().as_micros() - schedule_time;
 
                 let sync_start = loop_start.elapsed().as_micros();
                 // let device = logits.device();
@@ -30,22 +65,258 @@ impl Generation {
 
                 generation_result_sender.blocking_send(GenerationStep { batch, logits })?;
                 let loop_end = loop_start.elapsed().as_micros();
-                tracing::debug!("generation task finished in: {:?} micro seconds | generation_time: {} ms | sync_time: {} ms", loop_end, generation_time, sync_time);
+                tracing::debug!("generation task finished in: {:?} micro seconds | schedule_time: {} ms | generation_time: {} ms | sync_time: {} ms", loop_end, schedule_time, generation_time, sync_time);
             }
         })
     }
+
+    /// Ends every request of a batch whose forward pass failed with a `FinishReason::Error`
+    /// result, the other batches keep running.
+    fn fail_batch(batch: TokenizedBatch, model_meta_data: ModelMetaData) -> Result<()> {
+        let TokenizedBatch {
+            requests,
+            token_ids,
+            ..
+        } = batch;
+        let mut row = 0;
+        for request in requests.into_values() {
+            let prompt_tokens = token_ids
+                .get(row)
+                .map(|row_token_ids| row_token_ids.len() as u32 - request.number_tokens_generated)
+                .unwrap_or_default();
+            row += request.number_of_rows();
+            if request.sender().is_closed() {
+                continue;
+            }
+            request.sender().blocking_send(GenerationResult {
+                id: request.id.clone(),
+                content: String::new(),
+                generated: String::new(),
+                is_end_of_sequence: true,
+                config: request.config.clone(),
+                sequences: Vec::new(),
+                stopped_by: None,
+                finish_reason: Some(FinishReason::Error),
+                usage: Some(Usage {
+                    prompt_tokens,
+                    completion_tokens: request.number_tokens_generated,
+                }),
+                meta: Some(
+                    request
+                        .metrics
+                        .meta_data(request.number_tokens_generated, model_meta_data.clone()),
+                ),
+                logprobs: None,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Takes the oldest waiting batch and merges into it every other waiting batch of the same
+    /// phase (prefill or decode) that still fits the configured limits. Batches left out stay
+    /// queued for the next step.
+    fn schedule(
+        model: &M,
+        config: &BatchingConfig,
+        waiting_batches: &mut VecDeque<TokenizedBatch>,
+    ) -> Result<TokenizedBatch> {
+        let first = waiting_batches
+            .pop_front()
+            .ok_or_else(|| Error::GenerationError {
+                message: "No batch waiting to be scheduled.".to_owned(),
+            })?;
+        let is_prefill = first.is_prefill();
+        let mut batch_size = first.number_of_rows();
+        let mut sequence_length = first.sequence_length()?;
+        let mut selected = vec![first];
+
+        let mut index = 0;
+        while index < waiting_batches.len() {
+            let candidate = &waiting_batches[index];
+            let next_batch_size = batch_size + candidate.number_of_rows();
+            let next_sequence_length = sequence_length.max(candidate.sequence_length()?);
+            let fits = next_batch_size <= config.max_batch_size
+                && next_batch_size * next_sequence_length <= config.max_batch_tokens;
+            if candidate.is_prefill() == is_prefill && fits {
+                if let Some(candidate) = waiting_batches.remove(index) {
+                    selected.push(candidate);
+                }
+                batch_size = next_batch_size;
+                sequence_length = next_sequence_length;
+            } else {
+                index += 1;
+            }
+        }
+
+        if selected.len() == 1 {
+            return Ok(selected.remove(0));
+        }
+        tracing::debug!(
+            "generation_task: merging {} batches into one of len: {} and sequence length: {}",
+            selected.len(),
+            batch_size,
+            sequence_length
+        );
+        if !is_prefill {
+            let mut cache_length = 0;
+            for batch in selected.iter() {
+                if let Some(cache) = batch.past_key_values.as_ref() {
+                    cache_length = cache_length.max(cache.sequence_length()?);
+                }
+            }
+            for batch in selected.iter_mut() {
+                if let Some(cache) = batch.past_key_values.take() {
+                    let positions = cache_length - cache.sequence_length()?;
+                    batch.past_key_values = Some(model.shift_key_value_cache(cache, positions)?);
+                }
+            }
+        }
+        TokenizedBatch::concatenate(selected)
+    }
 }
 
-impl Generation {
+impl<M: ModelBackend> Generation<M> {
     pub fn new(
-        model: Model,
+        model: M,
+        config: BatchingConfig,
         tokenized_batch_receiver: Receiver<TokenizedBatch>,
         generation_result_sender: Sender<GenerationStep>,
     ) -> Self {
         Self {
             model,
+            config,
             tokenized_batch_receiver,
             generation_result_sender,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GenerationRequest, KeyValueCache, Prompt, ScriptedModel};
+    use candle_core::{DType, Device, Tensor};
+    use indexmap::IndexMap;
+
+    /// A single request batch, in prefill with `length` prompt tokens or in decode with `length`
+    /// cached positions filled with ones.
+    fn batch(id: &str, length: usize, is_prefill: bool) -> Result<TokenizedBatch> {
+        let prompt = Prompt {
+            id: id.to_owned(),
+            ..Prompt::from("hello")
+        };
+        let (sender, _receiver) = tokio::sync::mpsc::channel(1);
+        let mut requests = IndexMap::new();
+        requests.insert(
+            id.to_owned(),
+            GenerationRequest::from_prompt(prompt, sender),
+        );
+        let (input_length, past_key_values) = if is_prefill {
+            (length, None)
+        } else {
+            let cache = Tensor::ones((1, 1, length, 1), DType::F32, &Device::Cpu)?;
+            let layer = Some((cache.clone(), cache));
+            (1, Some(KeyValueCache::new(vec![layer])))
+        };
+        let sequence_length = if is_prefill { length } else { length + 1 };
+        Ok(TokenizedBatch {
+            requests,
+            token_ids: vec![vec![3; sequence_length]],
+            input_ids: Tensor::full(3u32, (1, input_length), &Device::Cpu)?,
+            attention_mask: Tensor::zeros((1, sequence_length), DType::F32, &Device::Cpu)?,
+            past_key_values,
+            pad_id: 0,
+        })
+    }
+
+    fn schedule(
+        config: BatchingConfig,
+        waiting_batches: &mut VecDeque<TokenizedBatch>,
+    ) -> Result<TokenizedBatch> {
+        let model = ScriptedModel::new(vec![3], 2, 8);
+        Generation::schedule(&model, &config, waiting_batches)
+    }
+
+    fn ids(batch: &TokenizedBatch) -> Vec<&str> {
+        batch.requests.keys().map(|id| id.as_str()).collect()
+    }
+
+    #[test]
+    fn merges_the_waiting_batches_of_the_same_phase() -> Result<()> {
+        let mut waiting_batches = VecDeque::from([
+            batch("a", 3, true)?,
+            batch("b", 4, false)?,
+            batch("c", 5, true)?,
+        ]);
+        let scheduled = schedule(BatchingConfig::default(), &mut waiting_batches)?;
+        assert_eq!(ids(&scheduled), vec!["a", "c"]);
+        assert_eq!(scheduled.input_ids.dims(), &[2, 5]);
+        assert!(scheduled.is_prefill());
+
+        assert_eq!(waiting_batches.len(), 1);
+        assert_eq!(ids(&waiting_batches[0]), vec!["b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn stops_merging_at_the_batch_size_limit() -> Result<()> {
+        let mut waiting_batches = VecDeque::from([
+            batch("a", 3, true)?,
+            batch("b", 3, true)?,
+            batch("c", 3, true)?,
+        ]);
+        let config = BatchingConfig {
+            max_batch_size: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            ids(&schedule(config, &mut waiting_batches)?),
+            vec!["a", "b"]
+        );
+        assert_eq!(ids(&schedule(config, &mut waiting_batches)?), vec!["c"]);
+        assert!(waiting_batches.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn skips_the_batches_over_the_token_limit() -> Result<()> {
+        let mut waiting_batches = VecDeque::from([
+            batch("a", 3, true)?,
+            batch("b", 6, true)?,
+            batch("c", 2, true)?,
+        ]);
+        let config = BatchingConfig {
+            max_batch_tokens: 10,
+            ..Default::default()
+        };
+        // Padded to 6 positions, "a" and "b" would take 12 tokens.
+        assert_eq!(
+            ids(&schedule(config, &mut waiting_batches)?),
+            vec!["a", "c"]
+        );
+        assert_eq!(ids(&waiting_batches[0]), vec!["b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn shifts_the_shorter_caches_when_merging() -> Result<()> {
+        let mut waiting_batches = VecDeque::from([batch("a", 2, false)?, batch("b", 4, false)?]);
+        let scheduled = schedule(BatchingConfig::default(), &mut waiting_batches)?;
+        assert_eq!(ids(&scheduled), vec!["a", "b"]);
+
+        let cache = scheduled.past_key_values.as_ref().unwrap();
+        assert_eq!(cache.sequence_length()?, 4);
+        let (key, _value) = cache.layers[0].as_ref().unwrap();
+        assert_eq!(
+            key.flatten_all()?.to_vec1::<f32>()?,
+            vec![0., 0., 1., 1., 1., 1., 1., 1.]
+        );
+        let attention_mask = scheduled.attention_mask.to_vec2::<f32>()?;
+        assert_eq!(
+            attention_mask[0],
+            vec![f32::NEG_INFINITY, f32::NEG_INFINITY, 0., 0., 0.]
+        );
+        assert_eq!(attention_mask[1], vec![0.; 5]);
+        Ok(())
+    }
+}
@@ -8,6 +8,7 @@ use crate::Result;
 pub type TaskResult<T> = tokio::task::JoinHandle<Result<T>>;
 pub type Receiver<T> = tokio::sync::mpsc::Receiver<T>;
 pub type Sender<T> = tokio::sync::mpsc::Sender<T>;
+pub type WeakSender<T> = tokio::sync::mpsc::WeakSender<T>;
 pub use batching::Batching;
 pub use decoder::Decoder;
 pub use generation::Generation;
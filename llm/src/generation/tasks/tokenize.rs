@@ -28,6 +28,9 @@ impl Tokenize {
                     tokenized_batch_sender.blocking_send(tokenized_batch)?;
                     let loop_end = loop_start.elapsed().as_micros();
                     tracing::debug!("tokenize task finished in: {:?} micro seconds", loop_end);
+                } else {
+                    tracing::debug!("tokenize_task: batch receiver is closed, stopping");
+                    return Ok(());
                 }
             }
         })
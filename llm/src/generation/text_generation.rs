@@ -1,13 +1,13 @@
-use crate::{Model, ModelConfig, Result, TokenizedBatch, Tokenizer};
+use crate::{Model, ModelBackend, ModelConfig, Result, TokenizedBatch, Tokenizer};
 use candle_core::Tensor;
 
-pub struct TextGeneration {
-    pub model: Model,
+pub struct TextGeneration<M: ModelBackend = Model> {
+    pub model: M,
     pub tokenizer: Tokenizer,
 }
 
-impl TextGeneration {
-    pub fn next_token(&mut self, batch: &TokenizedBatch) -> Result<Tensor> {
+impl<M: ModelBackend> TextGeneration<M> {
+    pub fn next_token(&mut self, batch: &mut TokenizedBatch) -> Result<Tensor> {
         let logits = self.model.forward(batch)?.squeeze(1)?;
         Ok(logits)
     }
@@ -16,7 +16,7 @@ impl TextGeneration {
 impl TextGeneration {
     pub fn new(config: ModelConfig) -> Result<Self> {
         Ok(Self {
-            tokenizer: Tokenizer::load(config.model_id)?,
+            tokenizer: Tokenizer::from_model_config(&config)?,
             model: Model::load(config)?,
         })
     }
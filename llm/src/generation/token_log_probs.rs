@@ -0,0 +1,133 @@
+use crate::{Tokenizer, TokenizerResult};
+use candle_core::{DType, Tensor, D};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogProb {
+    pub token_id: u32,
+    pub token: String,
+    pub logprob: f32,
+}
+
+/// Log probability of a sampled token and of the most likely tokens at its position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogProbs {
+    pub token: TokenLogProb,
+    /// Sorted from most to least likely.
+    pub alternatives: Vec<TokenLogProb>,
+}
+
+impl TokenLogProbs {
+    /// `logits` is the `(vocab_size,)` row the token was sampled from, after the penalties but
+    /// before the temperature and the top-k/top-p filtering.
+    pub fn from_logits(
+        tokenizer: &Tokenizer,
+        logits: &Tensor,
+        token_id: u32,
+        number_of_alternatives: usize,
+    ) -> TokenizerResult<Self> {
+        let log_probs = candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?
+            .to_vec1::<f32>()?;
+        Self::from_log_probs(tokenizer, &log_probs, token_id, number_of_alternatives)
+    }
+
+    /// `log_probs` is the log softmax over the vocabulary the token was sampled from.
+    pub fn from_log_probs(
+        tokenizer: &Tokenizer,
+        log_probs: &[f32],
+        token_id: u32,
+        number_of_alternatives: usize,
+    ) -> TokenizerResult<Self> {
+        let mut token_ids: Vec<u32> = (0..log_probs.len() as u32).collect();
+        let number_of_alternatives = number_of_alternatives.min(token_ids.len());
+        let by_log_prob =
+            |a: &u32, b: &u32| log_probs[*b as usize].total_cmp(&log_probs[*a as usize]);
+        if number_of_alternatives > 0 && number_of_alternatives < token_ids.len() {
+            token_ids.select_nth_unstable_by(number_of_alternatives - 1, by_log_prob);
+        }
+        token_ids.truncate(number_of_alternatives);
+        token_ids.sort_by(by_log_prob);
+
+        let token_log_prob = |token_id: u32| -> TokenizerResult<TokenLogProb> {
+            Ok(TokenLogProb {
+                token_id,
+                token: tokenizer.decode(&[token_id], false)?,
+                logprob: log_probs
+                    .get(token_id as usize)
+                    .copied()
+                    .unwrap_or(f32::NEG_INFINITY),
+            })
+        };
+        let mut alternatives = Vec::with_capacity(token_ids.len());
+        for alternative in token_ids.into_iter() {
+            alternatives.push(token_log_prob(alternative)?);
+        }
+        Ok(Self {
+            token: token_log_prob(token_id)?,
+            alternatives,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+    use huggingface_tokenizers::models::wordlevel::WordLevel;
+
+    fn tokenizer() -> TokenizerResult<Tokenizer> {
+        let vocab = ["a", "b", "c", "d"]
+            .into_iter()
+            .enumerate()
+            .map(|(token_id, token)| (token.to_owned(), token_id as u32))
+            .collect();
+        let model = WordLevel::builder().vocab(vocab).build()?;
+        Tokenizer::new(
+            huggingface_tokenizers::Tokenizer::new(model),
+            "a".to_owned(),
+            "d".to_owned(),
+            "d".to_owned(),
+            None,
+        )
+    }
+
+    fn log_probs(number_of_alternatives: usize) -> TokenizerResult<(TokenLogProbs, Vec<f32>)> {
+        let logits = [1f32, 3., 2., 0.5];
+        let log_sum_exp = logits.iter().map(|logit| logit.exp()).sum::<f32>().ln();
+        let expected = logits.iter().map(|logit| logit - log_sum_exp).collect();
+        let logits = Tensor::new(&logits, &Device::Cpu)?;
+        let log_probs =
+            TokenLogProbs::from_logits(&tokenizer()?, &logits, 0, number_of_alternatives)?;
+        Ok((log_probs, expected))
+    }
+
+    #[test]
+    fn logprobs_are_the_log_softmax_of_the_logits() -> TokenizerResult<()> {
+        let (log_probs, expected) = log_probs(4)?;
+        assert_eq!(log_probs.token.token_id, 0);
+        assert_eq!(log_probs.token.token, "a");
+        approx::assert_abs_diff_eq!(log_probs.token.logprob, expected[0], epsilon = 1e-5);
+        for alternative in log_probs.alternatives.iter() {
+            approx::assert_abs_diff_eq!(
+                alternative.logprob,
+                expected[alternative.token_id as usize],
+                epsilon = 1e-5
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn alternatives_are_the_most_likely_tokens_in_order() -> TokenizerResult<()> {
+        let token_ids = |log_probs: TokenLogProbs| -> Vec<u32> {
+            log_probs
+                .alternatives
+                .iter()
+                .map(|alternative| alternative.token_id)
+                .collect()
+        };
+        assert_eq!(token_ids(log_probs(2)?.0), vec![1, 2]);
+        assert_eq!(token_ids(log_probs(10)?.0), vec![1, 2, 0, 3]);
+        assert!(log_probs(0)?.0.alternatives.is_empty());
+        Ok(())
+    }
+}
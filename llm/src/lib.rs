@@ -10,6 +10,7 @@ extern crate candle_examples;
 extern crate candle_nn;
 extern crate candle_transformers;
 extern crate clap;
+#[cfg(feature = "cuda")]
 extern crate cudarc;
 extern crate hf_hub;
 extern crate minijinja;
@@ -29,6 +30,7 @@ mod logits;
 mod models;
 mod prompts;
 mod tokenizers;
+mod traits;
 mod utils;
 
 pub use candle_core::DType;
@@ -41,4 +43,5 @@ pub use logits::*;
 pub use models::*;
 pub use prompts::*;
 pub use tokenizers::*;
+pub use traits::*;
 pub use utils::*;
@@ -1,25 +1,162 @@
 use crate::{PromptConfig, Result};
-use candle_core::Tensor;
-// use candle_transformers;
+use candle_core::{DType, Tensor};
+use std::collections::HashMap;
 
 #[derive(Debug)]
 pub struct LogitsPreProcessor {
     repetition_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    penalty_window: Option<usize>,
 }
 
 impl LogitsPreProcessor {
     pub fn from_config(config: &PromptConfig) -> Self {
         Self {
             repetition_penalty: config.repetition_penalty,
+            frequency_penalty: config.frequency_penalty,
+            presence_penalty: config.presence_penalty,
+            penalty_window: config.penalty_window,
         }
     }
 
-    pub fn process_logits(&self, logits: Tensor) -> Result<Tensor> {
-        if let Some(_repetition_penalty) = self.repetition_penalty {
-            // todo actually apply rep penalty
-            Ok(logits)
-        } else {
-            Ok(logits)
+    pub fn is_enabled(&self) -> bool {
+        self.repetition_penalty.is_some()
+            || self.frequency_penalty.is_some()
+            || self.presence_penalty.is_some()
+    }
+
+    /// Applies the penalties to a single `(vocab_size,)` row of logits given the token history of
+    /// the sequence, whose first `prompt_length` tokens are the prompt. Only the last
+    /// `penalty_window` tokens of the history are looked at when a window is set.
+    ///
+    /// - repetition penalty: positive logits of seen tokens, prompt included, are divided by it,
+    ///   negative ones multiplied.
+    /// - frequency penalty: subtracted once per occurrence of the token in the generated text.
+    /// - presence penalty: subtracted once if the token occurs in the generated text.
+    pub fn process_logits(
+        &self,
+        logits: &Tensor,
+        token_ids: &[u32],
+        prompt_length: usize,
+    ) -> Result<Tensor> {
+        if !self.is_enabled() || token_ids.is_empty() {
+            return Ok(logits.clone());
+        }
+        let start = match self.penalty_window {
+            Some(window) => token_ids.len().saturating_sub(window),
+            None => 0,
+        };
+        // Occurrences in the generated text, zero for the tokens only seen in the prompt.
+        let mut counts: HashMap<u32, usize> = HashMap::with_capacity(token_ids.len() - start);
+        for (index, token_id) in token_ids.iter().enumerate().skip(start) {
+            let count = counts.entry(*token_id).or_default();
+            if index >= prompt_length {
+                *count += 1;
+            }
         }
+
+        let mut values = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        for (token_id, count) in counts.into_iter() {
+            if let Some(logit) = values.get_mut(token_id as usize) {
+                if let Some(penalty) = self.repetition_penalty {
+                    if *logit >= 0. {
+                        *logit /= penalty
+                    } else {
+                        *logit *= penalty
+                    }
+                }
+                if count == 0 {
+                    continue;
+                }
+                if let Some(penalty) = self.frequency_penalty {
+                    *logit -= penalty * count as f32
+                }
+                if let Some(penalty) = self.presence_penalty {
+                    *logit -= penalty
+                }
+            }
+        }
+        let length = values.len();
+        Ok(Tensor::from_vec(values, length, logits.device())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    fn penalties(
+        repetition_penalty: Option<f32>,
+        frequency_penalty: Option<f32>,
+        presence_penalty: Option<f32>,
+        penalty_window: Option<usize>,
+    ) -> LogitsPreProcessor {
+        LogitsPreProcessor {
+            repetition_penalty,
+            frequency_penalty,
+            presence_penalty,
+            penalty_window,
+        }
+    }
+
+    fn process(
+        preprocessor: &LogitsPreProcessor,
+        token_ids: &[u32],
+        prompt_length: usize,
+    ) -> Result<Vec<f32>> {
+        let logits = Tensor::new(&[2f32, -2., 1., 0.5], &Device::Cpu)?;
+        Ok(preprocessor
+            .process_logits(&logits, token_ids, prompt_length)?
+            .to_vec1()?)
+    }
+
+    #[test]
+    fn repetition_penalty_counts_the_prompt() -> Result<()> {
+        let preprocessor = penalties(Some(2.), None, None, None);
+        assert_eq!(process(&preprocessor, &[0, 1], 2)?, vec![1., -4., 1., 0.5]);
+        assert_eq!(
+            process(&preprocessor, &[0, 1, 1], 1)?,
+            vec![1., -4., 1., 0.5]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn frequency_penalty_counts_only_the_generated_tokens() -> Result<()> {
+        let preprocessor = penalties(None, Some(0.5), None, None);
+        assert_eq!(
+            process(&preprocessor, &[2, 2, 0, 0, 0], 2)?,
+            vec![0.5, -2., 1., 0.5]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn presence_penalty_is_subtracted_once_per_generated_token() -> Result<()> {
+        let preprocessor = penalties(None, None, Some(1.), None);
+        assert_eq!(
+            process(&preprocessor, &[2, 0, 0, 3], 1)?,
+            vec![1., -2., 1., -0.5]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn penalty_window_only_looks_at_the_last_tokens() -> Result<()> {
+        let preprocessor = penalties(None, Some(1.), None, Some(2));
+        assert_eq!(
+            process(&preprocessor, &[0, 1, 1, 2], 0)?,
+            vec![2., -3., 0., 0.5]
+        );
+
+        // The window covers the end of the prompt, which only the repetition penalty sees.
+        let preprocessor = penalties(Some(2.), Some(1.), None, Some(3));
+        assert_eq!(
+            process(&preprocessor, &[0, 1, 3, 3], 3)?,
+            vec![2., -4., 1., -0.75]
+        );
+        Ok(())
     }
 }
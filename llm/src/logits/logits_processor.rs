@@ -58,4 +58,26 @@ impl Debug for LogitsProcessor {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    #[test]
+    fn same_seed_reproduces_the_same_tokens() -> Result<()> {
+        let logits = Tensor::new(&[1f32, 1., 1., 1., 1., 1., 1., 1.], &Device::Cpu)?;
+        let sampling = Sampling::All { temperature: 1. };
+        let mut first = LogitsProcessor::from_sampling(42, sampling.clone());
+        let mut second = LogitsProcessor::from_sampling(42, sampling);
+
+        let first_tokens = (0..16)
+            .map(|_| first.sample(&logits))
+            .collect::<Result<Vec<u32>>>()?;
+        let second_tokens = (0..16)
+            .map(|_| second.sample(&logits))
+            .collect::<Result<Vec<u32>>>()?;
+        assert_eq!(first_tokens, second_tokens);
+        // The random stream advances between tokens instead of restarting for each one.
+        assert!(first_tokens.iter().any(|token| *token != first_tokens[0]));
+        Ok(())
+    }
+}
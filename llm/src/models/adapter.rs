@@ -0,0 +1,155 @@
+use crate::{ModelError, ModelFiles, ModelResult};
+use candle_core::{DType, Device, Tensor};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Prefix PEFT adds to the names of the base model tensors.
+const PEFT_PREFIX: &str = "base_model.model.";
+
+/// `adapter_config.json` of a PEFT LoRA adapter, only the fields the forward pass needs.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct LoraConfig {
+    pub r: usize,
+    pub lora_alpha: f64,
+    #[serde(default)]
+    pub use_rslora: bool,
+    pub peft_type: Option<String>,
+}
+
+impl LoraConfig {
+    /// Factor of the low rank update, `lora_alpha / r`, or `lora_alpha / sqrt(r)` for rank
+    /// stabilized adapters.
+    pub fn scale(&self) -> f64 {
+        if self.use_rslora {
+            self.lora_alpha / (self.r as f64).sqrt()
+        } else {
+            self.lora_alpha / self.r as f64
+        }
+    }
+}
+
+/// A LoRA adapter in the PEFT format, selected by requests through its name.
+#[derive(Debug)]
+pub struct LoraAdapter {
+    pub name: String,
+    pub config: LoraConfig,
+    /// `lora_A` and `lora_B` weights, keyed by the tensor names of the base model without the
+    /// PEFT prefix, `model.layers.0.self_attn.q_proj.lora_A.weight`.
+    tensors: HashMap<String, Tensor>,
+}
+
+impl LoraAdapter {
+    pub fn new(name: String, config: LoraConfig, tensors: HashMap<String, Tensor>) -> Self {
+        let tensors = tensors
+            .into_iter()
+            .map(|(tensor_name, tensor)| {
+                let tensor_name = tensor_name
+                    .strip_prefix(PEFT_PREFIX)
+                    .map(str::to_owned)
+                    .unwrap_or(tensor_name)
+                    .replace(".default.", ".");
+                (tensor_name, tensor)
+            })
+            .collect();
+        Self {
+            name,
+            config,
+            tensors,
+        }
+    }
+
+    /// Reads `adapter_config.json` and `adapter_model.safetensors` from `directory`, the weights
+    /// are loaded in the dtype of the base model.
+    pub fn from_dir(
+        name: &str,
+        directory: &Path,
+        device: &Device,
+        dtype: DType,
+    ) -> ModelResult<Self> {
+        let file = |file: &str| {
+            Some(directory.join(file))
+                .filter(|path| path.is_file())
+                .ok_or_else(|| ModelError::MissingFile {
+                    directory: directory.to_path_buf(),
+                    file: file.to_owned(),
+                })
+        };
+        tracing::debug!("loading adapter {name} from {:?}", directory);
+        let config: LoraConfig = ModelFiles::load_file(file("adapter_config.json")?)?;
+        if let Some(peft_type) = config
+            .peft_type
+            .as_deref()
+            .filter(|&peft_type| peft_type != "LORA")
+        {
+            return Err(ModelError::InvalidAdapter {
+                name: name.to_owned(),
+                reason: format!("unsupported peft_type {peft_type}"),
+            });
+        }
+        let tensors = candle_core::safetensors::load(file("adapter_model.safetensors")?, device)?
+            .into_iter()
+            .map(|(tensor_name, tensor)| Ok((tensor_name, tensor.to_dtype(dtype)?)))
+            .collect::<candle_core::Result<_>>()?;
+        Ok(Self::new(name.to_owned(), config, tensors))
+    }
+
+    /// `lora_A` `(r, in)` and `lora_B` `(out, r)` of the linear layer at `prefix`,
+    /// `model.layers.0.self_attn.q_proj`, when the adapter targets it.
+    pub fn weights(&self, prefix: &str) -> Option<(&Tensor, &Tensor)> {
+        let a = self.tensors.get(&format!("{prefix}.lora_A.weight"))?;
+        let b = self.tensors.get(&format!("{prefix}.lora_B.weight"))?;
+        Some((a, b))
+    }
+
+    pub fn scale(&self) -> f64 {
+        self.config.scale()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::models::lora::lora_linear_no_bias;
+    use candle_core::Module;
+
+    #[test]
+    fn rows_only_go_through_their_adapter() -> candle_core::Result<()> {
+        let device = Device::Cpu;
+        let base: HashMap<String, Tensor> = [(
+            "q_proj.weight".to_owned(),
+            Tensor::eye(2, DType::F32, &device)?,
+        )]
+        .into_iter()
+        .collect();
+        let vb = candle_nn::VarBuilder::from_tensors(base, DType::F32, &device);
+        let mut projection = lora_linear_no_bias(2, 2, vb.pp("q_proj"))?;
+
+        let tensors: HashMap<String, Tensor> = [
+            (
+                "base_model.model.q_proj.lora_A.weight".to_owned(),
+                Tensor::new(&[[1f32, 0.]], &device)?,
+            ),
+            (
+                "base_model.model.q_proj.lora_B.weight".to_owned(),
+                Tensor::new(&[[0f32], [1.]], &device)?,
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let config = LoraConfig {
+            r: 1,
+            lora_alpha: 2.,
+            use_rslora: false,
+            peft_type: Some("LORA".to_owned()),
+        };
+        let adapter = LoraAdapter::new("swap".to_owned(), config, tensors);
+        assert!(projection.load_adapter(&adapter)?);
+
+        // The first row uses the base weights, the second one the adapter.
+        projection.set_rows(&[(0, Tensor::new(&[1u32], &device)?)]);
+        let xs = Tensor::new(&[[[1f32, 1.]], [[1., 1.]]], &device)?;
+        let ys: Vec<Vec<Vec<f32>>> = projection.forward(&xs)?.to_vec3()?;
+        assert_eq!(ys, vec![vec![vec![1., 1.]], vec![vec![1., 3.]]]);
+        Ok(())
+    }
+}
@@ -12,6 +12,41 @@ pub enum ModelError {
     JsonError(#[from] serde_json::Error),
     #[error(transparent)]
     TokenizerError(#[from] crate::tokenizers::TokenizerError),
+    #[error(transparent)]
+    DeviceError(#[from] crate::device::DeviceError),
+    #[error("Missing {file} in model directory {directory:?}")]
+    MissingFile {
+        directory: std::path::PathBuf,
+        file: String,
+    },
+    #[error("Unsupported model architecture: {architectures:?} (model_type: {model_type:?})")]
+    UnsupportedArchitecture {
+        architectures: Vec<String>,
+        model_type: Option<String>,
+    },
+    #[error("Missing {key} in the GGUF file")]
+    MissingGgufMetadata { key: String },
+    #[error("No GGUF file of the {variant} quantization among {available:?}")]
+    GgufVariantNotFound {
+        variant: String,
+        available: Vec<String>,
+    },
+    #[error("Unsupported dtype {0}, expected BF16, F16 or F32")]
+    InvalidDType(String),
+    #[error("Dtype {dtype:?} is not supported on {device}: {reason}")]
+    UnsupportedDType {
+        dtype: candle_core::DType,
+        device: String,
+        reason: String,
+    },
+    #[error("Unsupported quantization {0}, expected Q8_0 or Q4_K")]
+    InvalidQuantization(String),
+    #[error("Quantized weights are not supported for the {architecture} architecture")]
+    UnsupportedQuantization { architecture: &'static str },
+    #[error("Unsupported rope_scaling {rope_type}: {reason}")]
+    UnsupportedRopeScaling { rope_type: String, reason: String },
+    #[error("Invalid LoRA adapter {name}: {reason}")]
+    InvalidAdapter { name: String, reason: String },
     #[error("Generation error: {message}")]
     GenerationError { message: String },
 }
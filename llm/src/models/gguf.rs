@@ -0,0 +1,137 @@
+use crate::{ModelError, ModelResult};
+use candle_core::quantized::gguf_file;
+use std::path::{Path, PathBuf};
+
+/// Variants tried in order when no quantization variant is requested.
+pub const DEFAULT_QUANT_VARIANTS: &[&str] = &["Q4_K_M", "Q4_K_S", "Q5_K_M", "Q8_0"];
+
+/// A GGUF file with its metadata and tensor infos read, the tensors themselves are only loaded
+/// when the network is built.
+#[derive(Debug)]
+pub struct GgufFile {
+    pub path: PathBuf,
+    pub content: gguf_file::Content,
+}
+
+impl GgufFile {
+    pub fn open(path: &Path) -> ModelResult<Self> {
+        tracing::debug!("reading gguf metadata: {:?}", path);
+        let mut file = std::fs::File::open(path)?;
+        let content = gguf_file::Content::read(&mut file)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            content,
+        })
+    }
+
+    pub fn metadata(&self, key: &str) -> ModelResult<&gguf_file::Value> {
+        self.content
+            .metadata
+            .get(key)
+            .ok_or_else(|| ModelError::MissingGgufMetadata {
+                key: key.to_owned(),
+            })
+    }
+
+    /// `general.architecture`, the prefix of the hyperparameter keys. Mistral models use the
+    /// `llama` architecture.
+    pub fn architecture(&self) -> ModelResult<&str> {
+        Ok(self.metadata("general.architecture")?.to_string()?.as_str())
+    }
+
+    /// Hyperparameter `key` of the file's architecture, e.g. `block_count` for
+    /// `llama.block_count`.
+    pub fn hyperparameter(&self, key: &str) -> ModelResult<&gguf_file::Value> {
+        self.metadata(&format!("{}.{key}", self.architecture()?))
+    }
+
+    /// Shape of a tensor, in candle's row major order.
+    pub fn tensor_shape(&self, name: &str) -> ModelResult<&[usize]> {
+        self.content
+            .tensor_infos
+            .get(name)
+            .map(|info| info.shape.dims())
+            .ok_or_else(|| ModelError::MissingGgufMetadata {
+                key: name.to_owned(),
+            })
+    }
+}
+
+/// Picks the file of the requested quantization variant (`Q4_K_M`, `Q8_0`, ...) among GGUF file
+/// names. The variant has to be a whole component of the name, so `Q4_K` does not match
+/// `model.Q4_K_M.gguf`. Without a variant the first of `DEFAULT_QUANT_VARIANTS` found is used,
+/// then the first file.
+pub fn select_gguf_variant<'a>(
+    file_names: &'a [String],
+    variant: Option<&str>,
+) -> ModelResult<&'a String> {
+    let not_found = |variant: &str| ModelError::GgufVariantNotFound {
+        variant: variant.to_owned(),
+        available: file_names.to_vec(),
+    };
+    let find = |variant: &str| {
+        file_names
+            .iter()
+            .find(|file_name| has_variant(file_name, variant))
+    };
+    match variant {
+        Some(variant) => find(variant).ok_or_else(|| not_found(variant)),
+        None => DEFAULT_QUANT_VARIANTS
+            .iter()
+            .find_map(|variant| find(variant))
+            .or_else(|| file_names.first())
+            .ok_or_else(|| not_found("*.gguf")),
+    }
+}
+
+fn has_variant(file_name: &str, variant: &str) -> bool {
+    let file_name = file_name.to_lowercase();
+    let variant = variant.to_lowercase();
+    let is_separator = |c: Option<char>| matches!(c, None | Some('.' | '-' | '/'));
+    file_name.match_indices(&variant).any(|(start, _)| {
+        let end = start + variant.len();
+        is_separator(file_name[..start].chars().last())
+            && is_separator(file_name[end..].chars().next())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_names() -> Vec<String> {
+        [
+            "mistral-7b-instruct-v0.2.Q2_K.gguf",
+            "mistral-7b-instruct-v0.2.Q4_K.gguf",
+            "mistral-7b-instruct-v0.2.Q4_K_M.gguf",
+            "mistral-7b-instruct-v0.2.Q8_0.gguf",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect()
+    }
+
+    #[test]
+    fn selects_the_requested_variant() {
+        let file_names = file_names();
+        let selected = select_gguf_variant(&file_names, Some("q4_k")).unwrap();
+        assert_eq!(selected, "mistral-7b-instruct-v0.2.Q4_K.gguf");
+        let selected = select_gguf_variant(&file_names, Some("Q8_0")).unwrap();
+        assert_eq!(selected, "mistral-7b-instruct-v0.2.Q8_0.gguf");
+    }
+
+    #[test]
+    fn defaults_to_the_preferred_variants() {
+        let file_names = file_names();
+        let selected = select_gguf_variant(&file_names, None).unwrap();
+        assert_eq!(selected, "mistral-7b-instruct-v0.2.Q4_K_M.gguf");
+    }
+
+    #[test]
+    fn rejects_missing_variants() {
+        assert!(matches!(
+            select_gguf_variant(&file_names(), Some("Q6_K")),
+            Err(ModelError::GgufVariantNotFound { .. })
+        ));
+    }
+}
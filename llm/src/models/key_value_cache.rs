@@ -0,0 +1,150 @@
+use crate::ModelResult;
+use candle_core::Tensor;
+
+/// Per layer `(key, value)` tensors of shape `(batch, num_kv_heads, seq_len, head_dim)`.
+pub type LayerKeyValues = Option<(Tensor, Tensor)>;
+
+#[derive(Debug, Clone)]
+pub struct KeyValueCache {
+    pub layers: Vec<LayerKeyValues>,
+}
+
+impl KeyValueCache {
+    pub fn new(layers: Vec<LayerKeyValues>) -> Self {
+        Self { layers }
+    }
+
+    pub fn into_layers(self) -> Vec<LayerKeyValues> {
+        self.layers
+    }
+}
+
+impl KeyValueCache {
+    /// Number of positions already held in the cache, used as the `seqlen_offset` of the next step.
+    pub fn sequence_length(&self) -> ModelResult<usize> {
+        match self.layers.iter().flatten().next() {
+            Some((key, _value)) => Ok(key.dim(2)?),
+            None => Ok(0),
+        }
+    }
+
+    /// Keeps the rows `indexes` of every layer. The first step of a model may cache transposed
+    /// values, which `index_select` can not read, so they are made contiguous first.
+    pub fn index_select(&self, indexes: &Tensor, dim: usize) -> ModelResult<Self> {
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for layer in self.layers.iter() {
+            let layer = match layer {
+                Some((key, value)) => Some((
+                    key.contiguous()?.index_select(indexes, dim)?,
+                    value.contiguous()?.index_select(indexes, dim)?,
+                )),
+                None => None,
+            };
+            layers.push(layer);
+        }
+        Ok(Self { layers })
+    }
+
+    /// Left pads every layer with zeroed key/values up to `sequence_length` positions.
+    pub fn left_pad(&self, sequence_length: usize) -> ModelResult<Self> {
+        let mut layers = Vec::with_capacity(self.layers.len());
+        for layer in self.layers.iter() {
+            let layer = match layer {
+                Some((key, value)) => Some((
+                    crate::utils::left_pad_cache(key, sequence_length)?,
+                    crate::utils::left_pad_cache(value, sequence_length)?,
+                )),
+                None => None,
+            };
+            layers.push(layer);
+        }
+        Ok(Self { layers })
+    }
+
+    /// Concatenates caches along the batch dimension, they must hold the same number of positions.
+    pub fn concatenate(caches: &[KeyValueCache]) -> ModelResult<Self> {
+        let number_of_layers = caches
+            .iter()
+            .map(|cache| cache.layers.len())
+            .max()
+            .unwrap_or_default();
+        let mut layers = Vec::with_capacity(number_of_layers);
+        for layer_index in 0..number_of_layers {
+            let mut keys = Vec::with_capacity(caches.len());
+            let mut values = Vec::with_capacity(caches.len());
+            for cache in caches.iter() {
+                if let Some(Some((key, value))) = cache.layers.get(layer_index) {
+                    keys.push(key);
+                    values.push(value);
+                }
+            }
+            let layer = if keys.is_empty() {
+                None
+            } else {
+                Some((Tensor::cat(&keys, 0)?, Tensor::cat(&values, 0)?))
+            };
+            layers.push(layer);
+        }
+        Ok(Self { layers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    /// Two layers, the second one empty. The first holds `batch_size` rows of one head and
+    /// `sequence_length` positions of size 2, increasing keys from `start` and their negation as
+    /// values.
+    fn cache(batch_size: usize, sequence_length: usize, start: f32) -> ModelResult<KeyValueCache> {
+        let shape = (batch_size, 1, sequence_length, 2);
+        let key = Tensor::arange(
+            start,
+            start + (batch_size * sequence_length * 2) as f32,
+            &Device::Cpu,
+        )?
+        .reshape(shape)?;
+        let value = (key.clone() * -1.)?;
+        Ok(KeyValueCache::new(vec![Some((key, value)), None]))
+    }
+
+    fn layer(cache: &KeyValueCache) -> ModelResult<(Vec<f32>, Vec<f32>)> {
+        let (key, value) = cache.layers[0].as_ref().unwrap();
+        Ok((
+            key.flatten_all()?.to_vec1()?,
+            value.flatten_all()?.to_vec1()?,
+        ))
+    }
+
+    #[test]
+    fn left_pad_prepends_zeroed_positions() -> ModelResult<()> {
+        let padded = cache(1, 2, 1.)?.left_pad(4)?;
+        assert_eq!(padded.sequence_length()?, 4);
+        assert!(padded.layers[1].is_none());
+        let (key, value) = layer(&padded)?;
+        assert_eq!(key, vec![0., 0., 0., 0., 1., 2., 3., 4.]);
+        assert_eq!(value, vec![0., 0., 0., 0., -1., -2., -3., -4.]);
+
+        // Caches already long enough are kept as is.
+        assert_eq!(layer(&padded.left_pad(3)?)?, layer(&padded)?);
+        Ok(())
+    }
+
+    #[test]
+    fn concatenate_appends_the_rows() -> ModelResult<()> {
+        let concatenated = KeyValueCache::concatenate(&[cache(1, 2, 0.)?, cache(2, 2, 4.)?])?;
+        let (key, value) = concatenated.layers[0].as_ref().unwrap();
+        assert_eq!(key.dims(), &[3, 1, 2, 2]);
+        assert_eq!(
+            key.flatten_all()?.to_vec1::<f32>()?,
+            (0..12).map(|value| value as f32).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            value.flatten_all()?.to_vec1::<f32>()?,
+            (0..12).map(|value| -(value as f32)).collect::<Vec<_>>()
+        );
+        assert!(concatenated.layers[1].is_none());
+        Ok(())
+    }
+}
@@ -1,12 +1,33 @@
+mod adapter;
 mod error;
+mod gguf;
+mod key_value_cache;
 mod model;
 mod model_config;
 mod model_files;
-mod model_type;
+mod model_meta_data;
+#[allow(clippy::module_inception)]
 mod models;
+mod quantization;
+mod registry;
+#[cfg(any(test, feature = "test-support"))]
+mod scripted_model;
+#[cfg(any(test, feature = "test-support"))]
+mod tiny_mistral;
 
-pub use self::model::Model;
+pub use self::adapter::{LoraAdapter, LoraConfig};
+pub use self::gguf::{select_gguf_variant, GgufFile, DEFAULT_QUANT_VARIANTS};
+pub use self::key_value_cache::{KeyValueCache, LayerKeyValues};
+pub use self::model::{check_dtype, Model};
 pub use self::model_config::*;
 pub use self::model_files::ModelFiles;
+pub use self::model_meta_data::ModelMetaData;
+pub use self::quantization::{Quantization, QuantizedWeights};
+pub use self::registry::{
+    ArchitectureEntry, ArchitectureLoader, LoadOptions, LoadedArchitecture, ARCHITECTURES,
+};
+#[cfg(any(test, feature = "test-support"))]
+pub use self::scripted_model::ScriptedModel;
+#[cfg(any(test, feature = "test-support"))]
+pub use self::tiny_mistral::TinyMistral;
 pub use error::*;
-pub use model_type::ModelType;
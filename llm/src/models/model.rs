@@ -1,90 +1,292 @@
-use crate::{ModelConfig, ModelFiles, ModelResult, TokenizedBatch};
+use super::{ArchitectureEntry, LoadOptions, LoadedArchitecture};
+use crate::{
+    Architecture, GgufFile, KeyValueCache, LoraAdapter, ModelBackend, ModelConfig, ModelError,
+    ModelFiles, ModelMetaData, ModelResult, TokenizedBatch,
+};
 use candle_core::Tensor;
-use candle_nn::VarBuilder;
 use hf_hub::api::sync::ApiRepo;
 
-#[derive(Debug)]
-enum InnerModel {
-    Mistral(super::models::mistral::Model),
-    QuantizedMistral(super::models::quantized_mistral::Model),
-}
-
 #[derive(Debug)]
 pub struct Model {
     config: ModelConfig,
-    inner: InnerModel,
+    architecture: &'static str,
+    network: Box<dyn Architecture>,
     device: candle_core::Device,
+    dtype: candle_core::DType,
+    vocab_size: usize,
+    context_length: usize,
+    eos_token_ids: Vec<u32>,
+    /// Names of the loaded LoRA adapters, in load order.
+    adapters: Vec<String>,
 }
 
-impl Model {
-    pub fn forward(&mut self, batch: &TokenizedBatch) -> ModelResult<Tensor> {
-        match &mut self.inner {
-            InnerModel::Mistral(model) => Ok(model
-                .forward_with_attention(&batch.input_ids, &batch.attention_mask, 0)
-                .expect("Error in model inner forward.")),
-            InnerModel::QuantizedMistral(model) => Ok(model
-                .forward_with_attention(&batch.input_ids, &batch.attention_mask, 0)
-                .expect("Error in model inner forward.")),
+impl ModelBackend for Model {
+    fn forward(&mut self, batch: &mut TokenizedBatch) -> ModelResult<Tensor> {
+        let (seqlen_offset, cache) = match batch.past_key_values.take() {
+            Some(cache) => (cache.sequence_length()?, cache.into_layers()),
+            None => (0, Vec::new()),
+        };
+        if !self.adapters.is_empty() {
+            // Rows are laid out request after request, one per beam.
+            let rows: Vec<Option<usize>> = batch
+                .requests
+                .values()
+                .flat_map(|request| {
+                    let adapter = request
+                        .adapter
+                        .as_ref()
+                        .and_then(|adapter| self.adapters.iter().position(|name| name == adapter));
+                    std::iter::repeat_n(adapter, request.number_of_rows())
+                })
+                .collect();
+            self.network.set_adapters(&rows)?;
+        }
+        self.network.set_kv_cache(cache);
+        let logits = self.network.forward_with_attention(
+            &batch.input_ids,
+            &batch.attention_mask,
+            seqlen_offset,
+        );
+        batch.past_key_values = Some(KeyValueCache::new(self.network.take_kv_cache()));
+        Ok(logits?)
+    }
+
+    fn meta_data(&self) -> ModelMetaData {
+        let device = device_name(&self.device);
+        let dtype = match (self.config.is_gguf(), self.config.load_quantization) {
+            (true, _) => "gguf".to_owned(),
+            (false, Some(quantization)) => quantization.to_string(),
+            (false, None) => format!("{:?}", self.dtype),
+        };
+        ModelMetaData {
+            device,
+            dtype,
+            model_type: format!("{} ({})", self.config.model_id, self.architecture),
+            number_of_devices: 1,
         }
     }
+
+    /// The keys are re-rotated so they hold the position they are moved to.
+    fn shift_key_value_cache(
+        &self,
+        cache: KeyValueCache,
+        positions: usize,
+    ) -> ModelResult<KeyValueCache> {
+        if positions == 0 {
+            return Ok(cache);
+        }
+        let sequence_length = cache.sequence_length()? + positions;
+        let layers = self
+            .network
+            .shift_kv_cache(cache.into_layers(), positions)?;
+        KeyValueCache::new(layers).left_pad(sequence_length)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    fn context_length(&self) -> usize {
+        self.context_length
+    }
+
+    fn eos_token_ids(&self) -> Vec<u32> {
+        self.eos_token_ids.clone()
+    }
+
+    fn adapters(&self) -> Vec<String> {
+        self.adapters.clone()
+    }
+
+    fn device(&self) -> candle_core::Device {
+        self.device.clone()
+    }
 }
 
 impl Model {
+    /// Loads the model from its local directory when there is one, without any network access,
+    /// otherwise from the hub.
     pub fn load(config: ModelConfig) -> ModelResult<Self> {
         tracing::debug!("loading model_id: {:?}", config.model_id);
-        let repo = config.api_repo()?;
-        Self::from_repo(config, &repo)
+        let files = ModelFiles::from_model_config(&config)?;
+        Self::from_files(config, files)
     }
 
+    /// Builds the model with the architecture its `config.json` declares, or its GGUF file when
+    /// quantized.
     pub fn from_files(config: ModelConfig, files: ModelFiles) -> ModelResult<Self> {
-        let device = Model::init_device()?;
-        let dtype = Model::init_dtype()?;
-        let inner = match config.quantize {
-            true => {
-                let config = files.load_config()?;
-                // let generation_config = files.load_generation_config();
-                tracing::debug!("Model config: {:?}", &config);
-                let gguf_file = files
-                    .quantized_weights
-                    .first()
-                    .expect("Cannot load quantized weights. None were found.");
-                let vars = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(
-                    gguf_file, &device,
-                )?;
-                let model = super::models::quantized_mistral::Model::new(&config, vars)?;
-                InnerModel::QuantizedMistral(model)
-            }
-            _ => {
-                let config = files.load_config()?;
-                tracing::debug!("Model config: {:?}", &config);
-                let vars =
-                    unsafe { VarBuilder::from_mmaped_safetensors(&files.weights, dtype, &device)? };
-                let model = super::models::mistral::Model::new(&config, vars)?;
-                InnerModel::Mistral(model)
-            }
+        let device = config.device.candle_device()?;
+        let quantized = config.is_gguf() || config.load_quantization.is_some();
+        let dtype = match config.dtype {
+            Some(dtype) => dtype,
+            None if quantized => candle_core::DType::F32,
+            None => Model::init_dtype(&device),
         };
-        Ok(Self {
-            inner,
+        if quantized && dtype != candle_core::DType::F32 {
+            return Err(ModelError::UnsupportedDType {
+                dtype,
+                device: device_name(&device),
+                reason: "quantized models run in F32".to_owned(),
+            });
+        }
+        check_dtype(&device, dtype)?;
+        tracing::debug!("loading model on {} in {:?}", device_name(&device), dtype);
+        let options = LoadOptions {
             device,
+            dtype,
+            quantize: config.is_gguf(),
+            quantization: config.load_quantization,
+        };
+        let entry = if options.quantize {
+            ArchitectureEntry::detect_gguf(GgufFile::open(files.gguf_file()?)?.architecture()?)?
+        } else {
+            ArchitectureEntry::detect(&files.load_config::<serde_json::Value>()?)?
+        };
+        tracing::debug!("loading {} architecture", entry.name);
+        let LoadedArchitecture {
+            mut network,
+            vocab_size,
+            context_length,
+        } = (entry.load)(&files, &options)?;
+        let mut adapters = Vec::with_capacity(config.adapters.len());
+        for adapter in config.adapters.iter() {
+            let adapter = LoraAdapter::from_dir(
+                &adapter.name,
+                &adapter.path,
+                &options.device,
+                options.dtype,
+            )?;
+            network.load_adapter(&adapter)?;
+            adapters.push(adapter.name);
+        }
+        let eos_token_ids = files.eos_token_ids()?;
+        tracing::debug!("eos_token_ids: {:?}", &eos_token_ids);
+        Ok(Self {
             config,
+            architecture: entry.name,
+            network,
+            device: options.device,
+            dtype: options.dtype,
+            vocab_size,
+            context_length,
+            eos_token_ids,
+            adapters,
         })
     }
 
     pub fn from_repo(config: ModelConfig, repo: &ApiRepo) -> ModelResult<Self> {
-        let tokenizer_files = ModelFiles::from_repo(config.model_id, repo)?;
-        Self::from_files(config, tokenizer_files)
-    }
-
-    pub fn init_device() -> ModelResult<candle_core::Device> {
-        Ok(candle_examples::device(false)?)
+        let model_files =
+            ModelFiles::from_repo(repo, config.quantize, config.quant_variant.as_deref())?;
+        Self::from_files(config, model_files)
     }
 
-    pub fn init_dtype() -> ModelResult<candle_core::DType> {
-        let device = Model::init_device()?;
+    pub fn init_dtype(device: &candle_core::Device) -> candle_core::DType {
         if device.is_cuda() {
-            Ok(candle_core::DType::BF16)
+            candle_core::DType::BF16
         } else {
-            Ok(candle_core::DType::F32)
+            candle_core::DType::F32
         }
     }
 }
+
+/// `cpu`, `cuda:N` or `metal:N`.
+fn device_name(device: &candle_core::Device) -> String {
+    match device.location() {
+        candle_core::DeviceLocation::Cpu => "cpu".to_owned(),
+        candle_core::DeviceLocation::Cuda { gpu_id } => format!("cuda:{gpu_id}"),
+        candle_core::DeviceLocation::Metal { gpu_id } => format!("metal:{gpu_id}"),
+    }
+}
+
+/// Fails when `device` can not run a model in `dtype`, BF16 on CUDA GPUs older than Ampere for
+/// instance. Runs a small matmul and softmax, the kernels every architecture relies on.
+pub fn check_dtype(device: &candle_core::Device, dtype: candle_core::DType) -> ModelResult<()> {
+    let unsupported = |reason: String| ModelError::UnsupportedDType {
+        dtype,
+        device: device_name(device),
+        reason,
+    };
+    if !dtype.is_float() {
+        return Err(unsupported("the weights need a float dtype".to_owned()));
+    }
+    let probe = || -> candle_core::Result<Tensor> {
+        let xs = Tensor::ones((2, 2), dtype, device)?;
+        candle_nn::ops::softmax_last_dim(&xs.matmul(&xs)?)
+    };
+    probe()
+        .map(|_| ())
+        .map_err(|error| unsupported(error.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device};
+
+    #[test]
+    fn checks_dtypes_on_the_cpu() {
+        for dtype in [DType::F32, DType::F16] {
+            assert!(check_dtype(&Device::Cpu, dtype).is_ok(), "{dtype:?}");
+        }
+        // The CPU backend has no BF16 matmul.
+        for dtype in [DType::BF16, DType::U32] {
+            assert!(matches!(
+                check_dtype(&Device::Cpu, dtype),
+                Err(ModelError::UnsupportedDType { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn shifted_cache_matches_the_left_padded_prompt() -> crate::Result<()> {
+        let fixture = crate::TinyMistral::new()?;
+        let files = ModelFiles::from_dir(&fixture.directory, None)?;
+        let mut model = Model::from_files(fixture.model_config(), files)?;
+        let mut run = |input_ids: &[u32], attention_mask: &[f32]| -> crate::Result<KeyValueCache> {
+            let mut batch = TokenizedBatch {
+                requests: Default::default(),
+                token_ids: vec![input_ids.to_vec()],
+                input_ids: Tensor::new(vec![input_ids], &Device::Cpu)?,
+                attention_mask: Tensor::new(vec![attention_mask], &Device::Cpu)?,
+                past_key_values: None,
+                pad_id: 0,
+            };
+            model.forward(&mut batch)?;
+            Ok(batch.past_key_values.unwrap())
+        };
+        let cache = run(&[1, 5, 6], &[0.; 3])?;
+        let padded = run(
+            &[0, 0, 1, 5, 6],
+            &[f32::NEG_INFINITY, f32::NEG_INFINITY, 0., 0., 0.],
+        )?;
+
+        let shifted = model.shift_key_value_cache(cache, 2)?;
+        assert_eq!(shifted.sequence_length()?, 5);
+        for (shifted, padded) in shifted.layers.iter().zip(padded.layers.iter()) {
+            let (shifted_key, shifted_value) = shifted.as_ref().unwrap();
+            let (padded_key, padded_value) = padded.as_ref().unwrap();
+            for (shifted, padded) in [(shifted_key, padded_key), (shifted_value, padded_value)] {
+                let zeros = shifted.narrow(2, 0, 2)?.abs()?.flatten_all()?.max(0)?;
+                assert_eq!(zeros.to_scalar::<f32>()?, 0.);
+                let difference = (shifted.narrow(2, 2, 3)? - padded.narrow(2, 2, 3)?)?
+                    .abs()?
+                    .flatten_all()?
+                    .max(0)?
+                    .to_scalar::<f32>()?;
+                assert!(difference < 1e-4, "{difference}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parses_float_dtypes_only() {
+        assert_eq!(crate::str_to_dtype("bf16").unwrap(), DType::BF16);
+        assert_eq!(crate::str_to_dtype("F32").unwrap(), DType::F32);
+        assert!(matches!(
+            crate::str_to_dtype("fp32"),
+            Err(ModelError::InvalidDType(_))
+        ));
+        assert!(crate::str_to_dtype("U8").is_err());
+    }
+}
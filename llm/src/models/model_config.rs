@@ -2,18 +2,80 @@ use super::ModelResult;
 
 #[derive(Debug, Clone)]
 pub struct ModelConfig {
-    pub model_id: super::ModelType,
-    pub dtype: candle_core::DType,
+    /// Hub repo id of the model, or the path of a local directory holding its files.
+    pub model_id: String,
+    /// Device the model runs on, the tokenized batches are created on it too.
+    pub device: crate::DeviceConfig,
+    /// Dtype of the weights, the attention masks and the key/value cache. `None` picks BF16 on
+    /// CUDA and F32 otherwise, quantized models always run in F32.
+    pub dtype: Option<candle_core::DType>,
+    /// Load the quantized weights of a GGUF file instead of the safetensors ones.
     pub quantize: bool,
+    /// Quantization of the GGUF file to load when there are several, `Q4_K_M`, `Q8_0`, ...
+    pub quant_variant: Option<String>,
+    /// Quantize the linear layers of safetensors weights while loading them.
+    pub load_quantization: Option<super::Quantization>,
+    /// Local directory holding the model and tokenizer files, or a single GGUF file. When set
+    /// nothing is downloaded.
+    pub model_path: Option<std::path::PathBuf>,
+    /// LoRA adapters loaded over the base weights, requests select them by name.
+    pub adapters: Vec<AdapterConfig>,
+}
+
+/// A PEFT LoRA adapter directory, holding `adapter_config.json` and `adapter_model.safetensors`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdapterConfig {
+    pub name: String,
+    pub path: std::path::PathBuf,
+}
+
+/// Parses `name=path`, or a bare path named after its last component.
+impl std::str::FromStr for AdapterConfig {
+    type Err = super::ModelError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, path) = match value.split_once('=') {
+            Some((name, path)) => (name.to_owned(), std::path::PathBuf::from(path)),
+            None => {
+                let path = std::path::PathBuf::from(value);
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                (name, path)
+            }
+        };
+        if name.is_empty() {
+            return Err(super::ModelError::InvalidAdapter {
+                name: value.to_owned(),
+                reason: "expected name=path".to_owned(),
+            });
+        }
+        Ok(Self { name, path })
+    }
 }
 
 impl ModelConfig {
+    /// Directory or GGUF file the model is loaded from without network access: `model_path` when
+    /// it is set, otherwise `model_id` when it names an existing directory or file.
+    pub fn local_path(&self) -> Option<std::path::PathBuf> {
+        match &self.model_path {
+            Some(model_path) => Some(model_path.clone()),
+            None => Some(std::path::PathBuf::from(&self.model_id)).filter(|path| path.exists()),
+        }
+    }
+
+    /// Whether the model is read from GGUF files, either asked for or given a GGUF file.
+    pub fn is_gguf(&self) -> bool {
+        self.quantize || self.local_path().is_some_and(|path| path.is_file())
+    }
+
     pub fn api_repo(&self) -> ModelResult<hf_hub::api::sync::ApiRepo> {
         let api = hf_hub::api::sync::ApiBuilder::new()
             .with_cache_dir("./.cache/huggingface".into())
-            .with_token(Some(std::env!("HUGGING_FACE_TOKEN").to_owned()))
+            .with_token(std::env::var("HUGGING_FACE_TOKEN").ok())
             .build()?;
-        let model_id = self.model_id.path();
+        let model_id = self.model_id.clone();
         tracing::debug!("loading model_id: {model_id}");
         Ok(api.repo(hf_hub::Repo::with_revision(
             model_id,
@@ -23,20 +85,12 @@ impl ModelConfig {
     }
 }
 
-pub fn str_to_dtype(value: &str) -> candle_core::DType {
-    match value {
-        "BF16" => candle_core::DType::BF16,
-        "F32" => candle_core::DType::F32,
-        "F16" => candle_core::DType::F16,
-        "I64" => candle_core::DType::I64,
-        "U32" => candle_core::DType::U32,
-        "U8" => candle_core::DType::U8,
-        "bf16" => candle_core::DType::BF16,
-        "f32" => candle_core::DType::F32,
-        "f16" => candle_core::DType::F16,
-        "i64" => candle_core::DType::I64,
-        "u32" => candle_core::DType::U32,
-        "u8" => candle_core::DType::U8,
-        _ => candle_core::DType::BF16,
+/// Parses the dtype a model runs in, `BF16`, `F16` or `F32` in any case.
+pub fn str_to_dtype(value: &str) -> ModelResult<candle_core::DType> {
+    match value.to_uppercase().as_str() {
+        "BF16" => Ok(candle_core::DType::BF16),
+        "F16" => Ok(candle_core::DType::F16),
+        "F32" => Ok(candle_core::DType::F32),
+        _ => Err(super::ModelError::InvalidDType(value.to_owned())),
     }
 }
@@ -1,50 +1,144 @@
-use super::ModelType;
-use crate::ModelResult;
+use super::{select_gguf_variant, LoadOptions};
+use crate::{ModelConfig, ModelError, ModelResult};
 use candle_examples::hub_load_safetensors;
+use candle_nn::VarBuilder;
 use hf_hub::api::sync::ApiRepo;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone)]
 pub struct ModelFiles {
-    pub model_type: ModelType,
-    pub config: PathBuf,
+    /// `config.json`, GGUF repos and files come without one.
+    pub config: Option<PathBuf>,
     pub weights: Vec<PathBuf>,
     pub quantized_weights: Vec<PathBuf>,
     pub generation_config: Option<PathBuf>,
 }
 
 impl ModelFiles {
-    pub fn from_repo(model_type: ModelType, repo: &ApiRepo) -> ModelResult<Self> {
-        let config = repo.get("config.json")?;
-        let generation_config = match repo.get("generation_config.json") {
-            Ok(config_path) => Some(config_path),
-            Err(_) => None,
-        };
+    /// Finds the files of the model described by `config`: a local GGUF file, a local directory
+    /// or a hub repo.
+    pub fn from_model_config(config: &ModelConfig) -> ModelResult<Self> {
+        let quant_variant = config.quant_variant.as_deref();
+        match config.local_path() {
+            Some(path) if path.is_file() => Ok(Self::from_gguf_file(&path)),
+            Some(path) => Self::from_dir(&path, quant_variant),
+            None => Self::from_repo(&config.api_repo()?, config.quantize, quant_variant),
+        }
+    }
+
+    /// Downloads the config and the weights of a hub repo. When `quantize` is set only the GGUF
+    /// file of `quant_variant` is downloaded, otherwise the safetensors shards.
+    pub fn from_repo(
+        repo: &ApiRepo,
+        quantize: bool,
+        quant_variant: Option<&str>,
+    ) -> ModelResult<Self> {
+        let generation_config = repo.get("generation_config.json").ok();
         let mut weights = Vec::new();
         let mut quantized_weights = Vec::new();
-        let repo_info = repo.info()?;
-        if model_type.is_quantizable() {
-            quantized_weights = repo_info
+        let config = if quantize {
+            let gguf_files: Vec<String> = repo
+                .info()?
                 .siblings
                 .into_iter()
-                .filter_map(|sibling| {
-                    let filename = sibling.rfilename.clone();
-                    tracing::debug!("sibling filename: {:?}", &filename);
-                    if filename.ends_with(".gguf") {
-                        return repo.get(&filename).ok();
-                    }
-                    None
-                })
+                .map(|sibling| sibling.rfilename)
+                .filter(|filename| filename.ends_with(".gguf"))
                 .collect();
-            if quantized_weights.is_empty() {
-                tracing::debug!("attempting to get model-q4k.gguf");
-                quantized_weights = vec![repo.get("model-q4k.gguf")?]
-            }
+            tracing::debug!("gguf files: {:?}", &gguf_files);
+            let gguf_file = select_gguf_variant(&gguf_files, quant_variant)?;
+            tracing::debug!("downloading {:?}", gguf_file);
+            quantized_weights.push(repo.get(gguf_file)?);
+            repo.get("config.json").ok()
         } else {
-            weights = hub_load_safetensors(repo, "model.safetensors.index.json")?;
+            weights = match repo.get("model.safetensors.index.json") {
+                Ok(_) => hub_load_safetensors(repo, "model.safetensors.index.json")?,
+                // Small models ship their weights in a single file, without an index.
+                Err(_) => vec![repo.get("model.safetensors")?],
+            };
+            Some(repo.get("config.json")?)
+        };
+        Ok(Self {
+            config,
+            weights,
+            quantized_weights,
+            generation_config,
+        })
+    }
+
+    /// A single GGUF file, holding the hyperparameters, tokenizer and weights of the model.
+    pub fn from_gguf_file(path: &Path) -> Self {
+        Self {
+            config: None,
+            weights: Vec::new(),
+            quantized_weights: vec![path.to_path_buf()],
+            generation_config: None,
+        }
+    }
+
+    /// Finds the model files in a local directory laid out like a hub repo, without any network
+    /// access. Safetensors weights are read from `model.safetensors.index.json` when there is one,
+    /// otherwise every `*.safetensors` file of the directory is used. Of the `*.gguf` files only
+    /// the one of `quant_variant` is kept.
+    pub fn from_dir(directory: &Path, quant_variant: Option<&str>) -> ModelResult<Self> {
+        let missing = |file: &str| ModelError::MissingFile {
+            directory: directory.to_path_buf(),
+            file: file.to_owned(),
+        };
+        let config = Some(directory.join("config.json")).filter(|path| path.is_file());
+        let generation_config =
+            Some(directory.join("generation_config.json")).filter(|path| path.is_file());
+
+        let mut weights = Vec::new();
+        let mut quantized_weights = Vec::new();
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("safetensors") => weights.push(path),
+                Some("gguf") => quantized_weights.push(path),
+                _ => (),
+            }
+        }
+        weights.sort();
+        quantized_weights.sort();
+        if !quantized_weights.is_empty() {
+            let file_names: Vec<String> = quantized_weights
+                .iter()
+                .filter_map(|path| path.file_name())
+                .map(|file_name| file_name.to_string_lossy().into_owned())
+                .collect();
+            let selected = select_gguf_variant(&file_names, quant_variant)?;
+            quantized_weights = vec![directory.join(selected)];
+        }
+
+        let index = directory.join("model.safetensors.index.json");
+        if index.is_file() {
+            let index: serde_json::Value = ModelFiles::load_file(index)?;
+            let weight_map = index
+                .get("weight_map")
+                .and_then(|weight_map| weight_map.as_object())
+                .ok_or_else(|| missing("weight_map in model.safetensors.index.json"))?;
+            let mut shards: Vec<&str> = weight_map
+                .values()
+                .filter_map(|shard| shard.as_str())
+                .collect();
+            shards.sort_unstable();
+            shards.dedup();
+            weights = Vec::with_capacity(shards.len());
+            for shard in shards.into_iter() {
+                let path = directory.join(shard);
+                if !path.is_file() {
+                    return Err(missing(shard));
+                }
+                weights.push(path);
+            }
+        }
+        if weights.is_empty() && quantized_weights.is_empty() {
+            return Err(missing("*.safetensors or *.gguf weights"));
+        }
+        if config.is_none() && quantized_weights.is_empty() {
+            return Err(missing("config.json"));
         }
         Ok(Self {
-            model_type,
             config,
             weights,
             quantized_weights,
@@ -52,6 +146,45 @@ impl ModelFiles {
         })
     }
 
+    /// Directory holding the model files.
+    pub fn directory(&self) -> PathBuf {
+        self.config
+            .iter()
+            .chain(self.weights.iter())
+            .chain(self.quantized_weights.iter())
+            .next()
+            .and_then(|path| path.parent())
+            .map(|directory| directory.to_path_buf())
+            .unwrap_or_default()
+    }
+
+    /// The first `*.gguf` file of the model.
+    pub fn gguf_file(&self) -> ModelResult<&std::path::Path> {
+        self.quantized_weights
+            .first()
+            .map(|path| path.as_path())
+            .ok_or_else(|| ModelError::MissingFile {
+                directory: self.directory(),
+                file: "*.gguf quantized weights".to_owned(),
+            })
+    }
+
+    /// Memory maps the safetensors weights of the model.
+    pub fn safetensors_var_builder(
+        &self,
+        options: &LoadOptions,
+    ) -> ModelResult<VarBuilder<'static>> {
+        if self.weights.is_empty() {
+            return Err(ModelError::MissingFile {
+                directory: self.directory(),
+                file: "*.safetensors weights".to_owned(),
+            });
+        }
+        Ok(unsafe {
+            VarBuilder::from_mmaped_safetensors(&self.weights, options.dtype, &options.device)?
+        })
+    }
+
     pub fn load_file<T>(file_path: std::path::PathBuf) -> ModelResult<T>
     where
         T: for<'a> serde::Deserialize<'a>,
@@ -65,7 +198,13 @@ impl ModelFiles {
     where
         T: for<'a> serde::Deserialize<'a>,
     {
-        ModelFiles::load_file(self.config.clone())
+        match self.config.clone() {
+            Some(config_path) => ModelFiles::load_file(config_path),
+            None => Err(ModelError::MissingFile {
+                directory: self.directory(),
+                file: "config.json".to_owned(),
+            }),
+        }
     }
 
     pub fn load_generation_config<T>(&self) -> ModelResult<Option<T>>
@@ -78,4 +217,28 @@ impl ModelFiles {
             Ok(None)
         }
     }
+
+    /// The `eos_token_id` of `config.json` and `generation_config.json`, either a single id or a
+    /// list of them like in Llama 3.
+    pub fn eos_token_ids(&self) -> ModelResult<Vec<u32>> {
+        let mut configs = Vec::with_capacity(2);
+        if self.config.is_some() {
+            configs.push(self.load_config::<serde_json::Value>()?);
+        }
+        configs.extend(self.load_generation_config::<serde_json::Value>()?);
+        let mut eos_token_ids = Vec::new();
+        for config in configs.iter() {
+            let ids = match config.get("eos_token_id") {
+                Some(serde_json::Value::Array(ids)) => ids.iter().collect(),
+                Some(id) => vec![id],
+                None => Vec::new(),
+            };
+            for id in ids.into_iter().filter_map(|id| id.as_u64()) {
+                if !eos_token_ids.contains(&(id as u32)) {
+                    eos_token_ids.push(id as u32);
+                }
+            }
+        }
+        Ok(eos_token_ids)
+    }
 }
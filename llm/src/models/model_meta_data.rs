@@ -0,0 +1,8 @@
+/// Description of the loaded model reported with every finished request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelMetaData {
+    pub device: String,
+    pub dtype: String,
+    pub model_type: String,
+    pub number_of_devices: usize,
+}
@@ -0,0 +1,75 @@
+/// Linear layers with LoRA adapters, https://arxiv.org/abs/2106.09685
+use crate::LoraAdapter;
+use candle_core::{Module, Result, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::with_tracing::{linear_no_bias, Linear};
+
+#[derive(Debug, Clone)]
+struct LoraWeights {
+    /// Transposed `lora_A`, `(in, r)`.
+    a: Tensor,
+    /// Transposed `lora_B`, `(r, out)`.
+    b: Tensor,
+    scale: f64,
+}
+
+/// A linear layer with any number of LoRA adapters over its shared base weights. Every batch row
+/// goes through the base weights plus the update of the adapter selected for it, if any.
+#[derive(Debug, Clone)]
+pub struct LoraLinear {
+    base: Linear,
+    /// Name of the layer in the base model, `model.layers.0.self_attn.q_proj`.
+    prefix: String,
+    /// Indexed by adapter in load order, `None` for the adapters that do not target the layer.
+    adapters: Vec<Option<LoraWeights>>,
+    /// Adapter index and the batch rows using it, for the current batch.
+    rows: Vec<(usize, Tensor)>,
+}
+
+impl LoraLinear {
+    /// Appends the weights `adapter` has for this layer, returns whether it targets the layer.
+    pub fn load_adapter(&mut self, adapter: &LoraAdapter) -> Result<bool> {
+        let weights = match adapter.weights(&self.prefix) {
+            Some((a, b)) => Some(LoraWeights {
+                a: a.t()?.contiguous()?,
+                b: b.t()?.contiguous()?,
+                scale: adapter.scale(),
+            }),
+            None => None,
+        };
+        let targeted = weights.is_some();
+        self.adapters.push(weights);
+        Ok(targeted)
+    }
+
+    /// Selects the rows of the next batches going through each adapter, as `u32` row indices.
+    pub fn set_rows(&mut self, rows: &[(usize, Tensor)]) {
+        self.rows = rows.to_vec();
+    }
+}
+
+impl Module for LoraLinear {
+    /// The low rank update of an adapter only runs over the rows using it.
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut ys = self.base.forward(xs)?;
+        for (adapter, rows) in self.rows.iter() {
+            if let Some(Some(lora)) = self.adapters.get(*adapter) {
+                let update = xs
+                    .index_select(rows, 0)?
+                    .broadcast_matmul(&lora.a)?
+                    .broadcast_matmul(&lora.b)?;
+                ys = ys.index_add(rows, &(update * lora.scale)?, 0)?;
+            }
+        }
+        Ok(ys)
+    }
+}
+
+pub fn lora_linear_no_bias(d1: usize, d2: usize, vb: VarBuilder) -> Result<LoraLinear> {
+    Ok(LoraLinear {
+        base: linear_no_bias(d1, d2, vb.clone())?,
+        prefix: vb.prefix(),
+        adapters: Vec::new(),
+        rows: Vec::new(),
+    })
+}
@@ -1,13 +1,18 @@
 /// Mistral LLM, https://github.com/mistralai/mistral-src
-/// copied to add forward_with_attention method on the model
-use candle_core::{DType, Device, Module, Result, Tensor, D};
+/// copied to add forward_with_attention method on the model, also runs the Mixtral
+/// mixture-of-experts models, https://mistral.ai/news/mixtral-of-experts
+use candle_core::{DType, Device, Module, Result, Tensor};
 use candle_nn::{Activation, VarBuilder};
 use candle_transformers::models::{
     // stable_diffusion::attention,
     with_tracing::{linear_no_bias, Linear, RmsNorm},
 };
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
+use super::lora::{lora_linear_no_bias, LoraLinear};
+use crate::LoraAdapter;
+
 fn default_use_flash_attn() -> bool {
     false
 }
@@ -27,6 +32,10 @@ pub struct Config {
     pub sliding_window: Option<usize>,
     #[serde(default = "default_use_flash_attn")]
     pub use_flash_attn: bool,
+    /// Number of experts of each sparse mixture-of-experts layer, Mixtral only.
+    pub num_local_experts: Option<usize>,
+    /// Number of experts every token is routed to, Mixtral only.
+    pub num_experts_per_tok: Option<usize>,
 }
 
 impl Config {
@@ -45,6 +54,8 @@ impl Config {
             rope_theta: 10_000.,
             sliding_window: Some(4096),
             use_flash_attn,
+            num_local_experts: None,
+            num_experts_per_tok: None,
         }
     }
 
@@ -64,6 +75,8 @@ impl Config {
             rope_theta: 10_000.,
             sliding_window: Some(4096),
             use_flash_attn,
+            num_local_experts: None,
+            num_experts_per_tok: None,
         }
     }
 
@@ -82,10 +95,14 @@ impl Config {
             rope_theta: 10_000.,
             sliding_window: Some(4096),
             use_flash_attn,
+            num_local_experts: None,
+            num_experts_per_tok: None,
         }
     }
 }
 
+const PADDING_MASK_VALUE: f32 = -1e4;
+
 #[derive(Debug, Clone)]
 struct RotaryEmbedding {
     sin: Tensor,
@@ -126,14 +143,32 @@ impl RotaryEmbedding {
         let k_embed = candle_nn::rotary_emb::rope(k, &cos, &sin)?;
         Ok((q_embed, k_embed))
     }
+
+    /// Rotates already embedded keys further by `positions`, used to move a cached sequence to
+    /// the right when it gets left padded.
+    fn shift_key(&self, k: &Tensor, positions: usize) -> Result<Tensor> {
+        let (_b_sz, _h, seq_len, _n_embd) = k.dims4()?;
+        let (_max_seq_len, half_dim) = self.cos.dims2()?;
+        let cos = self
+            .cos
+            .narrow(0, positions, 1)?
+            .broadcast_as((seq_len, half_dim))?
+            .contiguous()?;
+        let sin = self
+            .sin
+            .narrow(0, positions, 1)?
+            .broadcast_as((seq_len, half_dim))?
+            .contiguous()?;
+        candle_nn::rotary_emb::rope(&k.contiguous()?, &cos, &sin)
+    }
 }
 
 #[derive(Debug, Clone)]
 #[allow(clippy::upper_case_acronyms)]
 struct MLP {
-    gate_proj: Linear,
-    up_proj: Linear,
-    down_proj: Linear,
+    gate_proj: LoraLinear,
+    up_proj: LoraLinear,
+    down_proj: LoraLinear,
     act_fn: Activation,
 }
 
@@ -141,9 +176,9 @@ impl MLP {
     fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let hidden_sz = cfg.hidden_size;
         let intermediate_sz = cfg.intermediate_size;
-        let gate_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("gate_proj"))?;
-        let up_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("up_proj"))?;
-        let down_proj = linear_no_bias(intermediate_sz, hidden_sz, vb.pp("down_proj"))?;
+        let gate_proj = lora_linear_no_bias(hidden_sz, intermediate_sz, vb.pp("gate_proj"))?;
+        let up_proj = lora_linear_no_bias(hidden_sz, intermediate_sz, vb.pp("up_proj"))?;
+        let down_proj = lora_linear_no_bias(intermediate_sz, hidden_sz, vb.pp("down_proj"))?;
         Ok(Self {
             gate_proj,
             up_proj,
@@ -153,6 +188,26 @@ impl MLP {
     }
 }
 
+impl MLP {
+    /// Mixtral expert, the same MLP with the `w1` gate, `w3` up and `w2` down projections.
+    fn new_expert(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let intermediate_sz = cfg.intermediate_size;
+        Ok(Self {
+            gate_proj: lora_linear_no_bias(hidden_sz, intermediate_sz, vb.pp("w1"))?,
+            up_proj: lora_linear_no_bias(hidden_sz, intermediate_sz, vb.pp("w3"))?,
+            down_proj: lora_linear_no_bias(intermediate_sz, hidden_sz, vb.pp("w2"))?,
+            act_fn: cfg.hidden_act,
+        })
+    }
+}
+
+impl MLP {
+    fn lora_projections(&mut self) -> Vec<&mut LoraLinear> {
+        vec![&mut self.gate_proj, &mut self.up_proj, &mut self.down_proj]
+    }
+}
+
 impl Module for MLP {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
@@ -161,6 +216,112 @@ impl Module for MLP {
     }
 }
 
+#[derive(Debug, Clone)]
+struct SparseMoeBlock {
+    gate: Linear,
+    experts: Vec<MLP>,
+    num_experts_per_tok: usize,
+}
+
+impl SparseMoeBlock {
+    fn new(cfg: &Config, num_experts: usize, vb: VarBuilder) -> Result<Self> {
+        let gate = linear_no_bias(cfg.hidden_size, num_experts, vb.pp("gate"))?;
+        let vb_e = vb.pp("experts");
+        let experts = (0..num_experts)
+            .map(|expert_idx| MLP::new_expert(cfg, vb_e.pp(expert_idx)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            gate,
+            experts,
+            num_experts_per_tok: cfg.num_experts_per_tok.unwrap_or(2),
+        })
+    }
+}
+
+impl Module for SparseMoeBlock {
+    /// Every token of the batch, whatever its row, is routed to its top experts. Each expert then
+    /// runs once over all the tokens routed to it instead of once per sequence.
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_size, seq_len, hidden_dim) = xs.dims3()?;
+        let xs = xs.reshape(((), hidden_dim))?;
+        let routing_weights = candle_nn::ops::softmax_last_dim(&xs.apply(&self.gate)?)?
+            .to_dtype(DType::F32)?
+            .to_vec2::<f32>()?;
+
+        // Token indexes and renormalized routing weights of every expert.
+        let mut expert_tokens = vec![Vec::new(); self.experts.len()];
+        let mut expert_weights = vec![Vec::new(); self.experts.len()];
+        for (token_idx, weights) in routing_weights.iter().enumerate() {
+            let mut experts: Vec<usize> = (0..weights.len()).collect();
+            experts.sort_by(|&i, &j| weights[j].total_cmp(&weights[i]));
+            experts.truncate(self.num_experts_per_tok);
+            let sum_weights: f32 = experts.iter().map(|&expert_idx| weights[expert_idx]).sum();
+            for expert_idx in experts {
+                expert_tokens[expert_idx].push(token_idx as u32);
+                expert_weights[expert_idx].push(weights[expert_idx] / sum_weights);
+            }
+        }
+
+        let mut ys = xs.zeros_like()?;
+        for (expert_idx, expert) in self.experts.iter().enumerate() {
+            let tokens = &expert_tokens[expert_idx];
+            if tokens.is_empty() {
+                continue;
+            }
+            let tokens = Tensor::new(tokens.as_slice(), xs.device())?;
+            let weights = Tensor::new(expert_weights[expert_idx].as_slice(), xs.device())?
+                .reshape(((), 1))?
+                .to_dtype(xs.dtype())?;
+            let expert_ys = xs
+                .index_select(&tokens, 0)?
+                .apply(expert)?
+                .broadcast_mul(&weights)?;
+            ys = ys.index_add(&tokens, &expert_ys, 0)?;
+        }
+        ys.reshape((b_size, seq_len, hidden_dim))
+    }
+}
+
+/// Dense MLP of the Mistral layers, or the sparse mixture of experts of the Mixtral ones.
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+enum FeedForward {
+    Dense(MLP),
+    Sparse(SparseMoeBlock),
+}
+
+impl FeedForward {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        match cfg.num_local_experts {
+            Some(num_experts) => Ok(Self::Sparse(SparseMoeBlock::new(
+                cfg,
+                num_experts,
+                vb.pp("block_sparse_moe"),
+            )?)),
+            None => Ok(Self::Dense(MLP::new(cfg, vb.pp("mlp"))?)),
+        }
+    }
+}
+
+impl FeedForward {
+    /// Experts see the tokens routed to them rather than batch rows, they keep their base weights.
+    fn lora_projections(&mut self) -> Vec<&mut LoraLinear> {
+        match self {
+            Self::Dense(mlp) => mlp.lora_projections(),
+            Self::Sparse(_) => Vec::new(),
+        }
+    }
+}
+
+impl Module for FeedForward {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Dense(mlp) => mlp.forward(xs),
+            Self::Sparse(moe) => moe.forward(xs),
+        }
+    }
+}
+
 #[cfg(feature = "flash-attn")]
 fn flash_attn(
     q: &Tensor,
@@ -179,10 +340,10 @@ fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Ten
 
 #[derive(Debug, Clone)]
 struct Attention {
-    q_proj: Linear,
-    k_proj: Linear,
-    v_proj: Linear,
-    o_proj: Linear,
+    q_proj: LoraLinear,
+    k_proj: LoraLinear,
+    v_proj: LoraLinear,
+    o_proj: LoraLinear,
     num_heads: usize,
     num_kv_heads: usize,
     num_kv_groups: usize,
@@ -200,10 +361,10 @@ impl Attention {
         let num_kv_heads = cfg.num_key_value_heads;
         let num_kv_groups = num_heads / num_kv_heads;
         let head_dim = hidden_sz / num_heads;
-        let q_proj = linear_no_bias(hidden_sz, num_heads * head_dim, vb.pp("q_proj"))?;
-        let k_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("k_proj"))?;
-        let v_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("v_proj"))?;
-        let o_proj = linear_no_bias(num_heads * head_dim, hidden_sz, vb.pp("o_proj"))?;
+        let q_proj = lora_linear_no_bias(hidden_sz, num_heads * head_dim, vb.pp("q_proj"))?;
+        let k_proj = lora_linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("k_proj"))?;
+        let v_proj = lora_linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("v_proj"))?;
+        let o_proj = lora_linear_no_bias(num_heads * head_dim, hidden_sz, vb.pp("o_proj"))?;
         Ok(Self {
             q_proj,
             k_proj,
@@ -292,15 +453,20 @@ impl Attention {
             .apply(&self.o_proj)
     }
 
-    fn clear_kv_cache(&mut self) {
-        self.kv_cache = None
+    fn lora_projections(&mut self) -> Vec<&mut LoraLinear> {
+        vec![
+            &mut self.q_proj,
+            &mut self.k_proj,
+            &mut self.v_proj,
+            &mut self.o_proj,
+        ]
     }
 }
 
 #[derive(Debug, Clone)]
 struct DecoderLayer {
     self_attn: Attention,
-    mlp: MLP,
+    mlp: FeedForward,
     input_layernorm: RmsNorm,
     post_attention_layernorm: RmsNorm,
 }
@@ -308,7 +474,7 @@ struct DecoderLayer {
 impl DecoderLayer {
     fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let self_attn = Attention::new(rotary_emb, cfg, vb.pp("self_attn"))?;
-        let mlp = MLP::new(cfg, vb.pp("mlp"))?;
+        let mlp = FeedForward::new(cfg, vb.clone())?;
         let input_layernorm =
             RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
         let post_attention_layernorm = RmsNorm::new(
@@ -342,8 +508,10 @@ impl DecoderLayer {
         residual + xs
     }
 
-    fn clear_kv_cache(&mut self) {
-        self.self_attn.clear_kv_cache()
+    fn lora_projections(&mut self) -> Vec<&mut LoraLinear> {
+        let mut projections = self.self_attn.lora_projections();
+        projections.extend(self.mlp.lora_projections());
+        projections
     }
 }
 
@@ -353,6 +521,7 @@ pub struct Model {
     layers: Vec<DecoderLayer>,
     norm: RmsNorm,
     lm_head: Linear,
+    rotary_emb: Arc<RotaryEmbedding>,
     sliding_window: Option<usize>,
     device: Device,
     dtype: DType,
@@ -377,21 +546,25 @@ impl Model {
             layers,
             norm,
             lm_head,
+            rotary_emb,
             sliding_window: cfg.sliding_window,
             device: vb.device().clone(),
             dtype: vb.dtype(),
         })
     }
 
+    /// Causal mask of the new positions over the cached and the new ones. With a sliding window
+    /// a position only sees the `sliding_window` positions before it, cached ones included.
     fn prepare_decoder_attention_mask(
         &self,
         tgt_len: usize,
         seqlen_offset: usize,
     ) -> Result<Tensor> {
-        let sliding_window = self.sliding_window.unwrap_or(tgt_len + 1);
-        let mask: Vec<_> = (0..tgt_len)
+        let src_len = tgt_len + seqlen_offset;
+        let sliding_window = self.sliding_window.unwrap_or(src_len + 1);
+        let mask: Vec<_> = (seqlen_offset..src_len)
             .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
+                (0..src_len).map(move |j| {
                     if i < j || j + sliding_window < i {
                         f32::NEG_INFINITY
                     } else {
@@ -400,34 +573,16 @@ impl Model {
                 })
             })
             .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))?
+        Tensor::from_slice(&mask, (tgt_len, src_len), &self.device)?
+            .expand((1, 1, tgt_len, src_len))?
             .to_dtype(self.dtype)
     }
 
-    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
-        let (_b_size, seq_len) = input_ids.dims2()?;
-        let attention_mask = if seq_len <= 1 {
-            None
-        } else {
-            let mask = self.prepare_decoder_attention_mask(seq_len, seqlen_offset)?;
-            Some(mask)
-        };
-        let mut xs = self.embed_tokens.forward(input_ids)?;
-        for layer in self.layers.iter_mut() {
-            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset, Some(true))?
-        }
-        xs.narrow(1, seq_len - 1, 1)?
-            .apply(&self.norm)?
-            .apply(&self.lm_head)
-    }
-
+    /// Batched forward pass over left padded `input_ids`.
+    ///
+    /// `attention_mask` is the additive padding mask of shape `(batch, seqlen_offset + seq_len)`
+    /// covering both the cached and the new positions. The key/values of every layer are kept in
+    /// the layer caches so they can be taken out with `take_kv_cache` after the call.
     pub fn forward_with_attention(
         &mut self,
         input_ids: &Tensor,
@@ -436,13 +591,30 @@ impl Model {
     ) -> Result<Tensor> {
         let (_b_size, seq_len) = input_ids.dims2()?;
         let mut embedded_ids: Tensor = self.embed_tokens.forward(input_ids)?;
-        let attention_mask = attention_mask.unsqueeze(1)?.unsqueeze(1)?;
+        // Padded keys get a large finite penalty instead of -inf, otherwise the query rows of
+        // the padding itself are fully masked by the causal mask and the softmax yields NaNs.
+        let attention_mask = attention_mask
+            .maximum(PADDING_MASK_VALUE)?
+            .unsqueeze(1)?
+            .unsqueeze(1)?;
+        // A single new position sees every cached one, unless they reach past the window.
+        let is_windowed = self
+            .sliding_window
+            .is_some_and(|sliding_window| seqlen_offset > sliding_window);
+        let attention_mask = if seq_len <= 1 && !is_windowed {
+            attention_mask
+        } else {
+            let causal_mask = self.prepare_decoder_attention_mask(seq_len, seqlen_offset)?;
+            attention_mask.broadcast_add(&causal_mask.to_dtype(attention_mask.dtype())?)?
+        };
+        // Combined in F32, then cast once to the dtype the attention runs in.
+        let attention_mask = attention_mask.to_dtype(self.dtype)?;
         for layer in self.layers.iter_mut() {
             embedded_ids = layer.forward(
                 &embedded_ids,
                 Some(&attention_mask),
                 seqlen_offset,
-                Some(false),
+                Some(true),
             )?;
         }
         embedded_ids
@@ -451,9 +623,207 @@ impl Model {
             .apply(&self.lm_head)
     }
 
-    pub fn clear_kv_cache(&mut self) {
+    pub fn set_kv_cache(&mut self, cache: Vec<Option<(Tensor, Tensor)>>) {
+        for (layer, layer_cache) in self.layers.iter_mut().zip(cache) {
+            layer.self_attn.kv_cache = layer_cache
+        }
+    }
+
+    pub fn take_kv_cache(&mut self) -> Vec<Option<(Tensor, Tensor)>> {
+        self.layers
+            .iter_mut()
+            .map(|layer| layer.self_attn.kv_cache.take())
+            .collect()
+    }
+
+    pub fn shift_kv_cache(
+        &self,
+        cache: Vec<Option<(Tensor, Tensor)>>,
+        positions: usize,
+    ) -> Result<Vec<Option<(Tensor, Tensor)>>> {
+        cache
+            .into_iter()
+            .map(|layer_cache| match layer_cache {
+                Some((key, value)) => {
+                    Ok(Some((self.rotary_emb.shift_key(&key, positions)?, value)))
+                }
+                None => Ok(None),
+            })
+            .collect()
+    }
+}
+
+impl Model {
+    /// Loads a LoRA adapter onto the attention and MLP projections it targets. Adapters are
+    /// selected by their index in load order, see `set_adapters`.
+    pub fn load_adapter(&mut self, adapter: &LoraAdapter) -> Result<()> {
+        let mut targeted = 0;
         for layer in self.layers.iter_mut() {
-            layer.clear_kv_cache()
+            for projection in layer.lora_projections() {
+                if projection.load_adapter(adapter)? {
+                    targeted += 1;
+                }
+            }
+        }
+        if targeted == 0 {
+            candle_core::bail!(
+                "adapter {} targets none of the attention and MLP projections",
+                adapter.name
+            )
+        }
+        tracing::debug!("adapter {} loaded on {targeted} projections", adapter.name);
+        Ok(())
+    }
+
+    /// Selects the adapter of every batch row for the next forward passes, rows without one only
+    /// use the base weights.
+    pub fn set_adapters(&mut self, adapters: &[Option<usize>]) -> Result<()> {
+        let mut adapter_rows: BTreeMap<usize, Vec<u32>> = BTreeMap::new();
+        for (row, adapter) in adapters.iter().enumerate() {
+            if let Some(adapter) = adapter {
+                adapter_rows.entry(*adapter).or_default().push(row as u32);
+            }
+        }
+        let rows = adapter_rows
+            .into_iter()
+            .map(|(adapter, rows)| Ok((adapter, Tensor::new(rows, &self.device)?)))
+            .collect::<Result<Vec<_>>>()?;
+        for layer in self.layers.iter_mut() {
+            for projection in layer.lora_projections() {
+                projection.set_rows(&rows);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl crate::Architecture for Model {
+    fn forward_with_attention(
+        &mut self,
+        input_ids: &Tensor,
+        attention_mask: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        Model::forward_with_attention(self, input_ids, attention_mask, seqlen_offset)
+    }
+
+    fn set_kv_cache(&mut self, cache: Vec<Option<(Tensor, Tensor)>>) {
+        Model::set_kv_cache(self, cache)
+    }
+
+    fn take_kv_cache(&mut self) -> Vec<Option<(Tensor, Tensor)>> {
+        Model::take_kv_cache(self)
+    }
+
+    fn shift_kv_cache(
+        &self,
+        cache: Vec<Option<(Tensor, Tensor)>>,
+        positions: usize,
+    ) -> Result<Vec<Option<(Tensor, Tensor)>>> {
+        Model::shift_kv_cache(self, cache, positions)
+    }
+
+    fn load_adapter(&mut self, adapter: &LoraAdapter) -> Result<()> {
+        Model::load_adapter(self, adapter)
+    }
+
+    fn set_adapters(&mut self, adapters: &[Option<usize>]) -> Result<()> {
+        Model::set_adapters(self, adapters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+
+    fn config(sliding_window: Option<usize>) -> Config {
+        Config {
+            vocab_size: 32,
+            hidden_size: 32,
+            intermediate_size: 64,
+            num_hidden_layers: 2,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            hidden_act: Activation::Silu,
+            max_position_embeddings: 32,
+            rms_norm_eps: 1e-5,
+            rope_theta: 10_000.,
+            sliding_window,
+            use_flash_attn: false,
+            num_local_experts: None,
+            num_experts_per_tok: None,
+        }
+    }
+
+    fn max_abs_difference(lhs: &Tensor, rhs: &Tensor) -> Result<f32> {
+        (lhs - rhs)?.abs()?.flatten_all()?.max(0)?.to_scalar()
+    }
+
+    #[test]
+    fn sliding_window_applies_to_cached_decoding() -> Result<()> {
+        let vars = VarMap::new();
+        let mut model = Model::new(
+            &config(Some(2)),
+            VarBuilder::from_varmap(&vars, DType::F32, &Device::Cpu),
+        )?;
+        let input_ids = Tensor::new(&[[3u32, 7, 11, 5, 9, 2]], &Device::Cpu)?;
+        let no_padding = |length: usize| Tensor::zeros((1, length), DType::F32, &Device::Cpu);
+
+        let prefill = model.forward_with_attention(&input_ids, &no_padding(6)?, 0)?;
+        model.take_kv_cache();
+        model.forward_with_attention(&input_ids.narrow(1, 0, 5)?, &no_padding(5)?, 0)?;
+        let decoded =
+            model.forward_with_attention(&input_ids.narrow(1, 5, 1)?, &no_padding(6)?, 5)?;
+        assert!(max_abs_difference(&prefill, &decoded)? < 1e-4);
+
+        // Same weights without the window, the last position sees the whole prompt.
+        let mut unwindowed = Model::new(
+            &config(None),
+            VarBuilder::from_varmap(&vars, DType::F32, &Device::Cpu),
+        )?;
+        let unwindowed = unwindowed.forward_with_attention(&input_ids, &no_padding(6)?, 0)?;
+        assert!(max_abs_difference(&prefill, &unwindowed)? > 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn sparse_moe_block_mixes_the_top_experts() -> Result<()> {
+        let config = Config {
+            num_local_experts: Some(4),
+            num_experts_per_tok: Some(2),
+            ..config(None)
+        };
+        let vars = VarMap::new();
+        let vb = VarBuilder::from_varmap(&vars, DType::F32, &Device::Cpu);
+        let mut moe = SparseMoeBlock::new(&config, 4, vb)?;
+        // The router reads the first two dimensions only, its softmax over the experts is
+        // `[0.1, 0.4, 0.2, 0.3]` for the first token and `[0.5, 0.05, 0.15, 0.3]` for the second.
+        let mut gate = vec![0f32; 4 * config.hidden_size];
+        for (expert_idx, probabilities) in [[0.1f32, 0.5], [0.4, 0.05], [0.2, 0.15], [0.3, 0.3]]
+            .iter()
+            .enumerate()
+        {
+            gate[expert_idx * config.hidden_size] = probabilities[0].ln();
+            gate[expert_idx * config.hidden_size + 1] = probabilities[1].ln();
         }
+        let gate = Tensor::from_vec(gate, (4, config.hidden_size), &Device::Cpu)?;
+        moe.gate = Linear::from_weights(gate, None);
+        let mut xs = vec![0f32; 2 * config.hidden_size];
+        xs[0] = 1.;
+        xs[config.hidden_size + 1] = 1.;
+        let xs = Tensor::from_vec(xs, (1, 2, config.hidden_size), &Device::Cpu)?;
+
+        // Experts 1 and 3 weighted 0.4 and 0.3 renormalize to 4/7 and 3/7, experts 0 and 3
+        // weighted 0.5 and 0.3 to 5/8 and 3/8.
+        let token = |idx: usize| xs.narrow(1, idx, 1);
+        let expert =
+            |expert_idx: usize, token_idx: usize| token(token_idx)?.apply(&moe.experts[expert_idx]);
+        let first = ((expert(1, 0)? * (4. / 7.))? + (expert(3, 0)? * (3. / 7.))?)?;
+        let second = ((expert(0, 1)? * (5. / 8.))? + (expert(3, 1)? * (3. / 8.))?)?;
+        let expected = Tensor::cat(&[first, second], 1)?;
+
+        assert!(max_abs_difference(&moe.forward(&xs)?, &expected)? < 1e-6);
+        Ok(())
     }
 }
@@ -1,2 +1,6 @@
+pub mod llama;
+pub mod lora;
 pub mod mistral;
+pub mod phi3;
 pub mod quantized_mistral;
+pub mod qwen2;
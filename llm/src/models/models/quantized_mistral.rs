@@ -1,10 +1,92 @@
-use candle_core::{DType, Device, Module, Result, Tensor, D};
-use candle_nn::Activation;
-use candle_transformers::quantized_nn::{linear_no_bias, Embedding, Linear, RmsNorm};
-pub use candle_transformers::quantized_var_builder::VarBuilder;
+/// Quantized Mistral and Llama LLMs loaded from GGUF files with the llama.cpp tensor names
+/// (`token_embd`, `blk.N.attn_q`, ...), with the same batched forward_with_attention method as
+/// the mistral model
+use crate::{GgufFile, ModelError, ModelResult};
+use candle_core::quantized::{gguf_file, QTensor};
+use candle_core::{DType, Device, Module, Result, Shape, Tensor};
+use candle_nn::{Activation, Embedding, RmsNorm};
+use candle_transformers::quantized_nn::Linear;
+use std::collections::HashMap;
 use std::sync::Arc;
 
-pub use candle_transformers::models::mistral::Config;
+/// Hyperparameters of a quantized model, read from the metadata of its GGUF file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub hidden_act: Activation,
+    pub max_position_embeddings: usize,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f64,
+    pub sliding_window: Option<usize>,
+}
+
+impl From<&super::mistral::Config> for Config {
+    fn from(config: &super::mistral::Config) -> Self {
+        Self {
+            vocab_size: config.vocab_size,
+            hidden_size: config.hidden_size,
+            intermediate_size: config.intermediate_size,
+            num_hidden_layers: config.num_hidden_layers,
+            num_attention_heads: config.num_attention_heads,
+            num_key_value_heads: config.num_key_value_heads,
+            hidden_act: config.hidden_act,
+            max_position_embeddings: config.max_position_embeddings,
+            rms_norm_eps: config.rms_norm_eps,
+            rope_theta: config.rope_theta,
+            sliding_window: config.sliding_window,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_gguf(file: &GgufFile) -> ModelResult<Self> {
+        let architecture = file.architecture()?;
+        if architecture != "llama" {
+            return Err(ModelError::UnsupportedArchitecture {
+                architectures: vec![architecture.to_owned()],
+                model_type: None,
+            });
+        }
+        // Mixtral GGUF files share the llama architecture, their experts are not supported.
+        if let Ok(expert_count) = file.hyperparameter("expert_count") {
+            if expert_count.to_u32()? > 0 {
+                return Err(ModelError::UnsupportedQuantization {
+                    architecture: "mixtral",
+                });
+            }
+        }
+        let usize_value =
+            |key: &str| -> ModelResult<usize> { Ok(file.hyperparameter(key)?.to_u32()? as usize) };
+        let num_attention_heads = usize_value("attention.head_count")?;
+        let rope_theta = match file.hyperparameter("rope.freq_base") {
+            Ok(value) => value.to_f32()? as f64,
+            Err(_) => 10_000.,
+        };
+        Ok(Self {
+            vocab_size: file.tensor_shape("token_embd.weight")?[0],
+            hidden_size: usize_value("embedding_length")?,
+            intermediate_size: usize_value("feed_forward_length")?,
+            num_hidden_layers: usize_value("block_count")?,
+            num_attention_heads,
+            num_key_value_heads: usize_value("attention.head_count_kv")
+                .unwrap_or(num_attention_heads),
+            hidden_act: Activation::Silu,
+            max_position_embeddings: usize_value("context_length")?,
+            rms_norm_eps: file
+                .hyperparameter("attention.layer_norm_rms_epsilon")?
+                .to_f32()? as f64,
+            rope_theta,
+            sliding_window: None,
+        })
+    }
+}
+
+const PADDING_MASK_VALUE: f32 = -1e4;
 
 #[derive(Debug, Clone)]
 struct RotaryEmbedding {
@@ -42,10 +124,115 @@ impl RotaryEmbedding {
         let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
         let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
         let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
-        let q_embed = candle_nn::rotary_emb::rope(q, &cos, &sin)?;
-        let k_embed = candle_nn::rotary_emb::rope(k, &cos, &sin)?;
+        // llama.cpp permutes the query and key weights so the rotated pairs are interleaved.
+        let q_embed = candle_nn::rotary_emb::rope_i(q, &cos, &sin)?;
+        let k_embed = candle_nn::rotary_emb::rope_i(k, &cos, &sin)?;
         Ok((q_embed, k_embed))
     }
+
+    /// Rotates already embedded keys further by `positions`, used to move a cached sequence to
+    /// the right when it gets left padded.
+    fn shift_key(&self, k: &Tensor, positions: usize) -> Result<Tensor> {
+        let (_b_sz, _h, seq_len, _n_embd) = k.dims4()?;
+        let (_max_seq_len, half_dim) = self.cos.dims2()?;
+        let cos = self
+            .cos
+            .narrow(0, positions, 1)?
+            .broadcast_as((seq_len, half_dim))?
+            .contiguous()?;
+        let sin = self
+            .sin
+            .narrow(0, positions, 1)?
+            .broadcast_as((seq_len, half_dim))?
+            .contiguous()?;
+        candle_nn::rotary_emb::rope_i(&k.contiguous()?, &cos, &sin)
+    }
+}
+
+/// Quantized tensors by llama.cpp name, read from a GGUF file or handed over by the weights
+/// quantized while loading a safetensors checkpoint.
+#[derive(Debug, Clone)]
+pub struct VarBuilder {
+    tensors: Arc<HashMap<String, Arc<QTensor>>>,
+    path: Vec<String>,
+    device: Device,
+}
+
+impl VarBuilder {
+    pub fn from_gguf<P: AsRef<std::path::Path>>(path: P, device: &Device) -> Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        let content = gguf_file::Content::read(&mut file)?;
+        let mut tensors = Vec::with_capacity(content.tensor_infos.len());
+        for name in content.tensor_infos.keys() {
+            tensors.push((name.clone(), content.tensor(&mut file, name, device)?));
+        }
+        Ok(Self::from_qtensors(tensors, device))
+    }
+
+    /// The tensors are taken as is, they have to be on `device` already.
+    pub fn from_qtensors<I: IntoIterator<Item = (String, QTensor)>>(
+        tensors: I,
+        device: &Device,
+    ) -> Self {
+        let tensors = tensors
+            .into_iter()
+            .map(|(name, tensor)| (name, Arc::new(tensor)))
+            .collect();
+        Self {
+            tensors: Arc::new(tensors),
+            path: Vec::new(),
+            device: device.clone(),
+        }
+    }
+
+    pub fn pp<S: ToString>(&self, name: S) -> Self {
+        let mut path = self.path.clone();
+        path.push(name.to_string());
+        Self {
+            tensors: self.tensors.clone(),
+            path,
+            device: self.device.clone(),
+        }
+    }
+
+    pub fn get<S: Into<Shape>>(&self, shape: S, name: &str) -> Result<Arc<QTensor>> {
+        let mut path = self.path.clone();
+        path.push(name.to_owned());
+        let path = path.join(".");
+        let tensor = match self.tensors.get(&path) {
+            Some(tensor) => tensor,
+            None => candle_core::bail!("cannot find tensor {path}"),
+        };
+        let shape = shape.into();
+        if tensor.shape() != &shape {
+            candle_core::bail!(
+                "shape mismatch for {path}, got {:?}, expected {shape:?}",
+                tensor.shape()
+            )
+        }
+        Ok(tensor.clone())
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+}
+
+fn linear_no_bias(in_dim: usize, out_dim: usize, vb: VarBuilder) -> Result<Linear> {
+    Linear::from_arc(vb.get((out_dim, in_dim), "weight")?, None)
+}
+
+/// The embeddings and the norms are dequantized, only the linear layers run quantized.
+fn embedding(vocab_size: usize, hidden_size: usize, vb: VarBuilder) -> Result<Embedding> {
+    let embeddings = vb
+        .get((vocab_size, hidden_size), "weight")?
+        .dequantize(vb.device())?;
+    Ok(Embedding::new(embeddings, hidden_size))
+}
+
+fn rms_norm(size: usize, eps: f64, vb: VarBuilder) -> Result<RmsNorm> {
+    let weight = vb.get(size, "weight")?.dequantize(vb.device())?;
+    Ok(RmsNorm::new(weight, eps))
 }
 
 #[derive(Debug, Clone)]
@@ -61,9 +248,9 @@ impl MLP {
     fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let hidden_sz = cfg.hidden_size;
         let intermediate_sz = cfg.intermediate_size;
-        let gate_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("gate_proj"))?;
-        let up_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("up_proj"))?;
-        let down_proj = linear_no_bias(intermediate_sz, hidden_sz, vb.pp("down_proj"))?;
+        let gate_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("ffn_gate"))?;
+        let up_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("ffn_up"))?;
+        let down_proj = linear_no_bias(intermediate_sz, hidden_sz, vb.pp("ffn_down"))?;
         Ok(Self {
             gate_proj,
             up_proj,
@@ -103,10 +290,10 @@ impl Attention {
         let num_kv_heads = cfg.num_key_value_heads;
         let num_kv_groups = num_heads / num_kv_heads;
         let head_dim = hidden_sz / num_heads;
-        let q_proj = linear_no_bias(hidden_sz, num_heads * head_dim, vb.pp("q_proj"))?;
-        let k_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("k_proj"))?;
-        let v_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("v_proj"))?;
-        let o_proj = linear_no_bias(num_heads * head_dim, hidden_sz, vb.pp("o_proj"))?;
+        let q_proj = linear_no_bias(hidden_sz, num_heads * head_dim, vb.pp("attn_q"))?;
+        let k_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("attn_k"))?;
+        let v_proj = linear_no_bias(hidden_sz, num_kv_heads * head_dim, vb.pp("attn_v"))?;
+        let o_proj = linear_no_bias(num_heads * head_dim, hidden_sz, vb.pp("attn_output"))?;
         Ok(Self {
             q_proj,
             k_proj,
@@ -186,10 +373,6 @@ impl Attention {
             .reshape((b_sz, q_len, self.hidden_size))?
             .apply(&self.o_proj)
     }
-
-    fn clear_kv_cache(&mut self) {
-        self.kv_cache = None
-    }
 }
 
 #[derive(Debug, Clone)]
@@ -202,15 +385,11 @@ struct DecoderLayer {
 
 impl DecoderLayer {
     fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
-        let self_attn = Attention::new(rotary_emb, cfg, vb.pp("self_attn"))?;
-        let mlp = MLP::new(cfg, vb.pp("mlp"))?;
-        let input_layernorm =
-            RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
-        let post_attention_layernorm = RmsNorm::new(
-            cfg.hidden_size,
-            cfg.rms_norm_eps,
-            vb.pp("post_attention_layernorm"),
-        )?;
+        let self_attn = Attention::new(rotary_emb, cfg, vb.clone())?;
+        let mlp = MLP::new(cfg, vb.clone())?;
+        let input_layernorm = rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("attn_norm"))?;
+        let post_attention_layernorm =
+            rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("ffn_norm"))?;
         Ok(Self {
             self_attn,
             mlp,
@@ -236,10 +415,6 @@ impl DecoderLayer {
         let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
         residual + xs
     }
-
-    fn clear_kv_cache(&mut self) {
-        self.self_attn.clear_kv_cache()
-    }
 }
 
 #[derive(Debug, Clone)]
@@ -248,43 +423,54 @@ pub struct Model {
     layers: Vec<DecoderLayer>,
     norm: RmsNorm,
     lm_head: Linear,
+    rotary_emb: Arc<RotaryEmbedding>,
     sliding_window: Option<usize>,
     device: Device,
 }
 
 impl Model {
     pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
-        let vb_m = vb.pp("model");
-        let embed_tokens =
-            Embedding::new(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
-        let rotary_emb = Arc::new(RotaryEmbedding::new(cfg, vb_m.device())?);
+        let embed_tokens = embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("token_embd"))?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(cfg, vb.device())?);
         let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
-        let vb_l = vb_m.pp("layers");
+        let vb_l = vb.pp("blk");
         for layer_idx in 0..cfg.num_hidden_layers {
             let layer = DecoderLayer::new(rotary_emb.clone(), cfg, vb_l.pp(layer_idx))?;
             layers.push(layer)
         }
-        let norm = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?;
-        let lm_head = linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?;
+        let norm = rms_norm(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("output_norm"))?;
+        // Files of models with tied embeddings have no output weight.
+        let lm_head = match linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("output")) {
+            Ok(lm_head) => lm_head,
+            Err(_) => Linear::from_arc(
+                vb.pp("token_embd")
+                    .get((cfg.vocab_size, cfg.hidden_size), "weight")?,
+                None,
+            )?,
+        };
         Ok(Self {
             embed_tokens,
             layers,
             norm,
             lm_head,
+            rotary_emb,
             sliding_window: cfg.sliding_window,
             device: vb.device().clone(),
         })
     }
 
+    /// Causal mask of the new positions over the cached and the new ones. With a sliding window
+    /// a position only sees the `sliding_window` positions before it, cached ones included.
     fn prepare_decoder_attention_mask(
         &self,
         tgt_len: usize,
         seqlen_offset: usize,
     ) -> Result<Tensor> {
-        let sliding_window = self.sliding_window.unwrap_or(tgt_len + 1);
-        let mask: Vec<_> = (0..tgt_len)
+        let src_len = tgt_len + seqlen_offset;
+        let sliding_window = self.sliding_window.unwrap_or(src_len + 1);
+        let mask: Vec<_> = (seqlen_offset..src_len)
             .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
+                (0..src_len).map(move |j| {
                     if i < j || j + sliding_window < i {
                         f32::NEG_INFINITY
                     } else {
@@ -293,35 +479,16 @@ impl Model {
                 })
             })
             .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))?
+        Tensor::from_slice(&mask, (tgt_len, src_len), &self.device)?
+            .expand((1, 1, tgt_len, src_len))?
             .to_dtype(DType::F32)
     }
 
-    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
-        let (_b_size, seq_len) = input_ids.dims2()?;
-        let attention_mask = if seq_len <= 1 {
-            None
-        } else {
-            let mask = self.prepare_decoder_attention_mask(seq_len, seqlen_offset)?;
-            Some(mask)
-        };
-        let mut xs = self.embed_tokens.forward(input_ids)?;
-        for layer in self.layers.iter_mut() {
-            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset, Some(true))?
-        }
-        xs.narrow(1, seq_len - 1, 1)?
-            .contiguous()?
-            .apply(&self.norm)?
-            .apply(&self.lm_head)
-    }
-
+    /// Batched forward pass over left padded `input_ids`.
+    ///
+    /// `attention_mask` is the additive padding mask of shape `(batch, seqlen_offset + seq_len)`
+    /// covering both the cached and the new positions. The key/values of every layer are kept in
+    /// the layer caches so they can be taken out with `take_kv_cache` after the call.
     pub fn forward_with_attention(
         &mut self,
         input_ids: &Tensor,
@@ -330,13 +497,28 @@ impl Model {
     ) -> Result<Tensor> {
         let (_b_size, seq_len) = input_ids.dims2()?;
         let mut embedded_ids: Tensor = self.embed_tokens.forward(input_ids)?;
-        let attention_mask = attention_mask.unsqueeze(1)?.unsqueeze(1)?;
+        // Padded keys get a large finite penalty instead of -inf, otherwise the query rows of
+        // the padding itself are fully masked by the causal mask and the softmax yields NaNs.
+        let attention_mask = attention_mask
+            .maximum(PADDING_MASK_VALUE)?
+            .unsqueeze(1)?
+            .unsqueeze(1)?;
+        // A single new position sees every cached one, unless they reach past the window.
+        let is_windowed = self
+            .sliding_window
+            .is_some_and(|sliding_window| seqlen_offset > sliding_window);
+        let attention_mask = if seq_len <= 1 && !is_windowed {
+            attention_mask
+        } else {
+            let causal_mask = self.prepare_decoder_attention_mask(seq_len, seqlen_offset)?;
+            attention_mask.broadcast_add(&causal_mask.to_dtype(attention_mask.dtype())?)?
+        };
         for layer in self.layers.iter_mut() {
             embedded_ids = layer.forward(
                 &embedded_ids,
                 Some(&attention_mask),
                 seqlen_offset,
-                Some(false),
+                Some(true),
             )?;
         }
         embedded_ids
@@ -345,9 +527,59 @@ impl Model {
             .apply(&self.lm_head)
     }
 
-    pub fn clear_kv_cache(&mut self) {
-        for layer in self.layers.iter_mut() {
-            layer.clear_kv_cache()
+    pub fn set_kv_cache(&mut self, cache: Vec<Option<(Tensor, Tensor)>>) {
+        for (layer, layer_cache) in self.layers.iter_mut().zip(cache) {
+            layer.self_attn.kv_cache = layer_cache
         }
     }
+
+    pub fn take_kv_cache(&mut self) -> Vec<Option<(Tensor, Tensor)>> {
+        self.layers
+            .iter_mut()
+            .map(|layer| layer.self_attn.kv_cache.take())
+            .collect()
+    }
+
+    pub fn shift_kv_cache(
+        &self,
+        cache: Vec<Option<(Tensor, Tensor)>>,
+        positions: usize,
+    ) -> Result<Vec<Option<(Tensor, Tensor)>>> {
+        cache
+            .into_iter()
+            .map(|layer_cache| match layer_cache {
+                Some((key, value)) => {
+                    Ok(Some((self.rotary_emb.shift_key(&key, positions)?, value)))
+                }
+                None => Ok(None),
+            })
+            .collect()
+    }
+}
+
+impl crate::Architecture for Model {
+    fn forward_with_attention(
+        &mut self,
+        input_ids: &Tensor,
+        attention_mask: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        Model::forward_with_attention(self, input_ids, attention_mask, seqlen_offset)
+    }
+
+    fn set_kv_cache(&mut self, cache: Vec<Option<(Tensor, Tensor)>>) {
+        Model::set_kv_cache(self, cache)
+    }
+
+    fn take_kv_cache(&mut self) -> Vec<Option<(Tensor, Tensor)>> {
+        Model::take_kv_cache(self)
+    }
+
+    fn shift_kv_cache(
+        &self,
+        cache: Vec<Option<(Tensor, Tensor)>>,
+        positions: usize,
+    ) -> Result<Vec<Option<(Tensor, Tensor)>>> {
+        Model::shift_kv_cache(self, cache, positions)
+    }
 }
@@ -0,0 +1,534 @@
+/// Qwen2 LLM, https://github.com/QwenLM/Qwen2
+/// with the same batched forward_with_attention method as the mistral model
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{Activation, VarBuilder};
+use candle_transformers::models::with_tracing::{linear, linear_no_bias, Linear, RmsNorm};
+use std::sync::Arc;
+
+const PADDING_MASK_VALUE: f32 = -1e4;
+
+fn default_rope_theta() -> f64 {
+    10_000.
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub hidden_act: Activation,
+    pub max_position_embeddings: usize,
+    pub rms_norm_eps: f64,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f64,
+    /// Only used when `use_sliding_window` is set, which none of the released checkpoints do.
+    pub sliding_window: Option<usize>,
+    #[serde(default)]
+    pub use_sliding_window: bool,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
+}
+
+impl Config {
+    fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
+        let rope_theta = cfg.rope_theta as f32;
+        let dim = cfg.head_dim();
+        let max_seq_len = cfg.max_position_embeddings;
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / rope_theta.powf(i as f32 / dim as f32))
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(dtype)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(dtype)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: freqs.sin()?,
+            cos: freqs.cos()?,
+        })
+    }
+
+    fn apply_rotary_emb_qkv(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<(Tensor, Tensor)> {
+        let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
+        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
+        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        let q_embed = candle_nn::rotary_emb::rope(q, &cos, &sin)?;
+        let k_embed = candle_nn::rotary_emb::rope(k, &cos, &sin)?;
+        Ok((q_embed, k_embed))
+    }
+
+    /// Rotates already embedded keys further by `positions`, used to move a cached sequence to
+    /// the right when it gets left padded.
+    fn shift_key(&self, k: &Tensor, positions: usize) -> Result<Tensor> {
+        let (_b_sz, _h, seq_len, _n_embd) = k.dims4()?;
+        let (_max_seq_len, half_dim) = self.cos.dims2()?;
+        let cos = self
+            .cos
+            .narrow(0, positions, 1)?
+            .broadcast_as((seq_len, half_dim))?
+            .contiguous()?;
+        let sin = self
+            .sin
+            .narrow(0, positions, 1)?
+            .broadcast_as((seq_len, half_dim))?
+            .contiguous()?;
+        candle_nn::rotary_emb::rope(&k.contiguous()?, &cos, &sin)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+}
+
+impl MLP {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let intermediate_sz = cfg.intermediate_size;
+        let gate_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("gate_proj"))?;
+        let up_proj = linear_no_bias(hidden_sz, intermediate_sz, vb.pp("up_proj"))?;
+        let down_proj = linear_no_bias(intermediate_sz, hidden_sz, vb.pp("down_proj"))?;
+        Ok(Self {
+            gate_proj,
+            up_proj,
+            down_proj,
+            act_fn: cfg.hidden_act,
+        })
+    }
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let lhs = xs.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = xs.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    hidden_size: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl Attention {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden_sz = cfg.hidden_size;
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let num_kv_groups = num_heads / num_kv_heads;
+        let head_dim = cfg.head_dim();
+        // Unlike Mistral and Llama the query, key and value projections have a bias.
+        let q_proj = linear(hidden_sz, num_heads * head_dim, vb.pp("q_proj"))?;
+        let k_proj = linear(hidden_sz, num_kv_heads * head_dim, vb.pp("k_proj"))?;
+        let v_proj = linear(hidden_sz, num_kv_heads * head_dim, vb.pp("v_proj"))?;
+        let o_proj = linear_no_bias(num_heads * head_dim, hidden_sz, vb.pp("o_proj"))?;
+        Ok(Self {
+            q_proj,
+            k_proj,
+            v_proj,
+            o_proj,
+            num_heads,
+            num_kv_heads,
+            num_kv_groups,
+            head_dim,
+            hidden_size: hidden_sz,
+            rotary_emb,
+            kv_cache: None,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let query_states = self
+            .q_proj
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let key_states = self
+            .k_proj
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let value_states = self
+            .v_proj
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let (query_states, key_states) =
+            self.rotary_emb
+                .apply_rotary_emb_qkv(&query_states, &key_states, seqlen_offset)?;
+
+        let (key_states, value_states) = match &self.kv_cache {
+            None => (key_states, value_states),
+            Some((prev_k, prev_v)) => (
+                Tensor::cat(&[prev_k, &key_states], 2)?,
+                Tensor::cat(&[prev_v, &value_states], 2)?,
+            ),
+        };
+        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+
+        let key_states = candle_transformers::utils::repeat_kv(key_states, self.num_kv_groups)?;
+        let value_states = candle_transformers::utils::repeat_kv(value_states, self.num_kv_groups)?;
+
+        let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+        let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
+        let attn_weights =
+            attn_weights.broadcast_add(&attention_mask.to_dtype(attn_weights.dtype())?)?;
+        let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+        attn_weights
+            .matmul(&value_states)?
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, self.hidden_size))?
+            .apply(&self.o_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MLP,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let self_attn = Attention::new(rotary_emb, cfg, vb.pp("self_attn"))?;
+        let mlp = MLP::new(cfg, vb.pp("mlp"))?;
+        let input_layernorm =
+            RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?;
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            vb.pp("post_attention_layernorm"),
+        )?;
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.self_attn.forward(&xs, attention_mask, seqlen_offset)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
+        residual + xs
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    rotary_emb: Arc<RotaryEmbedding>,
+    sliding_window: Option<usize>,
+    device: Device,
+    dtype: DType,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let embed_tokens =
+            candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let rotary_emb = Arc::new(RotaryEmbedding::new(vb.dtype(), cfg, vb_m.device())?);
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let layer = DecoderLayer::new(rotary_emb.clone(), cfg, vb_l.pp(layer_idx))?;
+            layers.push(layer)
+        }
+        let norm = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?;
+        let lm_head = if cfg.tie_word_embeddings {
+            Linear::from_weights(embed_tokens.embeddings().clone(), None)
+        } else {
+            linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?
+        };
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            rotary_emb,
+            sliding_window: cfg.sliding_window.filter(|_| cfg.use_sliding_window),
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    fn prepare_causal_mask(&self, tgt_len: usize, seqlen_offset: usize) -> Result<Tensor> {
+        let sliding_window = self.sliding_window.unwrap_or(tgt_len + 1);
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| {
+                (0..tgt_len).map(move |j| {
+                    if i < j || j + sliding_window < i {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        let mask = if seqlen_offset > 0 {
+            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
+            Tensor::cat(&[&mask0, &mask], D::Minus1)?
+        } else {
+            mask
+        };
+        mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))?
+            .to_dtype(self.dtype)
+    }
+
+    /// Batched forward pass over left padded `input_ids`.
+    ///
+    /// `attention_mask` is the additive padding mask of shape `(batch, seqlen_offset + seq_len)`
+    /// covering both the cached and the new positions. The key/values of every layer are kept in
+    /// the layer caches so they can be taken out with `take_kv_cache` after the call.
+    pub fn forward_with_attention(
+        &mut self,
+        input_ids: &Tensor,
+        attention_mask: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        // Padded keys get a large finite penalty instead of -inf, otherwise the query rows of
+        // the padding itself are fully masked by the causal mask and the softmax yields NaNs.
+        let attention_mask = attention_mask
+            .maximum(PADDING_MASK_VALUE)?
+            .unsqueeze(1)?
+            .unsqueeze(1)?;
+        let attention_mask = if seq_len <= 1 {
+            attention_mask
+        } else {
+            let causal_mask = self.prepare_causal_mask(seq_len, seqlen_offset)?;
+            attention_mask.broadcast_add(&causal_mask.to_dtype(attention_mask.dtype())?)?
+        };
+        // Combined in F32, then cast once to the dtype the attention runs in.
+        let attention_mask = attention_mask.to_dtype(self.dtype)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, &attention_mask, seqlen_offset)?;
+        }
+        xs.narrow(1, seq_len - 1, 1)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+
+    pub fn set_kv_cache(&mut self, cache: Vec<Option<(Tensor, Tensor)>>) {
+        for (layer, layer_cache) in self.layers.iter_mut().zip(cache) {
+            layer.self_attn.kv_cache = layer_cache
+        }
+    }
+
+    pub fn take_kv_cache(&mut self) -> Vec<Option<(Tensor, Tensor)>> {
+        self.layers
+            .iter_mut()
+            .map(|layer| layer.self_attn.kv_cache.take())
+            .collect()
+    }
+
+    pub fn shift_kv_cache(
+        &self,
+        cache: Vec<Option<(Tensor, Tensor)>>,
+        positions: usize,
+    ) -> Result<Vec<Option<(Tensor, Tensor)>>> {
+        cache
+            .into_iter()
+            .map(|layer_cache| match layer_cache {
+                Some((key, value)) => {
+                    Ok(Some((self.rotary_emb.shift_key(&key, positions)?, value)))
+                }
+                None => Ok(None),
+            })
+            .collect()
+    }
+}
+
+impl crate::Architecture for Model {
+    fn forward_with_attention(
+        &mut self,
+        input_ids: &Tensor,
+        attention_mask: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        Model::forward_with_attention(self, input_ids, attention_mask, seqlen_offset)
+    }
+
+    fn set_kv_cache(&mut self, cache: Vec<Option<(Tensor, Tensor)>>) {
+        Model::set_kv_cache(self, cache)
+    }
+
+    fn take_kv_cache(&mut self) -> Vec<Option<(Tensor, Tensor)>> {
+        Model::take_kv_cache(self)
+    }
+
+    fn shift_kv_cache(
+        &self,
+        cache: Vec<Option<(Tensor, Tensor)>>,
+        positions: usize,
+    ) -> Result<Vec<Option<(Tensor, Tensor)>>> {
+        Model::shift_kv_cache(self, cache, positions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+
+    fn model() -> Result<Model> {
+        let config = Config {
+            vocab_size: 32,
+            hidden_size: 32,
+            intermediate_size: 64,
+            num_hidden_layers: 2,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            hidden_act: Activation::Silu,
+            max_position_embeddings: 32,
+            rms_norm_eps: 1e-6,
+            rope_theta: 10_000.,
+            sliding_window: None,
+            use_sliding_window: false,
+            tie_word_embeddings: false,
+        };
+        // Random weights, the query, key and value biases included.
+        let vars = VarMap::new();
+        Model::new(
+            &config,
+            VarBuilder::from_varmap(&vars, DType::F32, &Device::Cpu),
+        )
+    }
+
+    fn max_abs_difference(lhs: &Tensor, rhs: &Tensor) -> Result<f32> {
+        (lhs - rhs)?.abs()?.flatten_all()?.max(0)?.to_scalar()
+    }
+
+    fn no_padding(batch_size: usize, sequence_length: usize) -> Result<Tensor> {
+        Tensor::zeros((batch_size, sequence_length), DType::F32, &Device::Cpu)
+    }
+
+    #[test]
+    fn cached_decoding_matches_the_prefill() -> Result<()> {
+        let mut model = model()?;
+        let input_ids = Tensor::new(&[[3u32, 7, 11, 5, 2]], &Device::Cpu)?;
+        let prefill = model.forward_with_attention(&input_ids, &no_padding(1, 5)?, 0)?;
+        assert_eq!(prefill.dims(), &[1, 1, 32]);
+        model.take_kv_cache();
+
+        model.forward_with_attention(&input_ids.narrow(1, 0, 4)?, &no_padding(1, 4)?, 0)?;
+        let decoded =
+            model.forward_with_attention(&input_ids.narrow(1, 4, 1)?, &no_padding(1, 5)?, 4)?;
+        assert!(max_abs_difference(&prefill, &decoded)? < 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn left_padded_rows_match_the_unpadded_forward() -> Result<()> {
+        let mut model = model()?;
+        let alone = model.forward_with_attention(
+            &Tensor::new(&[[3u32, 7, 11]], &Device::Cpu)?,
+            &no_padding(1, 3)?,
+            0,
+        )?;
+        model.take_kv_cache();
+
+        let input_ids = Tensor::new(&[[0u32, 0, 3, 7, 11], [4, 9, 3, 7, 11]], &Device::Cpu)?;
+        let attention_mask = Tensor::new(
+            &[[f32::NEG_INFINITY, f32::NEG_INFINITY, 0., 0., 0.], [0.; 5]],
+            &Device::Cpu,
+        )?;
+        let batched = model.forward_with_attention(&input_ids, &attention_mask, 0)?;
+        assert_eq!(batched.dims(), &[2, 1, 32]);
+        assert!(max_abs_difference(&alone, &batched.narrow(0, 0, 1)?)? < 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn shifted_keys_match_the_keys_computed_at_the_shifted_positions() -> Result<()> {
+        let mut model = model()?;
+        model.forward_with_attention(
+            &Tensor::new(&[[3u32, 7, 11]], &Device::Cpu)?,
+            &no_padding(1, 3)?,
+            0,
+        )?;
+        let cache = model.take_kv_cache();
+        let shifted = model.shift_kv_cache(cache, 2)?;
+
+        model.forward_with_attention(
+            &Tensor::new(&[[0u32, 0, 3, 7, 11]], &Device::Cpu)?,
+            &Tensor::new(
+                &[[f32::NEG_INFINITY, f32::NEG_INFINITY, 0., 0., 0.]],
+                &Device::Cpu,
+            )?,
+            0,
+        )?;
+        let padded = model.take_kv_cache();
+
+        for (shifted, padded) in shifted.iter().zip(padded.iter()) {
+            let ((shifted_key, shifted_value), (padded_key, padded_value)) =
+                (shifted.as_ref().unwrap(), padded.as_ref().unwrap());
+            assert!(max_abs_difference(shifted_key, &padded_key.narrow(2, 2, 3)?)? < 1e-4);
+            assert!(max_abs_difference(shifted_value, &padded_value.narrow(2, 2, 3)?)? < 1e-4);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,421 @@
+use super::models::{mistral, quantized_mistral};
+use crate::{ArchitectureEntry, ModelError, ModelFiles, ModelResult};
+use candle_core::quantized::{ggml_file, gguf_file, GgmlDType, QTensor};
+use candle_core::{DType, Device, Tensor};
+
+/// Quantization applied to the linear layers of safetensors weights when they are loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantization {
+    Q8_0,
+    Q4K,
+}
+
+impl Quantization {
+    pub fn ggml_dtype(&self) -> GgmlDType {
+        match self {
+            Quantization::Q8_0 => GgmlDType::Q8_0,
+            Quantization::Q4K => GgmlDType::Q4K,
+        }
+    }
+}
+
+impl std::fmt::Display for Quantization {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Quantization::Q8_0 => write!(f, "Q8_0"),
+            Quantization::Q4K => write!(f, "Q4_K"),
+        }
+    }
+}
+
+impl std::str::FromStr for Quantization {
+    type Err = ModelError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_uppercase().as_str() {
+            "Q8_0" => Ok(Quantization::Q8_0),
+            "Q4_K" | "Q4K" => Ok(Quantization::Q4K),
+            _ => Err(ModelError::InvalidQuantization(value.to_owned())),
+        }
+    }
+}
+
+/// Safetensors weights quantized into the tensors and metadata of a llama.cpp GGUF file, as read
+/// by the quantized Mistral model.
+#[derive(Debug)]
+pub struct QuantizedWeights {
+    pub metadata: Vec<(String, gguf_file::Value)>,
+    pub tensors: Vec<(String, QTensor)>,
+}
+
+impl QuantizedWeights {
+    /// Quantizes the linear layers of a Mistral checkpoint, the embeddings and the norms are
+    /// kept in F32. Tensors whose rows do not fit the blocks of `quantization` fall back to
+    /// Q8_0, then F32.
+    pub fn from_safetensors(
+        files: &ModelFiles,
+        config: &mistral::Config,
+        quantization: Quantization,
+    ) -> ModelResult<Self> {
+        Self::convert_safetensors(files, config, quantization.ggml_dtype())
+    }
+
+    /// Renames, permutes and converts the tensors to `dtype`, F32 keeps the weights as they are.
+    fn convert_safetensors(
+        files: &ModelFiles,
+        config: &mistral::Config,
+        dtype: GgmlDType,
+    ) -> ModelResult<Self> {
+        if files.weights.is_empty() {
+            return Err(ModelError::MissingFile {
+                directory: files.directory(),
+                file: "*.safetensors weights".to_owned(),
+            });
+        }
+        let safetensors =
+            unsafe { candle_core::safetensors::MmapedSafetensors::multi(&files.weights)? };
+        let mut names: Vec<String> = safetensors
+            .tensors()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        let mut tensors = Vec::with_capacity(names.len());
+        for name in names.into_iter() {
+            let gguf_name = match gguf_tensor_name(&name) {
+                Some(gguf_name) => gguf_name,
+                None => {
+                    tracing::debug!("skipping tensor {name}");
+                    continue;
+                }
+            };
+            let tensor = safetensors
+                .load(&name, &Device::Cpu)?
+                .to_dtype(DType::F32)?;
+            let tensor = if gguf_name.ends_with("attn_q.weight") {
+                permute_rotary(&tensor, config.num_attention_heads)?
+            } else if gguf_name.ends_with("attn_k.weight") {
+                permute_rotary(&tensor, config.num_key_value_heads)?
+            } else {
+                tensor
+            };
+            let tensor_dtype = if is_float_tensor(&gguf_name) {
+                GgmlDType::F32
+            } else {
+                block_dtype(&tensor, dtype)
+            };
+            tracing::debug!("quantizing {name} as {gguf_name} to {tensor_dtype:?}");
+            tensors.push((gguf_name, QTensor::quantize(&tensor, tensor_dtype)?));
+        }
+        Ok(Self {
+            metadata: gguf_metadata(config),
+            tensors,
+        })
+    }
+
+    /// Quantizes the safetensors checkpoint of `files`. Only the Mistral family has a quantized
+    /// network to load the result back.
+    pub fn from_model_files(files: &ModelFiles, quantization: Quantization) -> ModelResult<Self> {
+        let entry = ArchitectureEntry::detect(&files.load_config::<serde_json::Value>()?)?;
+        if entry.name != "mistral" {
+            return Err(ModelError::UnsupportedQuantization {
+                architecture: entry.name,
+            });
+        }
+        let config: mistral::Config = files.load_config()?;
+        Self::from_safetensors(files, &config, quantization)
+    }
+
+    /// Adds or replaces a metadata entry.
+    pub fn set_metadata(&mut self, key: &str, value: gguf_file::Value) {
+        self.metadata.retain(|(existing, _)| existing != key);
+        self.metadata.push((key.to_owned(), value));
+    }
+
+    pub fn write<W: std::io::Seek + std::io::Write>(&self, writer: &mut W) -> ModelResult<()> {
+        let metadata: Vec<(&str, &gguf_file::Value)> = self
+            .metadata
+            .iter()
+            .map(|(key, value)| (key.as_str(), value))
+            .collect();
+        let tensors: Vec<(&str, &QTensor)> = self
+            .tensors
+            .iter()
+            .map(|(name, tensor)| (name.as_str(), tensor))
+            .collect();
+        gguf_file::write(writer, &metadata, &tensors)?;
+        Ok(())
+    }
+
+    /// Hands the quantized tensors over to the quantized model. They are quantized on the CPU,
+    /// other devices get a copy of their blocks.
+    pub fn into_var_builder(self, device: &Device) -> ModelResult<quantized_mistral::VarBuilder> {
+        let mut tensors = Vec::with_capacity(self.tensors.len());
+        for (name, tensor) in self.tensors.into_iter() {
+            let tensor = if device.is_cpu() {
+                tensor
+            } else {
+                ggml_file::qtensor_from_ggml(
+                    tensor.dtype(),
+                    &tensor.data()?,
+                    tensor.shape().dims().to_vec(),
+                    device,
+                )?
+            };
+            tensors.push((name, tensor));
+        }
+        Ok(quantized_mistral::VarBuilder::from_qtensors(
+            tensors, device,
+        ))
+    }
+}
+
+/// llama.cpp name of a Hugging Face Mistral/Llama tensor, `None` for the ones the model does not
+/// use.
+fn gguf_tensor_name(name: &str) -> Option<String> {
+    match name {
+        "model.embed_tokens.weight" => return Some("token_embd.weight".to_owned()),
+        "model.norm.weight" => return Some("output_norm.weight".to_owned()),
+        "lm_head.weight" => return Some("output.weight".to_owned()),
+        _ => (),
+    }
+    let layer = name.strip_prefix("model.layers.")?;
+    let (index, tensor) = layer.split_once('.')?;
+    let tensor = match tensor {
+        "self_attn.q_proj.weight" => "attn_q.weight",
+        "self_attn.k_proj.weight" => "attn_k.weight",
+        "self_attn.v_proj.weight" => "attn_v.weight",
+        "self_attn.o_proj.weight" => "attn_output.weight",
+        "mlp.gate_proj.weight" => "ffn_gate.weight",
+        "mlp.up_proj.weight" => "ffn_up.weight",
+        "mlp.down_proj.weight" => "ffn_down.weight",
+        "input_layernorm.weight" => "attn_norm.weight",
+        "post_attention_layernorm.weight" => "ffn_norm.weight",
+        _ => return None,
+    };
+    Some(format!("blk.{index}.{tensor}"))
+}
+
+fn is_float_tensor(gguf_name: &str) -> bool {
+    gguf_name == "token_embd.weight" || gguf_name.ends_with("_norm.weight")
+}
+
+/// `dtype` when the rows of `tensor` are made of whole blocks of it, otherwise the closest type
+/// that fits.
+fn block_dtype(tensor: &Tensor, dtype: GgmlDType) -> GgmlDType {
+    let row_length = tensor.dims().last().copied().unwrap_or_default();
+    [dtype, GgmlDType::Q8_0, GgmlDType::F32]
+        .into_iter()
+        .find(|dtype| row_length % dtype.block_size() == 0)
+        .unwrap_or(GgmlDType::F32)
+}
+
+/// Reorders the rows of a query or key projection so the rotated pairs are interleaved, the
+/// layout llama.cpp GGUF files use.
+fn permute_rotary(weight: &Tensor, number_of_heads: usize) -> candle_core::Result<Tensor> {
+    let (rows, columns) = weight.dims2()?;
+    weight
+        .reshape((number_of_heads, 2, rows / number_of_heads / 2, columns))?
+        .transpose(1, 2)?
+        .contiguous()?
+        .reshape((rows, columns))
+}
+
+/// Hyperparameters of the llama architecture, as read back by `quantized_mistral::Config`.
+fn gguf_metadata(config: &mistral::Config) -> Vec<(String, gguf_file::Value)> {
+    use gguf_file::Value;
+    let head_dim = config.hidden_size / config.num_attention_heads;
+    [
+        ("general.architecture", Value::String("llama".to_owned())),
+        (
+            "llama.context_length",
+            Value::U32(config.max_position_embeddings as u32),
+        ),
+        (
+            "llama.embedding_length",
+            Value::U32(config.hidden_size as u32),
+        ),
+        (
+            "llama.feed_forward_length",
+            Value::U32(config.intermediate_size as u32),
+        ),
+        (
+            "llama.block_count",
+            Value::U32(config.num_hidden_layers as u32),
+        ),
+        (
+            "llama.attention.head_count",
+            Value::U32(config.num_attention_heads as u32),
+        ),
+        (
+            "llama.attention.head_count_kv",
+            Value::U32(config.num_key_value_heads as u32),
+        ),
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            Value::F32(config.rms_norm_eps as f32),
+        ),
+        ("llama.rope.freq_base", Value::F32(config.rope_theta as f32)),
+        ("llama.rope.dimension_count", Value::U32(head_dim as u32)),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_owned(), value))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Model, ModelBackend, ModelConfig, TinyMistral, TokenizedBatch};
+
+    fn last_logits(model: &mut Model, token_ids: &[u32]) -> crate::Result<Vec<f32>> {
+        let device = model.device();
+        let mut batch = TokenizedBatch {
+            requests: Default::default(),
+            token_ids: vec![token_ids.to_vec()],
+            input_ids: Tensor::new(vec![token_ids], &device)?,
+            attention_mask: Tensor::zeros((1, token_ids.len()), DType::F32, &device)?,
+            past_key_values: None,
+            pad_id: 0,
+        };
+        Ok(model.forward(&mut batch)?.flatten_all()?.to_vec1()?)
+    }
+
+    /// Writes `weights` next to the fixture checkpoint and loads them back as a GGUF model.
+    fn gguf_model(
+        fixture: &TinyMistral,
+        weights: &QuantizedWeights,
+        file_name: &str,
+    ) -> crate::Result<Model> {
+        let gguf_path = fixture.directory.join(file_name);
+        weights.write(&mut std::fs::File::create(&gguf_path)?)?;
+        let gguf_config = ModelConfig {
+            model_path: Some(gguf_path.clone()),
+            ..fixture.model_config()
+        };
+        Ok(Model::from_files(
+            gguf_config,
+            ModelFiles::from_gguf_file(&gguf_path),
+        )?)
+    }
+
+    fn max_abs_difference(lhs: &[f32], rhs: &[f32]) -> f32 {
+        lhs.iter()
+            .zip(rhs.iter())
+            .fold(0f32, |max, (lhs, rhs)| max.max((lhs - rhs).abs()))
+    }
+
+    #[test]
+    fn unquantized_conversion_matches_the_original() -> crate::Result<()> {
+        let fixture = TinyMistral::new()?;
+        let files = ModelFiles::from_dir(&fixture.directory, None)?;
+        let config: mistral::Config = files.load_config()?;
+        let token_ids = [1, 7, 19, 4, 30, 2];
+        let mut original = Model::from_files(fixture.model_config(), files.clone())?;
+        let expected = last_logits(&mut original, &token_ids)?;
+
+        // Without quantization only the names and the rotary layout change, the logits must
+        // match up to the float rounding.
+        let mut weights = QuantizedWeights::convert_safetensors(&files, &config, GgmlDType::F32)?;
+        let mut converted = gguf_model(&fixture, &weights, "tiny-mistral.F32.gguf")?;
+        let logits = last_logits(&mut converted, &token_ids)?;
+        assert!(max_abs_difference(&expected, &logits) < 1e-5);
+
+        // The query and key projections left in the Hugging Face layout pair the wrong rows.
+        let safetensors = candle_core::safetensors::load(&files.weights[0], &Device::Cpu)?;
+        for (gguf_name, tensor) in weights.tensors.iter_mut() {
+            let name = match gguf_name.strip_suffix(".attn_q.weight") {
+                Some(layer) => format!("{}.self_attn.q_proj.weight", layer),
+                None => match gguf_name.strip_suffix(".attn_k.weight") {
+                    Some(layer) => format!("{}.self_attn.k_proj.weight", layer),
+                    None => continue,
+                },
+            };
+            let name = name.replace("blk.", "model.layers.");
+            *tensor = QTensor::quantize(&safetensors[&name], GgmlDType::F32)?;
+        }
+        let mut unpermuted = gguf_model(&fixture, &weights, "tiny-mistral.unpermuted.gguf")?;
+        let logits = last_logits(&mut unpermuted, &token_ids)?;
+        assert!(max_abs_difference(&expected, &logits) > 0.1);
+        Ok(())
+    }
+
+    #[test]
+    fn converted_model_logits_match_the_original() -> crate::Result<()> {
+        let fixture = TinyMistral::new()?;
+        let files = ModelFiles::from_dir(&fixture.directory, None)?;
+        let weights = QuantizedWeights::from_model_files(&files, Quantization::Q8_0)?;
+        let mut converted = gguf_model(&fixture, &weights, "tiny-mistral.Q8_0.gguf")?;
+        let mut original = Model::from_files(fixture.model_config(), files)?;
+        assert_logits_match(&mut original, &mut converted)
+    }
+
+    #[test]
+    fn quantized_loading_matches_the_original() -> crate::Result<()> {
+        let fixture = TinyMistral::new()?;
+        let files = ModelFiles::from_dir(&fixture.directory, None)?;
+        let mut original = Model::from_files(fixture.model_config(), files.clone())?;
+        let quantized_config = ModelConfig {
+            load_quantization: Some(Quantization::Q8_0),
+            ..fixture.model_config()
+        };
+        let mut quantized = Model::from_files(quantized_config, files)?;
+        assert_logits_match(&mut original, &mut quantized)
+    }
+
+    fn assert_logits_match(original: &mut Model, quantized: &mut Model) -> crate::Result<()> {
+        let token_ids = [1, 7, 19, 4, 30, 2];
+        let expected = last_logits(original, &token_ids)?;
+        let logits = last_logits(quantized, &token_ids)?;
+
+        let scale = expected
+            .iter()
+            .fold(0f32, |max, logit| max.max(logit.abs()));
+        let error = max_abs_difference(&expected, &logits);
+        assert_eq!(logits.len(), TinyMistral::VOCAB.len());
+        // Both the Q8_0 weights and the activations the Q8_0 matmuls quantize move the small
+        // logits of random weights by a few percent. The layout is checked exactly by
+        // `unquantized_conversion_matches_the_original`.
+        assert!(
+            error <= 0.1 * scale,
+            "max logit error {error} for logits up to {scale}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn maps_hugging_face_names_to_llama_cpp_ones() {
+        assert_eq!(
+            gguf_tensor_name("model.layers.3.self_attn.o_proj.weight").as_deref(),
+            Some("blk.3.attn_output.weight")
+        );
+        assert_eq!(
+            gguf_tensor_name("model.layers.0.post_attention_layernorm.weight").as_deref(),
+            Some("blk.0.ffn_norm.weight")
+        );
+        assert_eq!(
+            gguf_tensor_name("lm_head.weight").as_deref(),
+            Some("output.weight")
+        );
+        assert_eq!(
+            gguf_tensor_name("model.layers.0.self_attn.rotary_emb.inv_freq"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_quantizations() {
+        assert_eq!("q8_0".parse::<Quantization>().unwrap(), Quantization::Q8_0);
+        assert_eq!("Q4_K".parse::<Quantization>().unwrap(), Quantization::Q4K);
+        assert!("Q3_K".parse::<Quantization>().is_err());
+    }
+
+    #[test]
+    fn falls_back_when_rows_do_not_fit_the_blocks() -> candle_core::Result<()> {
+        let tensor = Tensor::zeros((4, 64), DType::F32, &Device::Cpu)?;
+        assert_eq!(block_dtype(&tensor, GgmlDType::Q4K), GgmlDType::Q8_0);
+        let tensor = Tensor::zeros((4, 256), DType::F32, &Device::Cpu)?;
+        assert_eq!(block_dtype(&tensor, GgmlDType::Q4K), GgmlDType::Q4K);
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+use super::models::{llama, mistral, phi3, quantized_mistral, qwen2};
+use super::{Quantization, QuantizedWeights};
+use crate::{Architecture, GgufFile, ModelError, ModelFiles, ModelResult};
+
+/// How the weights of a model are loaded.
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    pub device: candle_core::Device,
+    pub dtype: candle_core::DType,
+    /// Read the quantized weights of a GGUF file.
+    pub quantize: bool,
+    /// Quantize the safetensors weights while loading them.
+    pub quantization: Option<Quantization>,
+}
+
+/// A network built from the files of a model, with the hyperparameters the pipeline needs.
+#[derive(Debug)]
+pub struct LoadedArchitecture {
+    pub network: Box<dyn Architecture>,
+    pub vocab_size: usize,
+    pub context_length: usize,
+}
+
+pub type ArchitectureLoader = fn(&ModelFiles, &LoadOptions) -> ModelResult<LoadedArchitecture>;
+
+/// A model family the server can load, matched against the `architectures` and `model_type`
+/// fields of a model's `config.json`, or the `general.architecture` of its GGUF file.
+#[derive(Debug, Clone, Copy)]
+pub struct ArchitectureEntry {
+    pub name: &'static str,
+    pub architectures: &'static [&'static str],
+    pub model_types: &'static [&'static str],
+    /// GGUF architectures the family loads quantized, empty when it only loads safetensors.
+    pub gguf_architectures: &'static [&'static str],
+    pub load: ArchitectureLoader,
+}
+
+/// Every supported model family. Adding one only takes a new entry here.
+pub static ARCHITECTURES: &[ArchitectureEntry] = &[
+    ArchitectureEntry {
+        name: "mistral",
+        architectures: &["MistralForCausalLM"],
+        model_types: &["mistral"],
+        gguf_architectures: &["llama"],
+        load: load_mistral,
+    },
+    ArchitectureEntry {
+        name: "mixtral",
+        architectures: &["MixtralForCausalLM"],
+        model_types: &["mixtral"],
+        gguf_architectures: &[],
+        load: load_mixtral,
+    },
+    ArchitectureEntry {
+        name: "llama",
+        architectures: &["LlamaForCausalLM"],
+        model_types: &["llama"],
+        gguf_architectures: &[],
+        load: load_llama,
+    },
+    ArchitectureEntry {
+        name: "qwen2",
+        architectures: &["Qwen2ForCausalLM"],
+        model_types: &["qwen2"],
+        gguf_architectures: &[],
+        load: load_qwen2,
+    },
+    ArchitectureEntry {
+        name: "phi3",
+        architectures: &["Phi3ForCausalLM"],
+        model_types: &["phi3"],
+        gguf_architectures: &[],
+        load: load_phi3,
+    },
+];
+
+impl ArchitectureEntry {
+    /// Finds the entry of the model described by `config`, trying its `architectures` before its
+    /// `model_type`.
+    pub fn detect(config: &serde_json::Value) -> ModelResult<&'static ArchitectureEntry> {
+        let architectures: Vec<&str> = config
+            .get("architectures")
+            .and_then(|architectures| architectures.as_array())
+            .map(|architectures| {
+                architectures
+                    .iter()
+                    .filter_map(|architecture| architecture.as_str())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let model_type = config
+            .get("model_type")
+            .and_then(|model_type| model_type.as_str());
+
+        let by_architecture = architectures.iter().find_map(|architecture| {
+            ARCHITECTURES
+                .iter()
+                .find(|entry| entry.architectures.contains(architecture))
+        });
+        let by_model_type = || {
+            model_type.and_then(|model_type| {
+                ARCHITECTURES
+                    .iter()
+                    .find(|entry| entry.model_types.contains(&model_type))
+            })
+        };
+        by_architecture
+            .or_else(by_model_type)
+            .ok_or_else(|| ModelError::UnsupportedArchitecture {
+                architectures: architectures
+                    .iter()
+                    .map(|value| value.to_string())
+                    .collect(),
+                model_type: model_type.map(|value| value.to_owned()),
+            })
+    }
+}
+
+impl ArchitectureEntry {
+    /// Finds the entry loading the quantized models of a GGUF `general.architecture`.
+    pub fn detect_gguf(architecture: &str) -> ModelResult<&'static ArchitectureEntry> {
+        ARCHITECTURES
+            .iter()
+            .find(|entry| entry.gguf_architectures.contains(&architecture))
+            .ok_or_else(|| ModelError::UnsupportedArchitecture {
+                architectures: vec![architecture.to_owned()],
+                model_type: None,
+            })
+    }
+}
+
+fn load_mistral(files: &ModelFiles, options: &LoadOptions) -> ModelResult<LoadedArchitecture> {
+    if options.quantize {
+        let gguf = GgufFile::open(files.gguf_file()?)?;
+        let config = quantized_mistral::Config::from_gguf(&gguf)?;
+        tracing::debug!("Model config: {:?}", &config);
+        let vars = quantized_mistral::VarBuilder::from_gguf(&gguf.path, &options.device)?;
+        let network = quantized_mistral::Model::new(&config, vars)?;
+        Ok(LoadedArchitecture {
+            network: Box::new(network),
+            vocab_size: config.vocab_size,
+            context_length: config.max_position_embeddings,
+        })
+    } else if let Some(quantization) = options.quantization {
+        let config: mistral::Config = files.load_config()?;
+        tracing::debug!("Model config: {:?}", &config);
+        let weights = QuantizedWeights::from_safetensors(files, &config, quantization)?;
+        let network = quantized_mistral::Model::new(
+            &quantized_mistral::Config::from(&config),
+            weights.into_var_builder(&options.device)?,
+        )?;
+        Ok(LoadedArchitecture {
+            network: Box::new(network),
+            vocab_size: config.vocab_size,
+            context_length: config.max_position_embeddings,
+        })
+    } else {
+        let config: mistral::Config = files.load_config()?;
+        tracing::debug!("Model config: {:?}", &config);
+        let vars = files.safetensors_var_builder(options)?;
+        let network = mistral::Model::new(&config, vars)?;
+        Ok(LoadedArchitecture {
+            network: Box::new(network),
+            vocab_size: config.vocab_size,
+            context_length: config.max_position_embeddings,
+        })
+    }
+}
+
+/// Mixtral shares the Mistral network, its layers get a sparse mixture of experts in place of the
+/// dense MLP.
+fn load_mixtral(files: &ModelFiles, options: &LoadOptions) -> ModelResult<LoadedArchitecture> {
+    if options.quantize || options.quantization.is_some() {
+        return Err(ModelError::UnsupportedQuantization {
+            architecture: "mixtral",
+        });
+    }
+    let config: mistral::Config = files.load_config()?;
+    tracing::debug!("Model config: {:?}", &config);
+    let vars = files.safetensors_var_builder(options)?;
+    let network = mistral::Model::new(&config, vars)?;
+    Ok(LoadedArchitecture {
+        network: Box::new(network),
+        vocab_size: config.vocab_size,
+        context_length: config.max_position_embeddings,
+    })
+}
+
+fn load_llama(files: &ModelFiles, options: &LoadOptions) -> ModelResult<LoadedArchitecture> {
+    if options.quantize || options.quantization.is_some() {
+        return Err(ModelError::UnsupportedQuantization {
+            architecture: "llama",
+        });
+    }
+    let config: llama::Config = files.load_config()?;
+    tracing::debug!("Model config: {:?}", &config);
+    config.check_rope_scaling()?;
+    let vars = files.safetensors_var_builder(options)?;
+    let network = llama::Model::new(&config, vars)?;
+    Ok(LoadedArchitecture {
+        network: Box::new(network),
+        vocab_size: config.vocab_size,
+        context_length: config.max_position_embeddings,
+    })
+}
+
+fn load_qwen2(files: &ModelFiles, options: &LoadOptions) -> ModelResult<LoadedArchitecture> {
+    if options.quantize || options.quantization.is_some() {
+        return Err(ModelError::UnsupportedQuantization {
+            architecture: "qwen2",
+        });
+    }
+    let config: qwen2::Config = files.load_config()?;
+    tracing::debug!("Model config: {:?}", &config);
+    let vars = files.safetensors_var_builder(options)?;
+    let network = qwen2::Model::new(&config, vars)?;
+    Ok(LoadedArchitecture {
+        network: Box::new(network),
+        vocab_size: config.vocab_size,
+        context_length: config.max_position_embeddings,
+    })
+}
+
+fn load_phi3(files: &ModelFiles, options: &LoadOptions) -> ModelResult<LoadedArchitecture> {
+    if options.quantize || options.quantization.is_some() {
+        return Err(ModelError::UnsupportedQuantization {
+            architecture: "phi3",
+        });
+    }
+    let config: phi3::Config = files.load_config()?;
+    tracing::debug!("Model config: {:?}", &config);
+    let vars = files.safetensors_var_builder(options)?;
+    let network = phi3::Model::new(&config, vars)?;
+    Ok(LoadedArchitecture {
+        network: Box::new(network),
+        vocab_size: config.vocab_size,
+        context_length: config.max_position_embeddings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_the_architecture_before_the_model_type() {
+        let config = serde_json::json!({
+            "architectures": ["MistralForCausalLM"],
+            "model_type": "unknown",
+        });
+        assert_eq!(ArchitectureEntry::detect(&config).unwrap().name, "mistral");
+    }
+
+    #[test]
+    fn falls_back_to_the_model_type() {
+        let config = serde_json::json!({ "model_type": "mistral" });
+        assert_eq!(ArchitectureEntry::detect(&config).unwrap().name, "mistral");
+    }
+
+    #[test]
+    fn detects_every_registered_family() {
+        for (architecture, name) in [
+            ("MixtralForCausalLM", "mixtral"),
+            ("LlamaForCausalLM", "llama"),
+            ("Qwen2ForCausalLM", "qwen2"),
+            ("Phi3ForCausalLM", "phi3"),
+        ] {
+            let config = serde_json::json!({ "architectures": [architecture] });
+            assert_eq!(ArchitectureEntry::detect(&config).unwrap().name, name);
+        }
+    }
+
+    #[test]
+    fn detects_gguf_architectures() {
+        assert_eq!(
+            ArchitectureEntry::detect_gguf("llama").unwrap().name,
+            "mistral"
+        );
+        assert!(ArchitectureEntry::detect_gguf("qwen2").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_architectures() {
+        let config = serde_json::json!({
+            "architectures": ["GPT2LMHeadModel"],
+            "model_type": "gpt2",
+        });
+        assert!(matches!(
+            ArchitectureEntry::detect(&config),
+            Err(ModelError::UnsupportedArchitecture { .. })
+        ));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::{KeyValueCache, ModelBackend, ModelMetaData, ModelResult, TokenizedBatch};
+use candle_core::{DType, Tensor};
+
+/// A fake backend that generates a fixed script of tokens, used to run the generation pipeline
+/// without loading weights.
+///
+/// Every request generates `script[0]`, `script[1]`, ... and then `eos_id` forever. The logits
+/// put all the probability on the scripted token, so the output does not depend on the sampling
+/// parameters. The key/value cache holds a single layer of zeros, enough for the batching code
+/// to track, merge and filter it like a real one.
+#[derive(Debug, Clone)]
+pub struct ScriptedModel {
+    script: Vec<u32>,
+    eos_id: u32,
+    vocab_size: usize,
+    context_length: usize,
+    device: candle_core::Device,
+}
+
+impl ScriptedModel {
+    pub fn new(script: Vec<u32>, eos_id: u32, vocab_size: usize) -> Self {
+        Self {
+            script,
+            eos_id,
+            vocab_size,
+            context_length: 4096,
+            device: candle_core::Device::Cpu,
+        }
+    }
+
+    pub fn with_context_length(mut self, context_length: usize) -> Self {
+        self.context_length = context_length;
+        self
+    }
+
+    /// Token generated at step `step` of a request.
+    pub fn token_at(&self, step: usize) -> u32 {
+        self.script.get(step).copied().unwrap_or(self.eos_id)
+    }
+}
+
+impl ModelBackend for ScriptedModel {
+    fn forward(&mut self, batch: &mut TokenizedBatch) -> ModelResult<Tensor> {
+        let (number_of_rows, input_length) = batch.input_ids.dims2()?;
+        let mut logits = vec![f32::NEG_INFINITY; number_of_rows * self.vocab_size];
+        let mut row = 0;
+        for request in batch.requests.values() {
+            let token_id = self.token_at(request.number_tokens_generated as usize) as usize;
+            for _ in 0..request.number_of_rows() {
+                if token_id < self.vocab_size {
+                    logits[row * self.vocab_size + token_id] = 0.;
+                }
+                row += 1;
+            }
+        }
+
+        let added = Tensor::zeros(
+            (number_of_rows, 1, input_length, 1),
+            DType::F32,
+            &self.device,
+        )?;
+        let sequence_length = match &batch.past_key_values {
+            Some(cache) => cache.sequence_length()? + input_length,
+            None => input_length,
+        };
+        if sequence_length > self.context_length {
+            // Like the rotary embeddings of the real models, which only cover the context.
+            return Err(candle_core::Error::Msg(format!(
+                "{sequence_length} positions do not fit the context of {} positions",
+                self.context_length
+            ))
+            .into());
+        }
+        let layer = match batch
+            .past_key_values
+            .take()
+            .and_then(|cache| cache.into_layers().into_iter().next().flatten())
+        {
+            Some((key, value)) => (
+                Tensor::cat(&[&key, &added], 2)?,
+                Tensor::cat(&[&value, &added], 2)?,
+            ),
+            None => (added.clone(), added),
+        };
+        batch.past_key_values = Some(KeyValueCache::new(vec![Some(layer)]));
+
+        Ok(Tensor::from_vec(
+            logits,
+            (number_of_rows, 1, self.vocab_size),
+            &self.device,
+        )?)
+    }
+
+    fn shift_key_value_cache(
+        &self,
+        cache: KeyValueCache,
+        positions: usize,
+    ) -> ModelResult<KeyValueCache> {
+        let sequence_length = cache.sequence_length()? + positions;
+        cache.left_pad(sequence_length)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    fn context_length(&self) -> usize {
+        self.context_length
+    }
+
+    fn meta_data(&self) -> ModelMetaData {
+        ModelMetaData {
+            device: "cpu".to_owned(),
+            dtype: format!("{:?}", DType::F32),
+            model_type: "scripted".to_owned(),
+            number_of_devices: 1,
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{ModelConfig, Result, TokenizerError};
+use candle_core::{DType, Device, Tensor};
+use huggingface_tokenizers::models::wordlevel::WordLevel;
+use huggingface_tokenizers::pre_tokenizers::whitespace::WhitespaceSplit;
+use huggingface_tokenizers::AddedToken;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// A two layer Mistral model with random weights and a word level tokenizer, written to a
+/// temporary directory laid out like a hub repo. Small enough to load and generate with on the
+/// CPU, so the whole stack can be tested without network or GPU.
+///
+/// The directory is removed when the fixture is dropped.
+#[derive(Debug)]
+pub struct TinyMistral {
+    pub directory: PathBuf,
+}
+
+impl TinyMistral {
+    pub const HIDDEN_SIZE: usize = 64;
+    pub const INTERMEDIATE_SIZE: usize = 128;
+    pub const MAX_POSITION_EMBEDDINGS: usize = 64;
+    /// Every token of the tokenizer, the token id is the index.
+    pub const VOCAB: [&'static str; 32] = [
+        "<unk>", "<s>", "</s>", "hello", "world", "how", "are", "you", "the", "a", "tiny", "model",
+        "is", "fast", "what", "time", "it", "on", "cpu", "test", "one", "two", "three", "four",
+        "five", "six", "seven", "eight", "nine", "ten", "yes", "no",
+    ];
+    pub const BOS_ID: u32 = 1;
+    pub const EOS_ID: u32 = 2;
+    pub const CHAT_TEMPLATE: &'static str =
+        "{{ bos_token }}{% for message in messages %}{{ message['content'] }} {% endfor %}";
+
+    pub fn new() -> Result<Self> {
+        let directory = std::env::temp_dir().join(format!("tiny-mistral-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory)?;
+        let fixture = Self { directory };
+        fixture.write_config()?;
+        fixture.write_weights()?;
+        fixture.write_tokenizer()?;
+        Ok(fixture)
+    }
+
+    /// Loads the model on the CPU in F32.
+    pub fn model_config(&self) -> ModelConfig {
+        ModelConfig {
+            model_id: "tiny-mistral".to_owned(),
+            device: crate::DeviceConfig::Cpu,
+            dtype: Some(DType::F32),
+            quantize: false,
+            quant_variant: None,
+            load_quantization: None,
+            model_path: Some(self.directory.clone()),
+            adapters: Vec::new(),
+        }
+    }
+
+    fn write_config(&self) -> Result<()> {
+        let config = serde_json::json!({
+            "architectures": ["MistralForCausalLM"],
+            "model_type": "mistral",
+            "vocab_size": Self::VOCAB.len(),
+            "hidden_size": Self::HIDDEN_SIZE,
+            "intermediate_size": Self::INTERMEDIATE_SIZE,
+            "num_hidden_layers": 2,
+            "num_attention_heads": 4,
+            "num_key_value_heads": 2,
+            "hidden_act": "silu",
+            "max_position_embeddings": Self::MAX_POSITION_EMBEDDINGS,
+            "rms_norm_eps": 1e-5,
+            "rope_theta": 10000.0,
+            "sliding_window": null,
+            "bos_token_id": Self::BOS_ID,
+            "eos_token_id": Self::EOS_ID,
+        });
+        std::fs::write(self.directory.join("config.json"), config.to_string())?;
+        Ok(())
+    }
+
+    fn write_weights(&self) -> Result<()> {
+        let (vocab_size, hidden_size, intermediate_size) = (
+            Self::VOCAB.len(),
+            Self::HIDDEN_SIZE,
+            Self::INTERMEDIATE_SIZE,
+        );
+        let random = |shape: (usize, usize)| Tensor::randn(0f32, 0.1, shape, &Device::Cpu);
+        let ones = || Tensor::ones(hidden_size, DType::F32, &Device::Cpu);
+        let mut tensors: HashMap<String, Tensor> = HashMap::new();
+        tensors.insert(
+            "model.embed_tokens.weight".to_owned(),
+            random((vocab_size, hidden_size))?,
+        );
+        tensors.insert("model.norm.weight".to_owned(), ones()?);
+        tensors.insert(
+            "lm_head.weight".to_owned(),
+            random((vocab_size, hidden_size))?,
+        );
+        for layer in 0..2 {
+            let prefix = format!("model.layers.{layer}");
+            for (name, shape) in [
+                ("self_attn.q_proj", (hidden_size, hidden_size)),
+                ("self_attn.k_proj", (hidden_size / 2, hidden_size)),
+                ("self_attn.v_proj", (hidden_size / 2, hidden_size)),
+                ("self_attn.o_proj", (hidden_size, hidden_size)),
+                ("mlp.gate_proj", (intermediate_size, hidden_size)),
+                ("mlp.up_proj", (intermediate_size, hidden_size)),
+                ("mlp.down_proj", (hidden_size, intermediate_size)),
+            ] {
+                tensors.insert(format!("{prefix}.{name}.weight"), random(shape)?);
+            }
+            tensors.insert(format!("{prefix}.input_layernorm.weight"), ones()?);
+            tensors.insert(format!("{prefix}.post_attention_layernorm.weight"), ones()?);
+        }
+        candle_core::safetensors::save(&tensors, self.directory.join("model.safetensors"))?;
+        Ok(())
+    }
+
+    fn write_tokenizer(&self) -> Result<()> {
+        let vocab = Self::VOCAB
+            .iter()
+            .enumerate()
+            .map(|(token_id, token)| (token.to_string(), token_id as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_owned())
+            .build()
+            .map_err(TokenizerError::from)?;
+        let mut tokenizer = huggingface_tokenizers::Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(WhitespaceSplit);
+        tokenizer.add_special_tokens(&[
+            AddedToken::from("<s>", true),
+            AddedToken::from("</s>", true),
+        ]);
+        tokenizer
+            .save(self.directory.join("tokenizer.json"), false)
+            .map_err(TokenizerError::from)?;
+
+        let config = serde_json::json!({
+            "bos_token": "<s>",
+            "eos_token": "</s>",
+            "unk_token": "<unk>",
+            "chat_template": Self::CHAT_TEMPLATE,
+        });
+        std::fs::write(
+            self.directory.join("tokenizer_config.json"),
+            config.to_string(),
+        )?;
+        Ok(())
+    }
+}
+
+impl Drop for TinyMistral {
+    fn drop(&mut self) {
+        if let Err(error) = std::fs::remove_dir_all(&self.directory) {
+            tracing::warn!("could not remove {:?}: {}", &self.directory, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        FinishReason, GenerationResult, Generator, Model, ModelBackend, ModelFiles, PromptConfig,
+        TextGeneration, Tokenizer,
+    };
+
+    #[test]
+    fn loads_the_model_and_the_tokenizer() -> Result<()> {
+        let fixture = TinyMistral::new()?;
+        let files = ModelFiles::from_dir(&fixture.directory, None)?;
+        let model = Model::from_files(fixture.model_config(), files)?;
+        assert_eq!(model.eos_token_ids(), vec![TinyMistral::EOS_ID]);
+
+        let tokenizer = Tokenizer::from_model_config(&fixture.model_config())?;
+        let template = tokenizer.template.as_ref().expect("missing chat template");
+        assert_eq!(template.get_template(), TinyMistral::CHAT_TEMPLATE);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn generates_on_the_cpu() -> Result<()> {
+        let fixture = TinyMistral::new()?;
+        let generation = TextGeneration::new(fixture.model_config())?;
+        let generator = Generator::new(generation, Default::default()).await;
+        let mut prompt: crate::Prompt = "how are you".to_owned().into();
+        prompt.config = PromptConfig {
+            max_new_tokens: 4,
+            ..Default::default()
+        };
+        let mut receiver = generator.prompt(prompt).await?;
+
+        let mut results: Vec<GenerationResult> = Vec::new();
+        while let Some(result) = receiver.recv().await {
+            let is_end_of_sequence = result.is_end_of_sequence;
+            results.push(result);
+            if is_end_of_sequence {
+                break;
+            }
+        }
+        let last = results.last().expect("no generation result");
+        assert!(last.is_end_of_sequence);
+        assert!(matches!(
+            last.finish_reason,
+            Some(FinishReason::Length | FinishReason::Eos)
+        ));
+        let usage = last.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 3);
+        assert!((1..=4).contains(&usage.completion_tokens));
+        Ok(())
+    }
+}
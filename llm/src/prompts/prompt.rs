@@ -1,11 +1,12 @@
 use super::prompt_config::PromptConfig;
-use uuid;
 
 #[derive(Debug)]
 pub struct Prompt {
     pub id: String,
     pub content: String,
     pub config: PromptConfig,
+    /// LoRA adapter to generate with, the base model when `None`.
+    pub adapter: Option<String>,
 }
 
 impl Prompt {
@@ -20,6 +21,7 @@ impl From<String> for Prompt {
             id: Prompt::gen_id(),
             content,
             config: PromptConfig::default(),
+            adapter: None,
         }
     }
 }
@@ -30,6 +32,7 @@ impl From<&'static str> for Prompt {
             id: Prompt::gen_id(),
             content: content.to_owned(),
             config: PromptConfig::default(),
+            adapter: None,
         }
     }
 }
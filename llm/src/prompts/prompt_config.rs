@@ -1,26 +1,80 @@
+use crate::{Error, Result};
 use rand::Rng;
 
 #[derive(Debug, Clone)]
 pub struct PromptConfig {
     pub max_new_tokens: i32,
     pub num_beams: Option<i32>,
+    pub num_return_sequences: Option<i32>,
+    pub length_penalty: Option<f32>,
+    pub early_stopping: bool,
     pub temperature: Option<f64>,
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
     pub repetition_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub penalty_window: Option<usize>,
+    pub stop: Vec<String>,
+    pub stop_token_ids: Vec<u32>,
+    pub include_stop: bool,
+    /// Number of alternatives returned with the log probability of each sampled token, `None`
+    /// to not compute log probabilities.
+    pub logprobs: Option<usize>,
     pub seed: u64,
 }
 
+impl PromptConfig {
+    /// Most alternatives returned with each sampled token, every one of them is decoded.
+    pub const MAX_LOGPROBS: usize = 20;
+
+    /// Rejects the configs a batch can not run: beams take a row of the batch each, and
+    /// alternatives are decoded on every generated token.
+    pub fn validate(&self, max_batch_size: usize) -> Result<()> {
+        let invalid = |message: String| Err(Error::InvalidPromptConfig { message });
+        for (name, value) in [
+            ("num_beams", self.num_beams),
+            ("num_return_sequences", self.num_return_sequences),
+        ] {
+            if let Some(value) = value.filter(|value| *value as usize > max_batch_size) {
+                return invalid(format!(
+                    "{name} {value} is above the maximum batch size {max_batch_size}"
+                ));
+            }
+        }
+        if let Some(logprobs) = self
+            .logprobs
+            .filter(|logprobs| *logprobs > Self::MAX_LOGPROBS)
+        {
+            return invalid(format!(
+                "logprobs {logprobs} is above the maximum of {}",
+                Self::MAX_LOGPROBS
+            ));
+        }
+        Ok(())
+    }
+}
+
 impl Default for PromptConfig {
     fn default() -> Self {
         let mut rng = rand::thread_rng();
         Self {
             max_new_tokens: 100,
             num_beams: Default::default(),
+            num_return_sequences: Default::default(),
+            length_penalty: Default::default(),
+            early_stopping: Default::default(),
             temperature: Default::default(),
             top_k: Default::default(),
             top_p: Default::default(),
             repetition_penalty: Default::default(),
+            frequency_penalty: Default::default(),
+            presence_penalty: Default::default(),
+            penalty_window: Default::default(),
+            stop: Default::default(),
+            stop_token_ids: Default::default(),
+            include_stop: Default::default(),
+            logprobs: Default::default(),
             seed: rng.gen(),
         }
     }
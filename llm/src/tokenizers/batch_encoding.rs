@@ -10,7 +10,7 @@ pub struct BatchEncoding {
 
 impl BatchEncoding {
     pub fn token_length(&self) -> usize {
-        return self.ids.shape().dims()[1];
+        self.ids.shape().dims()[1]
     }
 
     pub fn append_tokens(&mut self, next_tokens: &Tensor) -> Result<()> {
@@ -14,4 +14,15 @@ pub enum TokenizerError {
     JsonError(#[from] serde_json::Error),
     #[error(transparent)]
     TokenizerError(#[from] huggingface_tokenizers::Error),
+    #[error(transparent)]
+    ModelError(#[from] Box<crate::ModelError>),
+    #[error("Missing {key} in the GGUF tokenizer metadata")]
+    MissingGgufMetadata { key: String },
+    #[error("Unsupported GGUF tokenizer model: {0}")]
+    UnsupportedGgufTokenizer(String),
+    #[error("Missing {file} in tokenizer directory {directory:?}")]
+    MissingFile {
+        directory: std::path::PathBuf,
+        file: String,
+    },
 }
@@ -0,0 +1,521 @@
+use super::template::ChatTemplate;
+use super::{Tokenizer, TokenizerError, TokenizerResult};
+use candle_core::quantized::gguf_file;
+use std::collections::HashMap;
+use std::str::FromStr;
+use tokenizers::decoders::byte_fallback::ByteFallback;
+use tokenizers::decoders::byte_level::ByteLevel;
+use tokenizers::decoders::fuse::Fuse;
+use tokenizers::decoders::sequence::Sequence as DecoderSequence;
+use tokenizers::decoders::strip::Strip;
+use tokenizers::decoders::DecoderWrapper;
+use tokenizers::models::bpe::BPE;
+use tokenizers::normalizers::{Prepend, Replace, Sequence as NormalizerSequence};
+use tokenizers::AddedToken;
+
+/// `tokenizer.ggml.token_type` of the regular vocabulary tokens.
+const NORMAL_TOKEN_TYPE: i32 = 1;
+/// `tokenizer.ggml.token_type` of the unknown token.
+const UNKNOWN_TOKEN_TYPE: i32 = 2;
+/// `tokenizer.ggml.token_type` of the control tokens, `<s>`, `</s>`, `<|im_end|>`, ...
+const CONTROL_TOKEN_TYPE: i32 = 3;
+/// `tokenizer.ggml.token_type` of the added tokens that are not special.
+const USER_DEFINED_TOKEN_TYPE: i32 = 4;
+/// `tokenizer.ggml.token_type` of the `<0x0A>` byte fallback tokens.
+const BYTE_TOKEN_TYPE: i32 = 6;
+/// The whole Hugging Face `tokenizer.json`, the GGUF key is part of the specification.
+const HUGGING_FACE_JSON_KEY: &str = "tokenizer.huggingface.json";
+
+impl Tokenizer {
+    /// Builds the tokenizer stored in the metadata of a GGUF file.
+    ///
+    /// Files holding a `tokenizer.huggingface.json` are read from it as is. Otherwise the
+    /// tokenizer is rebuilt from the `tokenizer.ggml.*` vocabulary: `llama` vocabularies are
+    /// SentencePiece BPE ones with byte fallback, their merges are rebuilt from the token scores
+    /// like the Hugging Face conversion of SentencePiece models does. `gpt2` vocabularies are
+    /// byte level BPE ones with their merges stored in the file, pre-tokenized with the GPT-2
+    /// split pattern.
+    pub fn from_gguf(content: &gguf_file::Content) -> TokenizerResult<Self> {
+        let metadata = |key: &str| {
+            content
+                .metadata
+                .get(key)
+                .ok_or_else(|| TokenizerError::MissingGgufMetadata {
+                    key: key.to_owned(),
+                })
+        };
+        let tokens: Vec<String> = metadata("tokenizer.ggml.tokens")?
+            .to_vec()?
+            .iter()
+            .map(|token| token.to_string().cloned())
+            .collect::<candle_core::Result<_>>()?;
+        let token_types: Vec<i32> = match metadata("tokenizer.ggml.token_type") {
+            Ok(token_types) => token_types
+                .to_vec()?
+                .iter()
+                .map(|token_type| token_type.to_i32())
+                .collect::<candle_core::Result<_>>()?,
+            Err(_) => Vec::new(),
+        };
+        let token_id = |key: &str| -> TokenizerResult<Option<u32>> {
+            match content.metadata.get(key) {
+                Some(value) => Ok(Some(value.to_u32()?)),
+                None => Ok(None),
+            }
+        };
+        let token = |token_id: u32| {
+            tokens.get(token_id as usize).cloned().ok_or_else(|| {
+                TokenizerError::MissingGgufMetadata {
+                    key: format!("tokenizer.ggml.tokens[{token_id}]"),
+                }
+            })
+        };
+        let eos_token = token(token_id("tokenizer.ggml.eos_token_id")?.ok_or_else(|| {
+            TokenizerError::MissingGgufMetadata {
+                key: "tokenizer.ggml.eos_token_id".to_owned(),
+            }
+        })?)?;
+        let bos_token = match token_id("tokenizer.ggml.bos_token_id")? {
+            Some(bos_token_id) => token(bos_token_id)?,
+            None => eos_token.clone(),
+        };
+        let pad_token = match token_id("tokenizer.ggml.padding_token_id")? {
+            Some(pad_token_id) => token(pad_token_id)?,
+            None => eos_token.clone(),
+        };
+
+        let tokenizer = if let Some(json) = content.metadata.get(HUGGING_FACE_JSON_KEY) {
+            tracing::debug!("gguf tokenizer read from {HUGGING_FACE_JSON_KEY}");
+            tokenizers::Tokenizer::from_str(json.to_string()?)?
+        } else {
+            let vocab: HashMap<String, u32> = tokens
+                .iter()
+                .enumerate()
+                .map(|(token_id, token)| (token.clone(), token_id as u32))
+                .collect();
+            let tokenizer_model = metadata("tokenizer.ggml.model")?.to_string()?.as_str();
+            let mut tokenizer = match tokenizer_model {
+                "llama" => {
+                    let scores: Vec<f32> = metadata("tokenizer.ggml.scores")?
+                        .to_vec()?
+                        .iter()
+                        .map(|score| score.to_f32())
+                        .collect::<candle_core::Result<_>>()?;
+                    let merges = sentencepiece_merges(&vocab, &scores);
+                    let unk_token = token_id("tokenizer.ggml.unknown_token_id")?
+                        .map(token)
+                        .transpose()?
+                        .unwrap_or_else(|| "<unk>".to_owned());
+                    let bpe = BPE::builder()
+                        .vocab_and_merges(vocab, merges)
+                        .unk_token(unk_token)
+                        .fuse_unk(true)
+                        .byte_fallback(true)
+                        .build()?;
+                    sentencepiece_tokenizer(bpe)?
+                }
+                "gpt2" => {
+                    let merges = metadata("tokenizer.ggml.merges")?
+                        .to_vec()?
+                        .iter()
+                        .map(|merge| {
+                            let merge = merge.to_string()?;
+                            merge
+                                .split_once(' ')
+                                .map(|(left, right)| (left.to_owned(), right.to_owned()))
+                                .ok_or_else(|| {
+                                    candle_core::Error::Msg(format!("invalid gguf merge {merge:?}"))
+                                })
+                        })
+                        .collect::<candle_core::Result<_>>()?;
+                    let bpe = BPE::builder().vocab_and_merges(vocab, merges).build()?;
+                    let mut tokenizer = tokenizers::Tokenizer::new(bpe);
+                    tokenizer.with_pre_tokenizer(ByteLevel::new(false, true, true));
+                    tokenizer.with_decoder(ByteLevel::new(false, true, true));
+                    tokenizer
+                }
+                tokenizer_model => {
+                    return Err(TokenizerError::UnsupportedGgufTokenizer(
+                        tokenizer_model.to_owned(),
+                    ))
+                }
+            };
+            let control_tokens: Vec<AddedToken> = tokens
+                .iter()
+                .zip(token_types.iter())
+                .filter(|(_, token_type)| **token_type == CONTROL_TOKEN_TYPE)
+                .map(|(token, _)| AddedToken::from(token.clone(), true))
+                .collect();
+            tokenizer.add_special_tokens(&control_tokens);
+            tracing::debug!("gguf tokenizer model: {tokenizer_model}");
+            tokenizer
+        };
+
+        let chat_template = match content.metadata.get("tokenizer.chat_template") {
+            Some(template) => Some(ChatTemplate::new(
+                template.to_string()?.clone(),
+                Some(bos_token.clone()),
+                Some(eos_token.clone()),
+            )),
+            None => None,
+        };
+        Self::new(tokenizer, bos_token, eos_token, pad_token, chat_template)
+    }
+
+    /// The `tokenizer.*` metadata of a GGUF file holding this tokenizer: the vocabulary in the
+    /// llama.cpp layout, the special token ids, the chat template and the whole Hugging Face
+    /// `tokenizer.json`, which `from_gguf` reads back as is.
+    ///
+    /// BPE vocabularies with byte fallback are written as `llama` ones, scored so that the merges
+    /// rebuilt from the scores keep their order. Other BPE vocabularies are written as `gpt2`
+    /// ones with their merges, Unigram ones as `llama` ones with their own scores.
+    pub fn gguf_metadata(&self) -> TokenizerResult<Vec<(String, gguf_file::Value)>> {
+        use gguf_file::Value;
+        let vocab = self.inner.get_vocab(true);
+        let vocab_size = vocab
+            .values()
+            .max()
+            .map_or(0, |token_id| *token_id as usize + 1);
+        // llama.cpp expects contiguous ids, holes are filled the way its conversion script does.
+        let mut tokens: Vec<String> = (0..vocab_size)
+            .map(|token_id| format!("[PAD{token_id}]"))
+            .collect();
+        for (token, token_id) in vocab.into_iter() {
+            tokens[token_id as usize] = token;
+        }
+
+        let model = serde_json::to_value(self.inner.get_model())?;
+        let model_type = model
+            .get("type")
+            .and_then(|model_type| model_type.as_str())
+            .unwrap_or_default();
+        let byte_fallback = model
+            .get("byte_fallback")
+            .and_then(|byte_fallback| byte_fallback.as_bool())
+            .unwrap_or_default();
+        let unk_id = match model.get("unk_id").and_then(|unk_id| unk_id.as_u64()) {
+            Some(unk_id) => Some(unk_id as u32),
+            None => model
+                .get("unk_token")
+                .and_then(|unk_token| unk_token.as_str())
+                .and_then(|unk_token| self.get_token(unk_token)),
+        };
+        let (tokenizer_model, scores, merges) = match model_type {
+            "BPE" => {
+                let merges = bpe_merges(&model)?;
+                if byte_fallback {
+                    ("llama", Some(merge_scores(&tokens, &merges)), None)
+                } else {
+                    ("gpt2", None, Some(merges))
+                }
+            }
+            "Unigram" => {
+                let mut scores = vec![0f32; tokens.len()];
+                let pieces = model
+                    .get("vocab")
+                    .and_then(|vocab| vocab.as_array())
+                    .cloned()
+                    .unwrap_or_default();
+                for (score, piece) in scores.iter_mut().zip(pieces.iter()) {
+                    *score = piece
+                        .get(1)
+                        .and_then(|score| score.as_f64())
+                        .unwrap_or_default() as f32;
+                }
+                ("llama", Some(scores), None)
+            }
+            model_type => {
+                return Err(TokenizerError::UnsupportedGgufTokenizer(
+                    model_type.to_owned(),
+                ))
+            }
+        };
+
+        let added_tokens = self.inner.get_added_tokens_decoder();
+        let token_types: Vec<Value> = tokens
+            .iter()
+            .enumerate()
+            .map(|(token_id, token)| {
+                let token_id = token_id as u32;
+                let token_type = match added_tokens.get(&token_id) {
+                    _ if unk_id == Some(token_id) => UNKNOWN_TOKEN_TYPE,
+                    Some(added_token) if added_token.special => CONTROL_TOKEN_TYPE,
+                    Some(_) => USER_DEFINED_TOKEN_TYPE,
+                    None if byte_fallback && is_byte_token(token) => BYTE_TOKEN_TYPE,
+                    None => NORMAL_TOKEN_TYPE,
+                };
+                Value::I32(token_type)
+            })
+            .collect();
+
+        let mut metadata = vec![
+            (
+                "tokenizer.ggml.model".to_owned(),
+                Value::String(tokenizer_model.to_owned()),
+            ),
+            (
+                "tokenizer.ggml.tokens".to_owned(),
+                Value::Array(tokens.into_iter().map(Value::String).collect()),
+            ),
+            (
+                "tokenizer.ggml.token_type".to_owned(),
+                Value::Array(token_types),
+            ),
+        ];
+        if let Some(scores) = scores {
+            metadata.push((
+                "tokenizer.ggml.scores".to_owned(),
+                Value::Array(scores.into_iter().map(Value::F32).collect()),
+            ));
+        }
+        if let Some(merges) = merges {
+            metadata.push((
+                "tokenizer.ggml.merges".to_owned(),
+                Value::Array(
+                    merges
+                        .into_iter()
+                        .map(|(left, right)| Value::String(format!("{left} {right}")))
+                        .collect(),
+                ),
+            ));
+        }
+        metadata.extend([
+            (
+                "tokenizer.ggml.bos_token_id".to_owned(),
+                Value::U32(self.bos_id),
+            ),
+            (
+                "tokenizer.ggml.eos_token_id".to_owned(),
+                Value::U32(self.eos_id),
+            ),
+            (
+                "tokenizer.ggml.padding_token_id".to_owned(),
+                Value::U32(self.pad_id),
+            ),
+        ]);
+        if let Some(unk_id) = unk_id {
+            metadata.push((
+                "tokenizer.ggml.unknown_token_id".to_owned(),
+                Value::U32(unk_id),
+            ));
+        }
+        if let Some(template) = &self.template {
+            metadata.push((
+                "tokenizer.chat_template".to_owned(),
+                Value::String(template.get_template()),
+            ));
+        }
+        metadata.push((
+            HUGGING_FACE_JSON_KEY.to_owned(),
+            Value::String(self.inner.to_string(false)?),
+        ));
+        Ok(metadata)
+    }
+}
+
+/// The normalizer and decoder of SentencePiece BPE tokenizers around `bpe`.
+fn sentencepiece_tokenizer(bpe: BPE) -> TokenizerResult<tokenizers::Tokenizer> {
+    let mut tokenizer = tokenizers::Tokenizer::new(bpe);
+    tokenizer.with_normalizer(NormalizerSequence::new(vec![
+        Prepend::new("▁".to_owned()).into(),
+        Replace::new(" ", "▁")?.into(),
+    ]));
+    tokenizer.with_decoder(DecoderSequence::new(vec![
+        DecoderWrapper::Replace(Replace::new("▁", " ")?),
+        ByteFallback::new().into(),
+        Fuse::new().into(),
+        Strip::new(' ', 1, 0).into(),
+    ]));
+    Ok(tokenizer)
+}
+
+/// Merges of a SentencePiece BPE vocabulary: every pair of tokens that concatenates into another
+/// token, ranked by the score of that token.
+fn sentencepiece_merges(vocab: &HashMap<String, u32>, scores: &[f32]) -> Vec<(String, String)> {
+    let mut merges: Vec<(f32, u32, &str, &str)> = Vec::new();
+    for (token, token_id) in vocab.iter() {
+        let score = scores.get(*token_id as usize).copied().unwrap_or_default();
+        for (split, _) in token.char_indices().skip(1) {
+            let (left, right) = token.split_at(split);
+            if let (Some(left_id), Some(right_id)) = (vocab.get(left), vocab.get(right)) {
+                merges.push((score, (*left_id).max(*right_id), left, right));
+            }
+        }
+    }
+    merges.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+    merges
+        .into_iter()
+        .map(|(_, _, left, right)| (left.to_owned(), right.to_owned()))
+        .collect()
+}
+
+/// Merges of a serialized BPE model, `"left right"` strings or `["left", "right"]` pairs
+/// depending on the version of the tokenizers library that wrote them.
+fn bpe_merges(model: &serde_json::Value) -> TokenizerResult<Vec<(String, String)>> {
+    let merges = model
+        .get("merges")
+        .and_then(|merges| merges.as_array())
+        .cloned()
+        .unwrap_or_default();
+    merges
+        .iter()
+        .map(|merge| {
+            let pair = match merge {
+                serde_json::Value::String(merge) => merge
+                    .split_once(' ')
+                    .map(|(left, right)| (left.to_owned(), right.to_owned())),
+                serde_json::Value::Array(pair) => match pair.as_slice() {
+                    [serde_json::Value::String(left), serde_json::Value::String(right)] => {
+                        Some((left.clone(), right.clone()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            pair.ok_or_else(|| {
+                TokenizerError::CandleError(candle_core::Error::Msg(format!(
+                    "invalid bpe merge {merge}"
+                )))
+            })
+        })
+        .collect()
+}
+
+/// Scores ranking the tokens by their first merge, the inverse of `sentencepiece_merges`. Tokens
+/// no merge produces get the lowest score, below every merged one.
+fn merge_scores(tokens: &[String], merges: &[(String, String)]) -> Vec<f32> {
+    let mut ranks: HashMap<String, usize> = HashMap::with_capacity(merges.len());
+    for (rank, (left, right)) in merges.iter().enumerate() {
+        ranks.entry(format!("{left}{right}")).or_insert(rank);
+    }
+    let lowest = -(merges.len() as f32) - 1.;
+    tokens
+        .iter()
+        .map(|token| match ranks.get(token) {
+            Some(rank) => -(*rank as f32),
+            None => lowest,
+        })
+        .collect()
+}
+
+fn is_byte_token(token: &str) -> bool {
+    token.len() == 6 && token.starts_with("<0x") && token.ends_with('>')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sentencepiece_test_tokenizer() -> TokenizerResult<Tokenizer> {
+        let vocab: HashMap<String, u32> = [
+            "<unk>", "<s>", "</s>", "▁", "h", "e", "l", "o", "he", "ll", "▁he", "llo",
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(token_id, token)| (token.to_owned(), token_id as u32))
+        .collect();
+        let merges = [("h", "e"), ("l", "l"), ("▁", "he"), ("ll", "o")]
+            .into_iter()
+            .map(|(left, right)| (left.to_owned(), right.to_owned()))
+            .collect();
+        let bpe = BPE::builder()
+            .vocab_and_merges(vocab, merges)
+            .unk_token("<unk>".to_owned())
+            .fuse_unk(true)
+            .byte_fallback(true)
+            .build()?;
+        let mut tokenizer = sentencepiece_tokenizer(bpe)?;
+        tokenizer.add_special_tokens(&[
+            AddedToken::from("<unk>", true),
+            AddedToken::from("<s>", true),
+            AddedToken::from("</s>", true),
+        ]);
+        let template = "{% for message in messages %}{{ message['content'] }}{% endfor %}";
+        Tokenizer::new(
+            tokenizer,
+            "<s>".to_owned(),
+            "</s>".to_owned(),
+            "</s>".to_owned(),
+            Some(ChatTemplate::new(
+                template.to_owned(),
+                Some("<s>".to_owned()),
+                Some("</s>".to_owned()),
+            )),
+        )
+    }
+
+    fn gguf_content(
+        metadata: &[(String, gguf_file::Value)],
+    ) -> TokenizerResult<gguf_file::Content> {
+        let metadata: Vec<(&str, &gguf_file::Value)> = metadata
+            .iter()
+            .map(|(key, value)| (key.as_str(), value))
+            .collect();
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        gguf_file::write(&mut buffer, &metadata, &[])?;
+        buffer.set_position(0);
+        Ok(gguf_file::Content::read(&mut buffer)?)
+    }
+
+    fn encode(tokenizer: &Tokenizer, prompt: &str) -> TokenizerResult<Vec<u32>> {
+        Ok(tokenizer
+            .encode_batch(vec![prompt], false)?
+            .token_ids
+            .remove(0))
+    }
+
+    #[test]
+    fn sentencepiece_merges_follow_the_scores() {
+        let vocab: HashMap<String, u32> = ["▁", "h", "e", "he", "▁h", "▁he"]
+            .into_iter()
+            .enumerate()
+            .map(|(token_id, token)| (token.to_owned(), token_id as u32))
+            .collect();
+        let scores = [0., 0., 0., -1., -3., -2.];
+        let merges = sentencepiece_merges(&vocab, &scores);
+        let merges: Vec<(&str, &str)> = merges
+            .iter()
+            .map(|(left, right)| (left.as_str(), right.as_str()))
+            .collect();
+        assert_eq!(
+            merges,
+            vec![("h", "e"), ("▁", "he"), ("▁h", "e"), ("▁", "h")]
+        );
+    }
+
+    #[test]
+    fn gguf_metadata_round_trips() -> TokenizerResult<()> {
+        let tokenizer = sentencepiece_test_tokenizer()?;
+        let metadata = tokenizer.gguf_metadata()?;
+        let loaded = Tokenizer::from_gguf(&gguf_content(&metadata)?)?;
+        assert_eq!(loaded.bos_id, tokenizer.bos_id);
+        assert_eq!(loaded.eos_id, tokenizer.eos_id);
+        assert_eq!(loaded.pad_id, tokenizer.pad_id);
+        assert_eq!(
+            loaded
+                .template
+                .as_ref()
+                .map(|template| template.get_template()),
+            tokenizer
+                .template
+                .as_ref()
+                .map(|template| template.get_template())
+        );
+        assert_eq!(
+            encode(&loaded, "hello he</s>")?,
+            encode(&tokenizer, "hello he</s>")?
+        );
+
+        // llama.cpp only reads the ggml vocabulary, the merges rebuilt from it must match.
+        let metadata: Vec<(String, gguf_file::Value)> = metadata
+            .into_iter()
+            .filter(|(key, _)| key != HUGGING_FACE_JSON_KEY)
+            .collect();
+        let loaded = Tokenizer::from_gguf(&gguf_content(&metadata)?)?;
+        assert_eq!(
+            encode(&loaded, "hello he")?,
+            encode(&tokenizer, "hello he")?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{Tokenizer, TokenizerResult};
+
+/// Number of tokens before the new ones that are decoded along with them, so the tokenizer sees
+/// enough context to restore word boundaries (e.g. the leading space of `▁word` pieces).
+const PREFIX_WINDOW: usize = 5;
+
+/// Turns the tokens of a single sequence into text one step at a time.
+///
+/// Only the text the new tokens add is returned. While the last tokens decode to an incomplete
+/// UTF-8 character (byte fallback tokens like `<0xE2>`) nothing is returned, the whole character
+/// is returned once its last byte is generated.
+#[derive(Debug, Clone)]
+pub struct IncrementalDetokenizer {
+    /// Start of the tokens decoded as context.
+    prefix_offset: usize,
+    /// Start of the tokens whose text was not returned yet.
+    read_offset: usize,
+}
+
+impl IncrementalDetokenizer {
+    /// `prompt_length` tokens of the sequence are part of the prompt and never returned.
+    pub fn new(prompt_length: usize) -> Self {
+        Self {
+            prefix_offset: prompt_length.saturating_sub(PREFIX_WINDOW),
+            read_offset: prompt_length,
+        }
+    }
+}
+
+impl IncrementalDetokenizer {
+    /// Returns the text added by the tokens of `token_ids` that were not decoded yet.
+    pub fn next(&mut self, tokenizer: &Tokenizer, token_ids: &[u32]) -> TokenizerResult<String> {
+        let prefix_text =
+            tokenizer.decode(&token_ids[self.prefix_offset..self.read_offset], true)?;
+        let new_text = tokenizer.decode(&token_ids[self.prefix_offset..], true)?;
+        if new_text.len() <= prefix_text.len() || new_text.ends_with(char::REPLACEMENT_CHARACTER) {
+            return Ok(String::new());
+        }
+        let delta = new_text
+            .get(prefix_text.len()..)
+            .unwrap_or_default()
+            .to_owned();
+        self.prefix_offset = self.read_offset;
+        self.read_offset = token_ids.len();
+        Ok(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use huggingface_tokenizers::decoders::byte_fallback::ByteFallback;
+    use huggingface_tokenizers::decoders::fuse::Fuse;
+    use huggingface_tokenizers::decoders::sequence::Sequence;
+    use huggingface_tokenizers::decoders::strip::Strip;
+    use huggingface_tokenizers::decoders::DecoderWrapper;
+    use huggingface_tokenizers::models::bpe::BPE;
+    use huggingface_tokenizers::normalizers::Replace;
+    use huggingface_tokenizers::AddedToken;
+
+    const HELLO: u32 = 3;
+    const WORLD: u32 = 4;
+    const BANG: u32 = 5;
+    /// The bytes of "€", `E2 82 AC`.
+    const EURO: [u32; 3] = [6, 7, 8];
+
+    /// A SentencePiece like tokenizer: `▁` marks the leading space of words, which the decoder
+    /// strips from the start of the text, and bytes missing from the vocabulary fall back to
+    /// `<0xNN>` tokens.
+    fn tokenizer() -> TokenizerResult<Tokenizer> {
+        let vocab = [
+            "<unk>", "<s>", "</s>", "▁hello", "▁world", "!", "<0xE2>", "<0x82>", "<0xAC>",
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(token_id, token)| (token.to_owned(), token_id as u32))
+        .collect();
+        let bpe = BPE::builder()
+            .vocab_and_merges(vocab, Vec::new())
+            .unk_token("<unk>".to_owned())
+            .byte_fallback(true)
+            .build()?;
+        let mut tokenizer = huggingface_tokenizers::Tokenizer::new(bpe);
+        tokenizer.with_decoder(Sequence::new(vec![
+            DecoderWrapper::Replace(Replace::new("▁", " ")?),
+            ByteFallback::new().into(),
+            Fuse::new().into(),
+            Strip::new(' ', 1, 0).into(),
+        ]));
+        tokenizer.add_special_tokens(&[
+            AddedToken::from("<s>", true),
+            AddedToken::from("</s>", true),
+        ]);
+        Tokenizer::new(
+            tokenizer,
+            "<s>".to_owned(),
+            "</s>".to_owned(),
+            "</s>".to_owned(),
+            None,
+        )
+    }
+
+    /// Feeds `generated` one token at a time after `prompt`, collecting the returned deltas.
+    fn deltas(prompt: &[u32], generated: &[u32]) -> TokenizerResult<Vec<String>> {
+        let tokenizer = tokenizer()?;
+        let mut detokenizer = IncrementalDetokenizer::new(prompt.len());
+        let mut token_ids = prompt.to_vec();
+        let mut deltas = Vec::with_capacity(generated.len());
+        for token_id in generated {
+            token_ids.push(*token_id);
+            deltas.push(detokenizer.next(&tokenizer, &token_ids)?);
+        }
+        Ok(deltas)
+    }
+
+    #[test]
+    fn keeps_the_leading_space_of_words() -> TokenizerResult<()> {
+        assert_eq!(
+            deltas(&[1, HELLO], &[WORLD, BANG, HELLO])?,
+            vec![" world", "!", " hello"]
+        );
+        // Decoded on its own the first word of a sequence loses its space.
+        assert_eq!(tokenizer()?.decode(&[WORLD], true)?, "world");
+        Ok(())
+    }
+
+    #[test]
+    fn waits_for_the_last_byte_of_a_character() -> TokenizerResult<()> {
+        let generated = [EURO[0], EURO[1], EURO[2], WORLD];
+        assert_eq!(
+            deltas(&[1, HELLO], &generated)?,
+            vec!["", "", "€", " world"]
+        );
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
 mod batch_encoding;
 mod error;
+mod gguf_tokenizer;
+mod incremental_detokenizer;
 mod template;
 mod tokenized_batch;
 mod tokenizer;
@@ -7,6 +9,7 @@ mod tokenizer_files;
 
 pub use self::batch_encoding::BatchEncoding;
 pub use self::error::*;
+pub use self::incremental_detokenizer::IncrementalDetokenizer;
 pub use self::template::*;
 pub use self::tokenized_batch::TokenizedBatch;
 pub use self::tokenizer::Tokenizer;
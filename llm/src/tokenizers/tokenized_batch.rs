@@ -1,4 +1,7 @@
-use crate::{BatchEncoding, GenerationBatch, GenerationRequest, Tokenizer, TokenizerResult};
+use crate::{
+    BatchEncoding, GenerationBatch, GenerationRequest, KeyValueCache, Result, Tokenizer,
+    TokenizerResult,
+};
 use candle_core::Tensor;
 use indexmap::IndexMap;
 
@@ -8,7 +11,8 @@ pub struct TokenizedBatch {
     pub token_ids: Vec<Vec<u32>>,
     pub input_ids: Tensor,
     pub attention_mask: Tensor,
-    pub past_key_values: Option<Tensor>,
+    pub past_key_values: Option<KeyValueCache>,
+    pub pad_id: u32,
 }
 
 impl TokenizedBatch {
@@ -19,7 +23,9 @@ impl TokenizedBatch {
         let mut prompts: Vec<&str> = Vec::with_capacity(batch.len());
         let GenerationBatch { requests } = batch;
         for request in requests.values() {
-            prompts.push(&request.content)
+            for _ in 0..request.number_of_rows() {
+                prompts.push(&request.content)
+            }
         }
 
         let BatchEncoding {
@@ -33,6 +39,62 @@ impl TokenizedBatch {
             input_ids: ids,
             attention_mask,
             past_key_values: None,
+            pad_id: tokenizer.pad_id,
+        })
+    }
+
+    /// Merges batches into one, left padding `input_ids` and `attention_mask` to the longest one.
+    ///
+    /// Batches either all hold a key/value cache or none do, and their caches must already be
+    /// aligned to the same length (see `Model::shift_key_value_cache`).
+    pub fn concatenate(batches: Vec<TokenizedBatch>) -> Result<Self> {
+        let pad_id = batches
+            .first()
+            .map(|batch| batch.pad_id)
+            .unwrap_or_default();
+        let mut input_length = 0;
+        let mut sequence_length = 0;
+        for batch in batches.iter() {
+            input_length = input_length.max(batch.input_ids.dim(1)?);
+            sequence_length = sequence_length.max(batch.sequence_length()?);
+        }
+
+        let number_of_requests = batches.iter().map(|batch| batch.len()).sum();
+        let number_of_rows = batches.iter().map(|batch| batch.number_of_rows()).sum();
+        let mut requests = IndexMap::with_capacity(number_of_requests);
+        let mut token_ids = Vec::with_capacity(number_of_rows);
+        let mut input_ids = Vec::with_capacity(batches.len());
+        let mut attention_masks = Vec::with_capacity(batches.len());
+        let mut caches = Vec::with_capacity(batches.len());
+        for batch in batches.into_iter() {
+            requests.extend(batch.requests);
+            token_ids.extend(batch.token_ids);
+            input_ids.push(crate::utils::left_pad(
+                &batch.input_ids,
+                input_length,
+                batch.pad_id,
+            )?);
+            attention_masks.push(crate::utils::left_pad(
+                &batch.attention_mask,
+                sequence_length,
+                f32::NEG_INFINITY,
+            )?);
+            if let Some(cache) = batch.past_key_values {
+                caches.push(cache);
+            }
+        }
+        let past_key_values = if caches.is_empty() {
+            None
+        } else {
+            Some(KeyValueCache::concatenate(&caches)?)
+        };
+        Ok(Self {
+            requests,
+            token_ids,
+            input_ids: Tensor::cat(&input_ids, 0)?,
+            attention_mask: Tensor::cat(&attention_masks, 0)?,
+            past_key_values,
+            pad_id,
         })
     }
 }
@@ -45,4 +107,24 @@ impl TokenizedBatch {
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Number of sequences in the batch, a request using beam search takes one row per beam.
+    pub fn number_of_rows(&self) -> usize {
+        self.token_ids.len()
+    }
+
+    /// True until the prompt has gone through the model and the key/value cache is filled.
+    pub fn is_prefill(&self) -> bool {
+        self.past_key_values.is_none()
+    }
+
+    /// Number of positions (cached and new) attended over by every sequence of the batch.
+    pub fn sequence_length(&self) -> Result<usize> {
+        Ok(self.attention_mask.dim(1)?)
+    }
+
+    /// Positions processed by a forward pass over this batch, padding included.
+    pub fn padded_token_count(&self) -> Result<usize> {
+        Ok(self.number_of_rows() * self.sequence_length()?)
+    }
 }
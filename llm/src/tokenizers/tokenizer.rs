@@ -1,25 +1,29 @@
 use super::template::ChatTemplate;
 use super::tokenizer_files::TokenizerFiles;
 use super::{BatchEncoding, TokenizerError, TokenizerResult};
-use crate::models::ModelType;
+use crate::models::{GgufFile, ModelConfig, ModelFiles};
 use candle_core::Tensor;
-use candle_examples::device as get_device;
-use clap::builder::Str;
 use hf_hub::{api, api::sync::ApiRepo, Repo, RepoType};
 use huggingface_tokenizers::{PaddingDirection, PaddingParams, PaddingStrategy};
+use std::path::Path;
 
 #[derive(Debug)]
 pub struct Tokenizer {
-    inner: tokenizers::Tokenizer,
+    pub(super) inner: tokenizers::Tokenizer,
     padding: tokenizers::PaddingParams,
     pub template: Option<ChatTemplate>,
     pub pad_id: u32,
     pub bos_id: u32,
     pub eos_id: u32,
+    /// Every token ending a sequence, `eos_id` first. Models like Llama 3 end their turns with
+    /// other tokens than the `eos_token` of the tokenizer.
+    pub eos_ids: Vec<u32>,
 
     pub pad_token: String,
     pub bos_token: String,
     pub eos_token: String,
+    /// Device of the batch tensors, the model's one. The CPU until a generator sets it.
+    device: candle_core::Device,
 }
 
 impl Tokenizer {
@@ -44,12 +48,21 @@ impl Tokenizer {
 
         for encoding in encodings.iter() {
             ids.push(encoding.get_ids());
-            token_ids.push(encoding.get_ids().into());
+            // Padding is only needed for the tensors, the token history holds the prompt alone.
+            token_ids.push(
+                encoding
+                    .get_ids()
+                    .iter()
+                    .zip(encoding.get_attention_mask())
+                    .filter(|(_, attention)| **attention == 1)
+                    .map(|(token_id, _)| *token_id)
+                    .collect(),
+            );
             attentions.push(encoding.get_attention_mask());
         }
-        let device = get_device(false)?;
-        let ids = Tensor::new(ids, &device)?;
-        let attention_mask = Tensor::new(attentions, &device)?.to_dtype(candle_core::DType::F32)?;
+        let ids = Tensor::new(ids, &self.device)?;
+        let attention_mask =
+            Tensor::new(attentions, &self.device)?.to_dtype(candle_core::DType::F32)?;
         let ignore_mask = Tensor::full(
             f32::NEG_INFINITY,
             attention_mask.shape(),
@@ -70,6 +83,11 @@ impl Tokenizer {
         })
     }
 
+    /// Number of tokens of `prompt`, without special tokens as in `encode_batch`.
+    pub fn count_tokens(&self, prompt: &str) -> TokenizerResult<usize> {
+        Ok(self.inner.encode(prompt, false)?.len())
+    }
+
     pub fn batch_decode(
         &self,
         token_ids: &Vec<Vec<u32>>,
@@ -82,6 +100,10 @@ impl Tokenizer {
         Ok(self.inner.decode_batch(&token_refs, skip_special_tokens)?)
     }
 
+    pub fn decode(&self, token_ids: &[u32], skip_special_tokens: bool) -> TokenizerResult<String> {
+        Ok(self.inner.decode(token_ids, skip_special_tokens)?)
+    }
+
     pub fn decode_batch(&self, batch: &BatchEncoding) -> TokenizerResult<Vec<String>> {
         let next_tokens_vec: Vec<Vec<u32>> = batch.ids.to_vec2()?;
         self.batch_decode(&next_tokens_vec, true)
@@ -136,11 +158,7 @@ impl Tokenizer {
             .map(|slice| slice.to_owned())
             .expect("eos_token is not a string in the tokenizer config.json");
 
-        let mut tokenizer = tokenizers::Tokenizer::from_file(files.model)?;
-        let pad_id = tokenizer.encode(pad_token.clone(), false)?.get_ids()[0];
-        let bos_id = tokenizer.encode(bos_token.clone(), false)?.get_ids()[0];
-        let eos_id = tokenizer.encode(eos_token.clone(), false)?.get_ids()[0];
-
+        let tokenizer = tokenizers::Tokenizer::from_file(files.model)?;
         let chat_template: Option<ChatTemplate> = match config.get("chat_template") {
             Some(value) => value.as_str().map(|value| {
                 ChatTemplate::new(
@@ -152,8 +170,23 @@ impl Tokenizer {
             None => None,
         };
         tracing::debug!("tokenizer chat_template: {:?}", &chat_template);
+        Self::new(tokenizer, bos_token, eos_token, pad_token, chat_template)
+    }
 
-        if tokenizer.get_vocab(true).get(&pad_token).is_none() {
+    /// Wraps an already loaded tokenizer, adding `pad_token` to its vocab if it is missing and
+    /// left padding its batches.
+    pub fn new(
+        mut tokenizer: tokenizers::Tokenizer,
+        bos_token: String,
+        eos_token: String,
+        pad_token: String,
+        chat_template: Option<ChatTemplate>,
+    ) -> TokenizerResult<Self> {
+        let pad_id = tokenizer.encode(pad_token.clone(), false)?.get_ids()[0];
+        let bos_id = tokenizer.encode(bos_token.clone(), false)?.get_ids()[0];
+        let eos_id = tokenizer.encode(eos_token.clone(), false)?.get_ids()[0];
+
+        if !tokenizer.get_vocab(true).contains_key(&pad_token) {
             tracing::debug!(
                 "Pad token {:?} not found in vocab, adding token.",
                 &pad_token
@@ -199,10 +232,12 @@ impl Tokenizer {
             pad_id,
             bos_id,
             eos_id,
+            eos_ids: vec![eos_id],
             bos_token,
             eos_token,
             pad_token,
             template: chat_template,
+            device: candle_core::Device::Cpu,
         })
     }
 
@@ -211,15 +246,36 @@ impl Tokenizer {
         Self::from_files(tokenizer_files)
     }
 
-    pub fn load(model_type: ModelType) -> TokenizerResult<Self> {
+    /// Loads the tokenizer files of a local directory, without any network access.
+    pub fn from_dir(directory: &Path) -> TokenizerResult<Self> {
+        tracing::debug!("loading tokenizer from: {:?}", directory);
+        let tokenizer_files = TokenizerFiles::from_dir(directory)?;
+        Self::from_files(tokenizer_files)
+    }
+
+    /// Loads the tokenizer of the model described by `config`, from its local directory when
+    /// there is one. Quantized models use the tokenizer stored in their GGUF file.
+    pub fn from_model_config(config: &ModelConfig) -> TokenizerResult<Self> {
+        if config.is_gguf() {
+            let gguf = ModelFiles::from_model_config(config)
+                .and_then(|files| GgufFile::open(files.gguf_file()?))
+                .map_err(Box::new)?;
+            return Self::from_gguf(&gguf.content);
+        }
+        match config.local_path() {
+            Some(model_path) => Self::from_dir(&model_path),
+            None => Self::load(&config.model_id),
+        }
+    }
+
+    pub fn load(model_id: &str) -> TokenizerResult<Self> {
         let api = api::sync::ApiBuilder::new()
             .with_cache_dir("./.cache/huggingface".into())
-            .with_token(Some("....".to_owned()))
+            .with_token(std::env::var("HUGGING_FACE_TOKEN").ok())
             .build()?;
-        let model_id = model_type.path();
         tracing::debug!("loading model_id: {model_id}");
         let repo = api.repo(Repo::with_revision(
-            model_id,
+            model_id.to_owned(),
             RepoType::Model,
             "main".to_owned(),
         ));
@@ -228,6 +284,24 @@ impl Tokenizer {
 }
 
 impl Tokenizer {
+    pub fn is_eos(&self, token_id: u32) -> bool {
+        self.eos_ids.contains(&token_id)
+    }
+
+    /// Adds the end of sequence ids a model declares in its configs.
+    pub fn add_eos_ids(&mut self, eos_ids: &[u32]) {
+        for eos_id in eos_ids {
+            if !self.eos_ids.contains(eos_id) {
+                self.eos_ids.push(*eos_id);
+            }
+        }
+    }
+
+    /// Creates the batch tensors on `device`, which has to be the very device the model runs on.
+    pub fn set_device(&mut self, device: candle_core::Device) {
+        self.device = device;
+    }
+
     pub fn new_chat_template(&self, template: String) -> ChatTemplate {
         ChatTemplate::new(
             template,
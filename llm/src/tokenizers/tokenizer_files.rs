@@ -1,6 +1,6 @@
-use crate::TokenizerResult;
+use crate::{TokenizerError, TokenizerResult};
 use hf_hub::api::sync::ApiRepo;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone)]
 pub struct TokenizerFiles {
@@ -21,6 +21,31 @@ impl TokenizerFiles {
         })
     }
 
+    /// Finds the tokenizer files in a local directory laid out like a hub repo, without any
+    /// network access.
+    pub fn from_dir(directory: &Path) -> TokenizerResult<Self> {
+        let required = |file: &str| -> TokenizerResult<PathBuf> {
+            let path = directory.join(file);
+            if path.is_file() {
+                Ok(path)
+            } else {
+                Err(TokenizerError::MissingFile {
+                    directory: directory.to_path_buf(),
+                    file: file.to_owned(),
+                })
+            }
+        };
+        let model = required("tokenizer.json")?;
+        let config = required("tokenizer_config.json")?;
+        let special_tokens =
+            Some(directory.join("special_tokens_map.json")).filter(|path| path.is_file());
+        Ok(Self {
+            model,
+            config,
+            special_tokens,
+        })
+    }
+
     pub fn load_file<T>(file_path: std::path::PathBuf) -> TokenizerResult<T>
     where
         T: for<'a> serde::Deserialize<'a>,
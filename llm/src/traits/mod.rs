@@ -0,0 +1,86 @@
+use crate::{
+    KeyValueCache, LayerKeyValues, LoraAdapter, ModelMetaData, ModelResult, TokenizedBatch,
+};
+use candle_core::Tensor;
+
+/// A model the generation pipeline can run batches through.
+///
+/// The backend is moved into the blocking generation task, every call happens on that thread.
+pub trait ModelBackend: Send + 'static {
+    /// Runs one step over the batch. On the first step the whole prompt is prefilled, after that
+    /// `batch.input_ids` only holds the newly sampled tokens and the key/values stored in
+    /// `batch.past_key_values` are updated in place.
+    ///
+    /// Returns the logits of the last position of every row, shaped `(batch, 1, vocab_size)`.
+    fn forward(&mut self, batch: &mut TokenizedBatch) -> ModelResult<Tensor>;
+
+    /// Moves every cached position `positions` places to the right, left padding with empty
+    /// positions, so the cache can be merged with a longer one.
+    fn shift_key_value_cache(
+        &self,
+        cache: KeyValueCache,
+        positions: usize,
+    ) -> ModelResult<KeyValueCache>;
+
+    fn vocab_size(&self) -> usize;
+
+    /// Maximum number of positions, prompt included, a sequence can hold.
+    fn context_length(&self) -> usize;
+
+    fn meta_data(&self) -> ModelMetaData;
+
+    /// End of sequence ids declared by the model on top of the `eos_token` of its tokenizer.
+    fn eos_token_ids(&self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    /// Names of the LoRA adapters requests can select, the base model being always available.
+    fn adapters(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Device the batches have to be on.
+    fn device(&self) -> candle_core::Device {
+        candle_core::Device::Cpu
+    }
+}
+
+/// The network of a model family, run by `Model` one batch at a time.
+///
+/// The key/value cache is moved into the network before a forward pass and taken back out after
+/// it, so it can travel with its batch between steps.
+pub trait Architecture: std::fmt::Debug + Send {
+    /// Batched forward pass over left padded `input_ids`. `attention_mask` is the additive padding
+    /// mask of shape `(batch, seqlen_offset + seq_len)` covering the cached and the new positions.
+    fn forward_with_attention(
+        &mut self,
+        input_ids: &Tensor,
+        attention_mask: &Tensor,
+        seqlen_offset: usize,
+    ) -> candle_core::Result<Tensor>;
+
+    fn set_kv_cache(&mut self, cache: Vec<LayerKeyValues>);
+
+    fn take_kv_cache(&mut self) -> Vec<LayerKeyValues>;
+
+    /// Re-rotates the cached keys so they hold the position `positions` places to the right.
+    fn shift_kv_cache(
+        &self,
+        cache: Vec<LayerKeyValues>,
+        positions: usize,
+    ) -> candle_core::Result<Vec<LayerKeyValues>>;
+
+    /// Loads a LoRA adapter over the base weights. Adapters are selected by their index in load
+    /// order.
+    fn load_adapter(&mut self, adapter: &LoraAdapter) -> candle_core::Result<()> {
+        candle_core::bail!(
+            "LoRA adapter {} cannot be loaded, the architecture does not support adapters",
+            adapter.name
+        )
+    }
+
+    /// Adapter of every batch row for the next forward passes, `None` for the base weights.
+    fn set_adapters(&mut self, _adapters: &[Option<usize>]) -> candle_core::Result<()> {
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 use crate::*;
-use candle_core::Tensor;
+use candle_core::{Tensor, WithDType};
 
 pub fn get_eos_tokens(tokens: &Tensor, eos_id: u32) -> Result<Vec<u8>> {
     Ok(tokens.eq(eos_id)?.squeeze(1)?.to_vec1::<u8>()?)
@@ -31,3 +31,33 @@ pub fn get_sampling(
         }
     }
 }
+
+/// Left pads a `(batch, seq_len)` tensor with `value` up to `length` columns.
+pub fn left_pad<D: WithDType>(tensor: &Tensor, length: usize, value: D) -> Result<Tensor> {
+    let (batch_size, sequence_length) = tensor.dims2()?;
+    if sequence_length >= length {
+        return Ok(tensor.clone());
+    }
+    let padding = Tensor::full(
+        value,
+        (batch_size, length - sequence_length),
+        tensor.device(),
+    )?
+    .to_dtype(tensor.dtype())?;
+    Ok(Tensor::cat(&[&padding, tensor], 1)?)
+}
+
+/// Left pads a `(batch, num_kv_heads, seq_len, head_dim)` key or value tensor with zeros up to
+/// `length` positions.
+pub fn left_pad_cache(tensor: &Tensor, length: usize) -> candle_core::Result<Tensor> {
+    let (batch_size, num_heads, sequence_length, head_dim) = tensor.dims4()?;
+    if sequence_length >= length {
+        return Ok(tensor.clone());
+    }
+    let padding = Tensor::zeros(
+        (batch_size, num_heads, length - sequence_length, head_dim),
+        tensor.dtype(),
+        tensor.device(),
+    )?;
+    Tensor::cat(&[&padding, tensor], 2)
+}